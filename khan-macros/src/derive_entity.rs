@@ -1,20 +1,120 @@
 use crate::{
     prelude::*,
-    utils::{build_fields_enum, extract_named_fields, extract_serde_rename, mongodb},
+    utils::{
+        build_fields_enum, build_helper_macros, build_typed_filter, build_typed_update,
+        build_update_apply, extract_named_fields, extract_serde_rename, generic_argument, mongodb,
+    },
 };
+use darling::ast::NestedMeta;
+use syn::{ExprLit, ExprUnary, Lit, Meta, UnOp};
 
 #[derive(FromAttributes)]
 #[darling(attributes(entity))]
 struct Attributes {
     #[darling(default)]
     projections: HashMap<Ident, PathList>,
-    indexes: HashMap<Ident, IndexAttributes>,
+    #[darling(default)]
+    indexes: Indexes,
+}
+
+#[derive(FromAttributes)]
+#[darling(attributes(entity))]
+struct FieldAttributes {
+    references: Option<syn::Path>,
+}
+
+/// Indexes in the order they were declared, e.g. `indexes(by_email(keys(email = 1)))`.
+#[derive(Default)]
+struct Indexes(Vec<(Ident, IndexAttributes)>);
+
+impl FromMeta for Indexes {
+    fn from_list(items: &[NestedMeta]) -> darling::Result<Self> {
+        items
+            .iter()
+            .map(|item| {
+                let NestedMeta::Meta(meta @ Meta::List(list)) = item else {
+                    return Err(
+                        darling::Error::custom("expected `name(keys(...), ...)`").with_span(item)
+                    );
+                };
+
+                let name = list.path.get_ident().cloned().ok_or_else(|| {
+                    darling::Error::custom("expected index name").with_span(&list.path)
+                })?;
+
+                Ok((name, IndexAttributes::from_meta(meta)?))
+            })
+            .try_collect()
+            .map(Self)
+    }
 }
 
 #[derive(FromMeta)]
 struct IndexAttributes {
-    keys: HashMap<Ident, LitInt>,
-    options: Expr,
+    keys: IndexKeys,
+    #[darling(default)]
+    unique: bool,
+    #[darling(default)]
+    sparse: bool,
+    /// A filter of the entity, only the documents matching it are indexed
+    partial: Option<Expr>,
+    /// Seconds after the date in the key when the document is deleted
+    expire_after: Option<u64>,
+    /// `IndexOptions` that the rest of the attributes are applied to
+    options: Option<Expr>,
+}
+
+/// Index keys in the order they were declared, e.g. `keys(org = 1, created_at = -1)`.
+struct IndexKeys(Vec<(Ident, IndexDirection)>);
+
+impl FromMeta for IndexKeys {
+    fn from_list(items: &[NestedMeta]) -> darling::Result<Self> {
+        items
+            .iter()
+            .map(|item| {
+                let NestedMeta::Meta(Meta::NameValue(name_value)) = item else {
+                    return Err(
+                        darling::Error::custom("expected `field = 1` or `field = -1`")
+                            .with_span(item),
+                    );
+                };
+
+                let key = name_value.path.get_ident().cloned().ok_or_else(|| {
+                    darling::Error::custom("expected field name").with_span(&name_value.path)
+                })?;
+
+                // `-1` is usually a negation of `1`, but is a single negative literal when it
+                // comes from a `literal` fragment of a declarative macro
+                let direction = match &name_value.value {
+                    value if int_digits(value) == Some("1") => IndexDirection::Pos,
+                    value if int_digits(value) == Some("-1") => IndexDirection::Neg,
+                    Expr::Unary(ExprUnary {
+                        op: UnOp::Neg(_),
+                        expr,
+                        ..
+                    }) if int_digits(expr) == Some("1") => IndexDirection::Neg,
+                    value => {
+                        return Err(
+                            darling::Error::custom("index direction must be `1` or `-1`")
+                                .with_span(value),
+                        );
+                    }
+                };
+
+                Ok((key, direction))
+            })
+            .try_collect()
+            .map(Self)
+    }
+}
+
+fn int_digits(expr: &Expr) -> Option<&str> {
+    match expr {
+        Expr::Lit(ExprLit {
+            lit: Lit::Int(lit), ..
+        }) => Some(lit.base10_digits()),
+        _ => None,
+    }
 }
 
 pub fn derive_entity(item: TokenStream) -> Result<TokenStream> {
@@ -33,6 +133,8 @@ pub fn derive_entity(item: TokenStream) -> Result<TokenStream> {
         for field in fields_named.named {
             let rename = extract_serde_rename(&field);
 
+            let field_attributes = FieldAttributes::from_attributes(&field.attrs)?;
+
             if field.ident.as_ref().unwrap() == "id" {
                 let missing_serde_attribute_err = || {
                     Error::new_spanned(&field, "id field must have `#[serde(rename = \"_id\")]`")
@@ -54,6 +156,7 @@ pub fn derive_entity(item: TokenStream) -> Result<TokenStream> {
                 FieldConfig {
                     ty: field.ty,
                     rename,
+                    references: field_attributes.references,
                 },
             );
         }
@@ -99,36 +202,29 @@ pub fn derive_entity(item: TokenStream) -> Result<TokenStream> {
 
     let indexes = attributes
         .indexes
+        .0
         .into_iter()
         .map(|(name, index_attrs)| {
-            let name = if name == "_" { None } else { Some(name) };
-
-            let keys = index_attrs
-                .keys
-                .into_iter()
-                .map(|(key, direction_lit)| {
-                    if !fields.contains_key(&key) {
-                        return Err(Error::new_spanned(key, "unknown field"));
-                    }
+            for (key, _) in &index_attrs.keys.0 {
+                if !fields.contains_key(key) {
+                    return Err(Error::new_spanned(key, "unknown field"));
+                }
+            }
+
+            if index_attrs.expire_after.is_some() && index_attrs.keys.0.len() != 1 {
+                return Err(Error::new_spanned(
+                    &name,
+                    "an index with `expire_after` must have a single key",
+                ));
+            }
 
-                    let direction = match direction_lit.base10_parse::<i8>()? {
-                        1 => IndexDirection::Pos,
-                        -1 => IndexDirection::Neg,
-                        _ => {
-                            return Err(Error::new_spanned(
-                                direction_lit,
-                                "index direction must be `1` or `-1`",
-                            ));
-                        }
-                    };
-
-                    Ok((key, direction))
-                })
-                .try_collect()?;
-
-            Ok::<_, syn::Error>(IndexConfig {
+            Ok(IndexConfig {
                 name,
-                keys,
+                keys: index_attrs.keys.0,
+                unique: index_attrs.unique,
+                sparse: index_attrs.sparse,
+                partial: index_attrs.partial,
+                expire_after: index_attrs.expire_after,
                 options: index_attrs.options,
             })
         })
@@ -149,6 +245,7 @@ pub fn derive_entity(item: TokenStream) -> Result<TokenStream> {
 struct FieldConfig {
     ty: Type,
     rename: Option<String>,
+    references: Option<syn::Path>,
 }
 
 struct ProjectionConfig {
@@ -158,9 +255,13 @@ struct ProjectionConfig {
 }
 
 struct IndexConfig {
-    name: Option<Ident>,
-    keys: HashMap<Ident, IndexDirection>,
-    options: Expr,
+    name: Ident,
+    keys: Vec<(Ident, IndexDirection)>,
+    unique: bool,
+    sparse: bool,
+    partial: Option<Expr>,
+    expire_after: Option<u64>,
+    options: Option<Expr>,
 }
 
 enum IndexDirection {
@@ -197,20 +298,6 @@ fn build(
         .map(|field_config| &field_config.ty)
         .collect_vec();
 
-    let filter_field_types = field_types.iter().map(|ty| {
-        if let Type::Path(type_path) = ty {
-            if type_path.qself.is_none() {
-                if let Some(ident) = type_path.path.get_ident() {
-                    if ident == "String" {
-                        return parse_quote! { str };
-                    }
-                }
-            }
-        }
-
-        (*ty).to_owned()
-    });
-
     let field_lits_by_ident = fields
         .iter()
         .map(|(field_ident, field_config)| {
@@ -220,14 +307,76 @@ fn build(
                     &field_config
                         .rename
                         .as_deref()
-                        .map_or_else(|| Cow::Owned(ident.to_string()), Cow::Borrowed),
+                        .map_or_else(|| Cow::Owned(field_ident.to_string()), Cow::Borrowed),
                     Span::call_site(),
                 ),
             )
         })
         .collect::<HashMap<_, _>>();
 
-    let field_lits = field_lits_by_ident.values().collect_vec();
+    let field_lits = field_idents
+        .iter()
+        .map(|field_ident| field_lits_by_ident.get(field_ident).unwrap())
+        .collect_vec();
+
+    let index_models = indexes.iter().map(|config| {
+        let keys = config.keys.iter().map(|(key, direction)| {
+            let lit = field_lits_by_ident.get(key).unwrap();
+            let direction = match direction {
+                IndexDirection::Pos => quote! { 1 },
+                IndexDirection::Neg => quote! { -1 },
+            };
+
+            quote! { #lit: #direction }
+        });
+
+        let base_options = config.options.as_ref().map_or_else(
+            || quote! { ::std::default::Default::default() },
+            |options| quote! { #options },
+        );
+
+        let name = LitStr::new(&config.name.to_string(), config.name.span());
+
+        let unique = config
+            .unique
+            .then(|| quote! { options.unique = ::std::option::Option::Some(true); });
+
+        let sparse = config
+            .sparse
+            .then(|| quote! { options.sparse = ::std::option::Option::Some(true); });
+
+        let partial = config.partial.as_ref().map(|partial| {
+            quote! {
+                options.partial_filter_expression = ::std::option::Option::Some(
+                    #krate::Filter::<#ident>::to_document(&(#partial)),
+                );
+            }
+        });
+
+        let expire_after = config.expire_after.map(|seconds| {
+            quote! {
+                options.expire_after = ::std::option::Option::Some(
+                    ::std::time::Duration::from_secs(#seconds),
+                );
+            }
+        });
+
+        quote! {
+            {
+                let mut options: #mongodb::options::IndexOptions = #base_options;
+                options.name = ::std::option::Option::Some(::std::borrow::ToOwned::to_owned(#name));
+                #unique
+                #sparse
+                #partial
+                #expire_after
+
+                #mongodb::IndexModel::builder()
+                    .keys(#mongodb::bson::doc! { #( #keys ),* })
+                    .options(options)
+                    .build()
+            }
+        }
+    });
 
     let update_apply_for_entity =
         build_update_apply(&krate, &mongodb, ident, field_idents.iter().copied());
@@ -252,7 +401,7 @@ fn build(
         };
 
         let projection_fields = projected_field_idents.iter().map(|field_ident| {
-            let field_config = fields.get(ident).unwrap();
+            let field_config = fields.get(field_ident).unwrap();
 
             let field_ty = &field_config.ty;
 
@@ -296,8 +445,119 @@ fn build(
         }
     });
 
+    let (relation_idents, relation_impls): (Vec<_>, Vec<_>) = field_idents
+        .iter()
+        .zip(&field_lits)
+        .filter_map(|(field_ident, field_lit)| {
+            let field_config = fields.get(*field_ident).unwrap();
+
+            let references = field_config.references.as_ref()?;
+
+            let relation_ident = Ident::new(
+                &field_ident.to_string().to_upper_camel_case(),
+                field_ident.span(),
+            );
+
+            let id = if generic_argument(&field_config.ty, "Option").is_some() {
+                quote! { entity.#field_ident }
+            } else {
+                quote! { ::std::option::Option::Some(entity.#field_ident) }
+            };
+
+            // Implemented outside of the `relations` module, where the referenced entity can't
+            // be shadowed by a relation with the same name
+            let relation_impl = quote! {
+                impl #krate::Relation for relations::#relation_ident {
+                    type From = #ident;
+
+                    type To = #references;
+
+                    const FIELD: &'static str = #field_lit;
+
+                    fn id(entity: &#ident) -> ::std::option::Option<<#references as #krate::Entity>::Id> {
+                        #id
+                    }
+                }
+            };
+
+            Some((relation_ident, relation_impl))
+        })
+        .unzip();
+
+    let relations_module = (!relation_idents.is_empty()).then(|| {
+        quote! {
+            pub mod relations {
+                #(
+                    #[derive(::std::fmt::Debug)]
+                    pub struct #relation_idents;
+                )*
+            }
+
+            #( #relation_impls )*
+        }
+    });
+
+    let typed_field_idents = field_idents
+        .iter()
+        .map(|field_ident| {
+            Ident::new(
+                &field_ident.to_string().to_upper_camel_case(),
+                field_ident.span(),
+            )
+        })
+        .collect_vec();
+
+    // Queries like `distinct` look into arrays and skip `null`, so their values are the
+    // elements of arrays, and the values of options
+    let typed_field_value_types = field_types.iter().map(|ty| {
+        let ty = generic_argument(ty, "Option").unwrap_or(ty);
+        generic_argument(ty, "Vec").unwrap_or(ty)
+    });
+
+    // Implemented outside of the `fields` module for the same reason as relations
+    let typed_fields_module = quote! {
+        pub mod fields {
+            #(
+                #[derive(::std::fmt::Debug, ::std::clone::Clone, ::std::marker::Copy)]
+                pub struct #typed_field_idents;
+            )*
+        }
+
+        #(
+            impl #krate::TypedField for fields::#typed_field_idents {
+                type Entity = #ident;
+
+                type Value = #typed_field_value_types;
+
+                fn name(&self) -> &'static str {
+                    #field_lits
+                }
+            }
+        )*
+    };
+
     let fields_enum = build_fields_enum(field_idents.iter().copied(), field_lits.iter().copied());
 
+    let typed_filter = build_typed_filter(
+        &krate,
+        &mongodb,
+        ident,
+        &field_idents,
+        &field_types,
+        &field_lits,
+    );
+
+    let typed_update = build_typed_update(
+        &krate,
+        &mongodb,
+        ident,
+        &field_idents,
+        &field_types,
+        &field_lits,
+    );
+
+    let helper_macros = build_helper_macros(&krate, &mod_ident, &field_idents, &field_types);
+
     quote! {
         #vis mod #mod_ident {
             use super::*;
@@ -310,10 +570,19 @@ fn build(
                 const COLLECTION_NAME: &'static str = #collection_name;
 
                 fn indexes() -> &'static [#mongodb::IndexModel] {
-                    &[]
+                    static INDEXES: ::std::sync::LazyLock<::std::vec::Vec<#mongodb::IndexModel>> =
+                        ::std::sync::LazyLock::new(|| ::std::vec![ #( #index_models ),* ]);
+
+                    &INDEXES
                 }
             }
 
+            impl #krate::HasFields for #ident {
+                type Fields = Fields;
+            }
+
+            #krate::register_entity!(#ident);
+
             impl #krate::Selectable<Self> for #ident {
                 const FIELDS: ::std::option::Option<&'static [&'static str]> = ::std::option::Option::None;
             }
@@ -324,100 +593,27 @@ fn build(
                 }
             }
 
-            #[derive(::std::fmt::Debug, ::std::default::Default)]
-            pub struct TypedFilter<'a> {
-                #(
-                    pub #field_idents: #krate::Field<#krate::FilterOperator<'a, #filter_field_types>>
-                ),*
-            }
-
-            impl #krate::Filter<#ident> for TypedFilter<'_> {
-                fn to_document(&self) -> #mongodb::bson::Document {
-                    let mut document = #mongodb::bson::doc! {};
-
-                    #(
-                        if let #krate::Field::Set(val) = &self.#field_idents {
-                            #mongodb::bson::Document::insert(
-                                &mut document,
-                                #field_lits,
-                                #krate::FilterOperator::to_document(val)
-                            );
-                        }
-                    )*
-
-                    document
-                }
-            }
+            #typed_filter
 
-            #[derive(::std::fmt::Debug, ::std::default::Default)]
-            pub struct TypedUpdate {
-                #(
-                    pub #field_idents: #krate::Field<#field_types>
-                ),*
-            }
-
-            impl #krate::Update<#ident> for TypedUpdate {
-                fn to_document(&self) -> #mongodb::bson::Document {
-                    let mut document = #mongodb::bson::doc! {};
-
-                    #(
-                        if let #krate::Field::Set(val) = &self.#field_idents {
-                            #mongodb::bson::Document::insert(
-                                &mut document,
-                                #field_lits,
-                                ::std::result::Result::unwrap(#mongodb::bson::to_bson(val)),
-                            );
-                        }
-                    )*
-
-                    document
-                }
-            }
+            #typed_update
 
             #update_apply_for_entity
 
             #( #projection_impls )*
 
-            #fields_enum
-
-            #[allow(unused_macros)]
-            macro_rules! filter {
-                ($( $input: tt )*) => {
-                   #krate::construct_filter!(#mod_ident, $( $input )*)
-                };
-            }
+            #relations_module
 
-            pub(crate) use filter;
+            #typed_fields_module
 
-            #[allow(unused_macros)]
-            macro_rules! update {
-                ($( $input: tt )*) => {
-                   #krate::construct_update!(#mod_ident, $( $input )*)
-                };
-            }
+            #fields_enum
 
-            pub(crate) use update;
+            #helper_macros
         }
-    }
-}
-
-fn build_update_apply<'a>(
-    krate: &TokenStream,
-    mongodb: &TokenStream,
-    apply_to: &Ident,
-    field_idents: impl Iterator<Item = &'a Ident>,
-) -> TokenStream {
-    quote! {
-        impl #krate::UpdateApply<#apply_to> for TypedUpdate {
-            fn apply(self, projection: &mut #apply_to) -> #mongodb::error::Result<()> {
-                #(
-                    if let #krate::Field::Set(val) = self.#field_idents {
-                        projection.#field_idents = val;
-                    }
-                )*
 
-                ::std::result::Result::Ok(())
-            }
+        // Outside of the module, so that the path is the one of the entity
+        impl #krate::EntityName for #ident {
+            const ENTITY_NAME: &'static str =
+                ::std::concat!(::std::module_path!(), "::", ::std::stringify!(#ident));
         }
     }
 }
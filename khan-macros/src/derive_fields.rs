@@ -1,6 +1,9 @@
 use crate::{
     prelude::*,
-    utils::{build_fields_enum, extract_named_fields, extract_serde_rename},
+    utils::{
+        build_fields_enum, build_helper_macros, build_typed_filter, build_typed_update,
+        build_update_apply, extract_named_fields, extract_serde_rename, mongodb,
+    },
 };
 
 pub fn derive_fields(item: TokenStream) -> Result<TokenStream> {
@@ -13,7 +16,7 @@ pub fn derive_fields(item: TokenStream) -> Result<TokenStream> {
         .into_iter()
         .map(|field| {
             let rename = extract_serde_rename(&field);
-            (field.ident.unwrap(), rename)
+            (field.ident.unwrap(), field.ty, rename)
         })
         .collect_vec();
 
@@ -22,16 +25,20 @@ pub fn derive_fields(item: TokenStream) -> Result<TokenStream> {
     Ok(output)
 }
 
-fn build(vis: &Visibility, ident: &Ident, fields: &[(Ident, Option<String>)]) -> TokenStream {
+fn build(vis: &Visibility, ident: &Ident, fields: &[(Ident, Type, Option<String>)]) -> TokenStream {
+    let krate = krate();
+    let mongodb = mongodb();
+
     let mod_ident = Ident::new(&ident.to_string().to_snake_case(), Span::call_site());
 
-    let field_idents = fields.iter().map(|field| &field.0);
+    let field_idents = fields.iter().map(|field| &field.0).collect_vec();
+    let field_types = fields.iter().map(|field| &field.1).collect_vec();
     let field_lits = fields
         .iter()
         .map(|field| {
             LitStr::new(
                 &field
-                    .1
+                    .2
                     .as_deref()
                     .map(Cow::Borrowed)
                     .unwrap_or_else(|| Cow::Owned(field.0.to_string())),
@@ -39,12 +46,49 @@ fn build(vis: &Visibility, ident: &Ident, fields: &[(Ident, Option<String>)]) ->
             )
         })
         .collect_vec();
+    let field_lits = field_lits.iter().collect_vec();
+
+    let fields_enum = build_fields_enum(field_idents.iter().copied(), field_lits.iter().copied());
+
+    let typed_filter = build_typed_filter(
+        &krate,
+        &mongodb,
+        ident,
+        &field_idents,
+        &field_types,
+        &field_lits,
+    );
 
-    let fields_enum = build_fields_enum(field_idents, field_lits.iter());
+    let typed_update = build_typed_update(
+        &krate,
+        &mongodb,
+        ident,
+        &field_idents,
+        &field_types,
+        &field_lits,
+    );
+
+    let update_apply = build_update_apply(&krate, &mongodb, ident, field_idents.iter().copied());
+
+    let helper_macros = build_helper_macros(&krate, &mod_ident, &field_idents, &field_types);
 
     quote! {
         #vis mod #mod_ident {
+            use super::*;
+
             #fields_enum
+
+            impl #krate::HasFields for #ident {
+                type Fields = Fields;
+            }
+
+            #typed_filter
+
+            #typed_update
+
+            #update_apply
+
+            #helper_macros
         }
     }
 }
@@ -1,66 +1,198 @@
 use crate::prelude::*;
+use quote::{ToTokens, quote_spanned};
+use syn::{Path, braced, bracketed, token};
 
 struct Input {
     module: Ident,
-    fields: Punctuated<Field, Token![,]>,
+    embedded: Embedded,
+    items: Punctuated<Item, Token![,]>,
 }
 
 impl Parse for Input {
     fn parse(input: syn::parse::ParseStream) -> Result<Self> {
         let constructor = input.parse()?;
         input.parse::<Token![,]>()?;
-        let fields = Punctuated::parse_terminated(input)?;
+        let embedded = input.parse()?;
+        input.parse::<Token![,]>()?;
+        let items = Punctuated::parse_terminated(input)?;
         Ok(Self {
             module: constructor,
-            fields,
+            embedded,
+            items,
+        })
+    }
+}
+
+/// Helper modules of embedded documents by field, passed in by the `filter!` and
+/// `update!` macros as `[address: address, ...]`.
+pub struct Embedded(HashMap<Ident, Path>);
+
+impl Embedded {
+    /// Returns the helper module of the embedded document behind the field.
+    pub fn module(&self, ident: &Ident) -> Result<&Path> {
+        self.0.get(ident).ok_or_else(|| {
+            Error::new_spanned(ident, format!("`{ident}` is not an embedded document"))
+        })
+    }
+}
+
+impl Parse for Embedded {
+    fn parse(input: syn::parse::ParseStream) -> Result<Self> {
+        let content;
+        bracketed!(content in input);
+
+        let entries =
+            Punctuated::<(Ident, Path), Token![,]>::parse_terminated_with(&content, |input| {
+                let ident = input.parse()?;
+                input.parse::<Token![:]>()?;
+                let path = input.parse()?;
+                Ok((ident, path))
+            })?;
+
+        Ok(Self(entries.into_iter().collect()))
+    }
+}
+
+/// Parses a field path, e.g. `age` or `address.city`.
+pub fn parse_field_path(input: syn::parse::ParseStream) -> Result<Vec<Ident>> {
+    let mut path = vec![input.parse()?];
+
+    while input.peek(Token![.]) {
+        input.parse::<Token![.]>()?;
+        path.push(input.parse()?);
+    }
+
+    Ok(path)
+}
+
+enum Item {
+    Field(Box<Field>),
+    Group(Group),
+}
+
+impl Parse for Item {
+    fn parse(input: syn::parse::ParseStream) -> Result<Self> {
+        if input.peek(Ident) && input.peek2(token::Brace) {
+            input.parse().map(Self::Group)
+        } else {
+            input.parse().map(|field| Self::Field(Box::new(field)))
+        }
+    }
+}
+
+struct Group {
+    operator: GroupOperator,
+    span: Span,
+    items: Punctuated<Item, Token![,]>,
+}
+
+enum GroupOperator {
+    And,
+    Or,
+    Nor,
+    Not,
+}
+
+impl Parse for Group {
+    fn parse(input: syn::parse::ParseStream) -> Result<Self> {
+        let ident = input.parse::<Ident>()?;
+
+        let operator = if ident == "and" {
+            GroupOperator::And
+        } else if ident == "or" {
+            GroupOperator::Or
+        } else if ident == "nor" {
+            GroupOperator::Nor
+        } else if ident == "not" {
+            GroupOperator::Not
+        } else {
+            return Err(Error::new_spanned(
+                ident,
+                "expected `and`, `or`, `nor` or `not`",
+            ));
+        };
+
+        let content;
+        braced!(content in input);
+        let items = Punctuated::parse_terminated(&content)?;
+
+        if items.is_empty() {
+            return Err(Error::new_spanned(
+                ident,
+                "expected at least one filter inside the group",
+            ));
+        }
+
+        Ok(Self {
+            operator,
+            span: ident.span(),
+            items,
         })
     }
 }
 
 struct Field {
     ident: Ident,
+    /// The rest of a dot-path, e.g. `city` in `address.city`
+    nested: Vec<Ident>,
     operator: Option<Ident>,
-    value: Expr,
+    value: Option<Expr>,
+    /// The filter as written, passed down to the embedded document for dot-paths
+    raw: TokenStream,
 }
 
 impl Parse for Field {
     fn parse(input: syn::parse::ParseStream) -> Result<Self> {
-        let ident = input.parse()?;
+        let mut path = parse_field_path(input)?;
+        let ident = path.remove(0);
         input.parse::<Token![:]>()?;
 
         let operator_or_value = input.parse::<Expr>()?;
 
         let mut operator_and_operand = None;
 
-        if let Expr::Call(expr_call) = &operator_or_value {
-            if let Expr::Path(expr_path) = expr_call.func.as_ref() {
-                if let Some(ident) = expr_path.path.get_ident() {
-                    if (ident == "Eq"
-                        || ident == "Ne"
-                        || ident == "Gt"
-                        || ident == "Gte"
-                        || ident == "Lt"
-                        || ident == "Lte"
-                        || ident == "In"
-                        || ident == "Nin")
-                        && expr_call.args.len() == 1
-                    {
-                        operator_and_operand = Some((ident, expr_call.args[0].clone()));
-                    }
-                }
-            }
+        if let Expr::Call(expr_call) = &operator_or_value
+            && let Expr::Path(expr_path) = expr_call.func.as_ref()
+            && let Some(ident) = expr_path.path.get_ident()
+            && (ident == "Eq"
+                || ident == "Ne"
+                || ident == "Gt"
+                || ident == "Gte"
+                || ident == "Lt"
+                || ident == "Lte"
+                || ident == "In"
+                || ident == "Nin"
+                || ident == "Exists"
+                || ident == "Type"
+                || ident == "Regex"
+                || ident == "Contains"
+                || ident == "Size"
+                || ident == "All"
+                || ident == "ElemMatch")
+            && expr_call.args.len() == 1
+        {
+            operator_and_operand = Some((ident, Some(expr_call.args[0].clone())));
+        } else if let Expr::Path(expr_path) = &operator_or_value
+            && let Some(ident) = expr_path.path.get_ident()
+            && ident == "Null"
+        {
+            operator_and_operand = Some((ident, None));
         }
 
         let output = match operator_and_operand {
             Some((operator, operand)) => Self {
                 ident,
+                nested: path,
                 operator: Some(operator.to_owned()),
                 value: operand,
+                raw: operator_or_value.to_token_stream(),
             },
             None => Self {
                 ident,
+                nested: path,
                 operator: None,
-                value: operator_or_value,
+                raw: operator_or_value.to_token_stream(),
+                value: Some(operator_or_value),
             },
         };
 
@@ -71,32 +203,216 @@ impl Parse for Field {
 pub fn func_construct_filter(input: TokenStream) -> Result<TokenStream> {
     let input = parse2::<Input>(input)?;
 
-    let output = build(&input);
+    let output = build(&input)?;
 
     Ok(output)
 }
 
-fn build(input: &Input) -> TokenStream {
+fn build(input: &Input) -> Result<TokenStream> {
     let krate = krate();
+
+    build_conjunction(&krate, input, input.items.iter())
+}
+
+/// Builds a single `TypedFilter` out of the plain fields, a nested filter for each
+/// embedded document referenced by dot-paths, and joins them with the groups using `$and`.
+fn build_conjunction<'a>(
+    krate: &TokenStream,
+    input: &Input,
+    items: impl Iterator<Item = &'a Item>,
+) -> Result<TokenStream> {
+    let (fields, groups): (Vec<_>, Vec<_>) = items.partition_map(|item| match item {
+        Item::Field(field) => either::Either::Left(field.as_ref()),
+        Item::Group(group) => either::Either::Right(group),
+    });
+
+    let (fields, nested_fields): (Vec<_>, Vec<_>) = fields
+        .into_iter()
+        .partition(|field| field.nested.is_empty());
+
+    let typed_filter = (!fields.is_empty() || (nested_fields.is_empty() && groups.is_empty()))
+        .then(|| build_typed_filter(krate, &input.module, fields.into_iter()));
+
+    let nested_filters = group_by_ident(nested_fields, |field| &field.ident)
+        .into_iter()
+        .map(|(ident, fields)| build_nested_filter(input, ident, &fields))
+        .try_collect::<_, Vec<_>, _>()?;
+
+    let groups = groups
+        .into_iter()
+        .map(|group| build_group(krate, input, group))
+        .try_collect::<_, Vec<_>, _>()?;
+
+    Ok(typed_filter
+        .into_iter()
+        .chain(nested_filters)
+        .chain(groups)
+        .reduce(|lhs, rhs| quote! { #krate::FilterAnd(#lhs, #rhs) })
+        .unwrap())
+}
+
+fn build_group(krate: &TokenStream, input: &Input, group: &Group) -> Result<TokenStream> {
+    let mut operands = group
+        .items
+        .iter()
+        .map(|item| match item {
+            Item::Field(field) if field.nested.is_empty() => Ok(build_typed_filter(
+                krate,
+                &input.module,
+                std::iter::once(field.as_ref()),
+            )),
+            Item::Field(field) => build_nested_filter(input, &field.ident, &[field]),
+            Item::Group(group) => build_group(krate, input, group),
+        })
+        .try_collect::<_, Vec<_>, _>()?
+        .into_iter();
+
+    let combinator = |name: &str| Ident::new(name, group.span);
+
+    let output = match group.operator {
+        GroupOperator::And => {
+            let combinator = combinator("FilterAnd");
+            operands
+                .reduce(|lhs, rhs| quote! { #krate::#combinator(#lhs, #rhs) })
+                .unwrap()
+        }
+        GroupOperator::Or => {
+            let combinator = combinator("FilterOr");
+            operands
+                .reduce(|lhs, rhs| quote! { #krate::#combinator(#lhs, #rhs) })
+                .unwrap()
+        }
+        GroupOperator::Nor => {
+            let first = operands.next().unwrap();
+
+            // `$nor: [a, { $or: [b, c] }]` is flattened to `$nor: [a, b, c]`
+            match operands.reduce(|lhs, rhs| quote! { #krate::FilterOr(#lhs, #rhs) }) {
+                Some(rest) => {
+                    let combinator = combinator("FilterNor");
+                    quote! { #krate::#combinator(#first, #rest) }
+                }
+                None => {
+                    let combinator = combinator("FilterNot");
+                    quote! { #krate::#combinator(#first) }
+                }
+            }
+        }
+        GroupOperator::Not => {
+            let combinator = combinator("FilterNot");
+            let operand = build_conjunction(krate, input, group.items.iter())?;
+            quote! { #krate::#combinator(#operand) }
+        }
+    };
+
+    Ok(output)
+}
+
+/// Groups dot-paths by their first segment, keeping the order in which they were written.
+/// Shared with `update!`, whose fields are grouped the same way.
+pub(crate) fn group_by_ident<'a, T>(
+    fields: Vec<&'a T>,
+    ident: impl Fn(&'a T) -> &'a Ident,
+) -> Vec<(&'a Ident, Vec<&'a T>)> {
+    let mut groups = Vec::<(&Ident, Vec<&T>)>::new();
+
+    for field in fields {
+        match groups
+            .iter_mut()
+            .find(|(group_ident, _)| *group_ident == ident(field))
+        {
+            Some((_, group)) => group.push(field),
+            None => groups.push((ident(field), vec![field])),
+        }
+    }
+
+    groups
+}
+
+/// Passes dot-paths starting with `ident` down to the `filter!` macro of the embedded
+/// document, e.g. `address.city: "Berlin"` becomes `address::filter! { city: "Berlin" }`.
+fn build_nested_filter(input: &Input, ident: &Ident, fields: &[&Field]) -> Result<TokenStream> {
     let module = &input.module;
+    let embedded_module = input.embedded.module(ident)?;
+
+    let variant = Ident::new(&ident.to_string().to_upper_camel_case(), ident.span());
+
+    let fields = fields.iter().map(|field| {
+        let path = &field.nested;
+        let raw = &field.raw;
+
+        quote! { #( #path ).*: #raw }
+    });
+
+    Ok(quote! {
+        #module::TypedFilter::nested(
+            #module::Fields::#variant,
+            #embedded_module::filter! { #( #fields ),* },
+        )
+    })
+}
 
-    let fields = input.fields.iter().map(|field| {
+fn build_typed_filter<'a>(
+    krate: &TokenStream,
+    module: &Ident,
+    fields: impl Iterator<Item = &'a Field>,
+) -> TokenStream {
+    let fields = fields.map(|field| {
         let ident = &field.ident;
         let operator = field
             .operator
             .clone()
             .unwrap_or_else(|| parse_quote! { Eq });
-        let value = &field.value;
+
+        // Presence checks belong to `Option<T>` fields, everything else is resolved
+        // through the operator type of the field
+        let filter_operator = if operator == "Exists" || operator == "Null" {
+            let operand = field.value.iter();
+
+            let krate = krate
+                .clone()
+                .into_iter()
+                .map(|mut token| {
+                    token.set_span(operator.span());
+                    token
+                })
+                .collect::<TokenStream>();
+
+            quote_spanned! { operator.span() =>
+                #krate::NullableFilterOperator::#operator #( (#operand) )*
+            }
+        } else {
+            let value = &field.value;
+
+            let operand = if operator == "ElemMatch" {
+                quote! { ::std::boxed::Box::new(#value) }
+            } else if operator == "Type" || operator == "Size" {
+                quote! { #value }
+            } else {
+                quote! { #krate::IntoOperand::into_operand(#value) }
+            };
+
+            quote! {
+                ::std::convert::From::from(#module::filter_operators::#ident::#operator(#operand))
+            }
+        };
 
         quote! {
-            #ident: #krate::Field::Set(#krate::FilterOperator::#operator(#value))
+            #ident: #krate::Field::Set(#filter_operator)
         }
     });
 
+    // Setting every field makes the base redundant, which shouldn't be reported in the
+    // caller's code. The block is typed through `identity`, otherwise it can't be the left
+    // operand of `&` and `|`
     quote! {
-        #module::TypedFilter {
-            #( #fields, )*
-            ..std::default::Default::default()
-        }
+        ::std::convert::identity::<#module::TypedFilter<'_>>({
+            #[allow(clippy::needless_update)]
+            let filter = #module::TypedFilter {
+                #( #fields, )*
+                ..std::default::Default::default()
+            };
+
+            filter
+        })
     }
 }
@@ -1,7 +1,11 @@
-use crate::prelude::*;
+use crate::{
+    func_construct_filter::{Embedded, group_by_ident, parse_field_path},
+    prelude::*,
+};
 
 struct Input {
     module: Ident,
+    embedded: Embedded,
     fields: Punctuated<Field, Token![,]>,
 }
 
@@ -9,9 +13,12 @@ impl Parse for Input {
     fn parse(input: syn::parse::ParseStream) -> Result<Self> {
         let constructor = input.parse()?;
         input.parse::<Token![,]>()?;
+        let embedded = input.parse()?;
+        input.parse::<Token![,]>()?;
         let fields = Punctuated::parse_terminated(input)?;
         Ok(Self {
             module: constructor,
+            embedded,
             fields,
         })
     }
@@ -19,44 +26,138 @@ impl Parse for Input {
 
 struct Field {
     ident: Ident,
+    /// The rest of a dot-path, e.g. `zip` in `address.zip`
+    nested: Vec<Ident>,
+    operator: Option<Ident>,
+    operand: Option<Expr>,
+    /// The update as written, passed down to the embedded document for dot-paths
     value: Expr,
 }
 
 impl Parse for Field {
     fn parse(input: syn::parse::ParseStream) -> Result<Self> {
-        let ident = input.parse()?;
+        let mut path = parse_field_path(input)?;
+        let ident = path.remove(0);
         input.parse::<Token![:]>()?;
-        let value = input.parse()?;
+        let value = input.parse::<Expr>()?;
+
+        let mut operator_and_operand = None;
+
+        if let Expr::Call(expr_call) = &value
+            && let Expr::Path(expr_path) = expr_call.func.as_ref()
+            && let Some(ident) = expr_path.path.get_ident()
+            && (ident == "Set"
+                || ident == "Inc"
+                || ident == "Mul"
+                || ident == "Min"
+                || ident == "Max"
+                || ident == "Push"
+                || ident == "Pull"
+                || ident == "AddToSet")
+            && expr_call.args.len() == 1
+        {
+            operator_and_operand = Some((ident.clone(), Some(expr_call.args[0].clone())));
+        } else if let Expr::Path(expr_path) = &value
+            && let Some(ident) = expr_path.path.get_ident()
+            && (ident == "Unset"
+                || ident == "CurrentDate"
+                || ident == "PopFirst"
+                || ident == "PopLast")
+        {
+            operator_and_operand = Some((ident.clone(), None));
+        }
+
+        let (operator, operand) = match operator_and_operand {
+            Some((operator, operand)) => (Some(operator), operand),
+            None => (None, Some(value.clone())),
+        };
 
-        Ok(Self { ident, value })
+        Ok(Self {
+            ident,
+            nested: path,
+            operator,
+            operand,
+            value,
+        })
     }
 }
 
 pub fn func_construct_update(input: TokenStream) -> Result<TokenStream> {
     let input = parse2::<Input>(input)?;
 
-    let output = build(&input);
+    let output = build(&input)?;
 
     Ok(output)
 }
 
-fn build(input: &Input) -> TokenStream {
+fn build(input: &Input) -> Result<TokenStream> {
     let krate = krate();
     let module = &input.module;
 
-    let fields = input.fields.iter().map(|field| {
-        let ident = &field.ident;
-        let value = &field.value;
+    let (fields, nested_fields): (Vec<_>, Vec<_>) = input
+        .fields
+        .iter()
+        .partition(|field| field.nested.is_empty());
+
+    let typed_update = (!fields.is_empty() || nested_fields.is_empty()).then(|| {
+        let fields = fields.iter().map(|field| {
+            let ident = &field.ident;
+            let operator = field
+                .operator
+                .clone()
+                .unwrap_or_else(|| parse_quote! { Set });
+            let operand = field.operand.iter();
+
+            quote! {
+                #ident: #krate::Field::Set(
+                    #module::update_operators::#ident::#operator #( (#operand) )*
+                )
+            }
+        });
 
+        // Setting every field makes the base redundant, which shouldn't be reported in
+        // the caller's code
         quote! {
-            #ident: #krate::Field::Set(#value)
+            ::std::convert::identity::<#module::TypedUpdate>({
+                #[allow(clippy::needless_update)]
+                let update = #module::TypedUpdate {
+                    #( #fields, )*
+                    ..std::default::Default::default()
+                };
+
+                update
+            })
         }
     });
 
-    quote! {
-        #module::TypedUpdate {
-            #( #fields, )*
-            ..std::default::Default::default()
-        }
-    }
+    // Dot-paths are passed down to the `update!` macro of the embedded document, e.g.
+    // `address.zip: value` becomes `address::update! { zip: value }`
+    let nested_updates = group_by_ident(nested_fields, |field| &field.ident)
+        .into_iter()
+        .map(|(ident, fields)| {
+            let embedded_module = input.embedded.module(ident)?;
+
+            let variant = Ident::new(&ident.to_string().to_upper_camel_case(), ident.span());
+
+            let fields = fields.iter().map(|field| {
+                let path = &field.nested;
+                let value = &field.value;
+
+                quote! { #( #path ).*: #value }
+            });
+
+            Ok::<_, Error>(quote! {
+                #module::TypedUpdate::nested(
+                    #module::Fields::#variant,
+                    #embedded_module::update! { #( #fields ),* },
+                )
+            })
+        })
+        .try_collect::<_, Vec<_>, _>()?;
+
+    Ok(typed_update
+        .into_iter()
+        .chain(nested_updates)
+        .reduce(|lhs, rhs| quote! { #krate::CombinedUpdate(#lhs, #rhs) })
+        .unwrap())
 }
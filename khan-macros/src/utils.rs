@@ -58,7 +58,7 @@ pub fn build_fields_enum<'a>(
                     "{}",
                     match self {
                         #(
-                            #field_idents_upper_camel_case => #field_lits
+                            Self::#field_idents_upper_camel_case => #field_lits
                         ),*
                     }
                 )
@@ -73,16 +73,409 @@ pub fn build_fields_enum<'a>(
     }
 }
 
+pub fn build_typed_filter(
+    krate: &TokenStream,
+    mongodb: &TokenStream,
+    filtered: &Ident,
+    field_idents: &[&Ident],
+    field_types: &[&Type],
+    field_lits: &[&LitStr],
+) -> TokenStream {
+    let (filter_field_types, operator_types): (Vec<_>, Vec<_>) = field_types
+        .iter()
+        .map(|ty| {
+            let (nullable, ty) = match generic_argument(ty, "Option") {
+                Some(inner) => (true, inner),
+                None => (false, *ty),
+            };
+
+            let operator_type = filter_operator_type(krate, ty);
+
+            let filter_field_type = if nullable {
+                quote! { #krate::NullableFilterOperator<#operator_type> }
+            } else {
+                operator_type.clone()
+            };
+
+            (filter_field_type, operator_type)
+        })
+        .unzip();
+
+    quote! {
+        #[derive(::std::fmt::Debug, ::std::default::Default)]
+        pub struct TypedFilter<'a> {
+            #(
+                pub #field_idents: #krate::Field<#filter_field_types>
+            ),*
+        }
+
+        impl #krate::Filter<#filtered> for TypedFilter<'_> {
+            fn to_document(&self) -> #mongodb::bson::Document {
+                let mut document = #mongodb::bson::doc! {};
+
+                #(
+                    if let #krate::Field::Set(val) = &self.#field_idents {
+                        #mongodb::bson::Document::insert(
+                            &mut document,
+                            #field_lits,
+                            #krate::Operator::to_document(val)
+                        );
+                    }
+                )*
+
+                document
+            }
+        }
+
+        impl TypedFilter<'_> {
+            /// Scopes a filter of an embedded document to one of the fields, used by the
+            /// `filter!` macro for dot-paths.
+            #[doc(hidden)]
+            pub fn nested<T, F: #krate::Filter<T>>(
+                field: Fields,
+                filter: F,
+            ) -> #krate::NestedFilter<#filtered, T, F> {
+                #krate::NestedFilter::new(field, filter)
+            }
+        }
+
+        #krate::impl_filter_ops!(['a,] TypedFilter<'a>);
+
+        /// Operator types of `TypedFilter` fields, used by the `filter!` macro.
+        #[doc(hidden)]
+        pub mod filter_operators {
+            use super::*;
+
+            #(
+                #[allow(non_camel_case_types)]
+                pub type #field_idents<'a> = #operator_types;
+            )*
+        }
+    }
+}
+
+const ORDERED: &[&str] = &[
+    "i8",
+    "i16",
+    "i32",
+    "i64",
+    "isize",
+    "u8",
+    "u16",
+    "u32",
+    "u64",
+    "usize",
+    "f32",
+    "f64",
+    "DateTime",
+    "NaiveDate",
+    "NaiveDateTime",
+    "ObjectId",
+    "Int32",
+    "Int64",
+    "Decimal128",
+    "Timestamp",
+];
+
+/// Picks the operator enum for a (non-optional) field type. Ordering operators are only
+/// available for types that are known to be ordered scalars.
+fn filter_operator_type(krate: &TokenStream, ty: &Type) -> TokenStream {
+    if is_string(ty) {
+        return quote! { #krate::StrFilterOperator<'a> };
+    }
+
+    if let Some(element) = generic_argument(ty, "Vec") {
+        return quote! { #krate::ArrayFilterOperator<'a, #element> };
+    }
+
+    if last_segment_ident(ty).is_some_and(|ident| ORDERED.iter().any(|ordered| ident == ordered)) {
+        quote! { #krate::FilterOperator<'a, #ty> }
+    } else {
+        quote! { #krate::EqFilterOperator<'a, #ty> }
+    }
+}
+
+fn last_segment_ident(ty: &Type) -> Option<&Ident> {
+    if let Type::Path(type_path) = ty
+        && type_path.qself.is_none()
+        && let Some(segment) = type_path.path.segments.last()
+    {
+        Some(&segment.ident)
+    } else {
+        None
+    }
+}
+
+fn has_generic_arguments(ty: &Type) -> bool {
+    if let Type::Path(type_path) = ty
+        && let Some(segment) = type_path.path.segments.last()
+    {
+        !segment.arguments.is_none()
+    } else {
+        false
+    }
+}
+
+/// Whether `ty` is `bson::DateTime` or `types::DateTime`, under any prefix.
+fn is_bson_date(ty: &Type) -> bool {
+    let Type::Path(type_path) = ty else {
+        return false;
+    };
+
+    let segments = type_path.path.segments.iter().collect_vec();
+
+    match segments.as_slice() {
+        [.., module, date] => {
+            date.ident == "DateTime"
+                && !has_generic_arguments(ty)
+                && (module.ident == "bson" || module.ident == "types")
+        }
+        _ => false,
+    }
+}
+
+fn is_string(ty: &Type) -> bool {
+    last_segment_ident(ty).is_some_and(|ident| ident == "String" || ident == "str")
+}
+
+/// Returns `T` if `ty` is `wrapper<T>`, e.g. `Option<T>` or `Vec<T>`.
+pub fn generic_argument<'a>(ty: &'a Type, wrapper: &str) -> Option<&'a Type> {
+    let Type::Path(type_path) = ty else {
+        return None;
+    };
+
+    let segment = type_path.path.segments.last()?;
+
+    if segment.ident != wrapper {
+        return None;
+    }
+
+    let syn::PathArguments::AngleBracketed(arguments) = &segment.arguments else {
+        return None;
+    };
+
+    match arguments.args.iter().collect_vec().as_slice() {
+        [syn::GenericArgument::Type(inner)] => Some(inner),
+        _ => None,
+    }
+}
+
+pub fn build_typed_update(
+    krate: &TokenStream,
+    mongodb: &TokenStream,
+    updated: &Ident,
+    field_idents: &[&Ident],
+    field_types: &[&Type],
+    field_lits: &[&LitStr],
+) -> TokenStream {
+    let operator_types = field_types
+        .iter()
+        .map(|ty| update_operator_type(krate, ty))
+        .collect_vec();
+
+    quote! {
+        #[derive(::std::fmt::Debug, ::std::default::Default)]
+        pub struct TypedUpdate {
+            #(
+                pub #field_idents: #krate::Field<#operator_types>
+            ),*
+        }
+
+        impl #krate::Update<#updated> for TypedUpdate {
+            fn to_document(&self) -> #mongodb::bson::Document {
+                let mut document = #mongodb::bson::doc! {};
+
+                #(
+                    if let #krate::Field::Set(operator) = &self.#field_idents {
+                        #krate::add_update_operator(&mut document, #field_lits, operator);
+                    }
+                )*
+
+                document
+            }
+        }
+
+        impl TypedUpdate {
+            /// Scopes an update of an embedded document to one of the fields, used by the
+            /// `update!` macro for dot-paths.
+            #[doc(hidden)]
+            pub fn nested<T, U: #krate::Update<T>>(
+                field: Fields,
+                update: U,
+            ) -> #krate::NestedUpdate<#updated, T, U> {
+                #krate::NestedUpdate::new(field, update)
+            }
+        }
+
+        /// Operator types of `TypedUpdate` fields, used by the `update!` macro.
+        #[doc(hidden)]
+        pub mod update_operators {
+            use super::*;
+
+            #(
+                #[allow(non_camel_case_types)]
+                pub type #field_idents = #operator_types;
+            )*
+        }
+    }
+}
+
+pub fn build_update_apply<'a>(
+    krate: &TokenStream,
+    mongodb: &TokenStream,
+    apply_to: &Ident,
+    field_idents: impl Iterator<Item = &'a Ident>,
+) -> TokenStream {
+    quote! {
+        impl #krate::UpdateApply<#apply_to> for TypedUpdate {
+            fn apply(self, projection: &mut #apply_to) -> #mongodb::error::Result<()> {
+                #(
+                    if let #krate::Field::Set(operator) = self.#field_idents {
+                        #krate::UpdateOperator::apply(operator, &mut projection.#field_idents)?;
+                    }
+                )*
+
+                ::std::result::Result::Ok(())
+            }
+        }
+    }
+}
+
+const NUMERIC: &[&str] = &[
+    "i8", "i16", "i32", "i64", "isize", "u8", "u16", "u32", "u64", "usize", "f32", "f64",
+];
+
+/// Picks the update operator enum for a field type. Arithmetic operators are only
+/// available for primitive numbers, and `$currentDate` for `bson` dates, which are
+/// stored as BSON dates (unlike e.g. `chrono` dates, which are stored as strings).
+/// Other types may be named `DateTime` too, so dates are only recognized by a path
+/// ending in `bson::DateTime` or `types::DateTime`.
+fn update_operator_type(krate: &TokenStream, ty: &Type) -> TokenStream {
+    if let Some(inner) = generic_argument(ty, "Option") {
+        return quote! { #krate::NullableUpdateOperator<#inner> };
+    }
+
+    if let Some(element) = generic_argument(ty, "Vec") {
+        return quote! { #krate::ArrayUpdateOperator<#element> };
+    }
+
+    let Some(ident) = last_segment_ident(ty) else {
+        return quote! { #krate::SetUpdateOperator<#ty> };
+    };
+
+    if NUMERIC.iter().any(|numeric| ident == numeric) {
+        quote! { #krate::NumUpdateOperator<#ty> }
+    } else if is_bson_date(ty) {
+        quote! { #krate::DateUpdateOperator<#ty> }
+    } else if ident != "Decimal128" && ORDERED.iter().any(|ordered| ident == ordered) {
+        // `Decimal128` can't be compared in memory
+        quote! { #krate::OrdUpdateOperator<#ty> }
+    } else {
+        quote! { #krate::SetUpdateOperator<#ty> }
+    }
+}
+
+/// Builds `filter!` and `update!` macros of a helper module.
+///
+/// Both macros receive the helper modules of embedded documents, which are used to
+/// expand dot-paths such as `address.city`. The module of an embedded type is only
+/// resolved when a dot-path into it is used, so types that don't derive `Fields` are
+/// fine as long as nobody filters or updates through them.
+pub fn build_helper_macros(
+    krate: &TokenStream,
+    mod_ident: &Ident,
+    field_idents: &[&Ident],
+    field_types: &[&Type],
+) -> TokenStream {
+    let (embedded_idents, embedded_modules): (Vec<_>, Vec<_>) = field_idents
+        .iter()
+        .zip(field_types)
+        .filter_map(|(ident, ty)| Some((*ident, embedded_module(ty)?)))
+        .unzip();
+
+    // `MongoDB` can't set a field of every element of an array with a plain dot-path
+    let (updatable_idents, updatable_modules): (Vec<_>, Vec<_>) = field_idents
+        .iter()
+        .zip(field_types)
+        .filter(|(_, ty)| {
+            let ty = generic_argument(ty, "Option").unwrap_or(ty);
+            generic_argument(ty, "Vec").is_none()
+        })
+        .filter_map(|(ident, ty)| Some((*ident, embedded_module(ty)?)))
+        .unzip();
+
+    quote! {
+        #[allow(unused_macros)]
+        macro_rules! filter {
+            ($( $input: tt )*) => {
+               #krate::construct_filter!(
+                   #mod_ident,
+                   [#( #embedded_idents: #embedded_modules ),*],
+                   $( $input )*
+               )
+            };
+        }
+
+        pub(crate) use filter;
+
+        #[allow(unused_macros)]
+        macro_rules! update {
+            ($( $input: tt )*) => {
+               #krate::construct_update!(
+                   #mod_ident,
+                   [#( #updatable_idents: #updatable_modules ),*],
+                   $( $input )*
+               )
+            };
+        }
+
+        pub(crate) use update;
+    }
+}
+
+/// Returns the path of the helper module of an embedded document type (or of the element
+/// type of an array or option), e.g. `models::address` for `Vec<models::Address>`.
+fn embedded_module(ty: &Type) -> Option<syn::Path> {
+    let ty = generic_argument(ty, "Option").unwrap_or(ty);
+    let ty = generic_argument(ty, "Vec").unwrap_or(ty);
+
+    let ident = last_segment_ident(ty)?;
+
+    if is_string(ty)
+        || ORDERED.iter().any(|ordered| ident == ordered)
+        || !ident.to_string().starts_with(char::is_uppercase)
+    {
+        return None;
+    }
+
+    let Type::Path(type_path) = ty else {
+        return None;
+    };
+
+    let mut path = type_path.path.clone();
+    let segment = path.segments.last_mut().unwrap();
+    segment.ident = Ident::new(&ident.to_string().to_snake_case(), ident.span());
+    segment.arguments = syn::PathArguments::None;
+
+    Some(path)
+}
+
 pub fn krate() -> TokenStream {
     match crate_name("khan").unwrap() {
         FoundCrate::Itself => quote! { crate },
-        FoundCrate::Name(name) => quote! { #name },
+        FoundCrate::Name(name) => {
+            let name = Ident::new(&name, Span::call_site());
+            quote! { ::#name }
+        }
     }
 }
 
 pub fn mongodb() -> TokenStream {
     match crate_name("khan").unwrap() {
         FoundCrate::Itself => quote! { ::mongodb },
-        FoundCrate::Name(name) => quote! { #name::mongodb },
+        FoundCrate::Name(name) => {
+            let name = Ident::new(&name, Span::call_site());
+            quote! { ::#name::mongodb }
+        }
     }
 }
@@ -107,12 +107,19 @@
 /// | `Entity::insert_many`             | Inserts multiple entities into the database.                                     | `User::insert_many(mongo, &[User { id, name: "Kit".into(), password: "pass".into() }]).await?;`         | `db.collection('user').insertMany([{ _id: id, name: "Kit", password: "pass" }]);`             |
 /// | `Entity::count`                   | Counts entities matching a filter.                                               | `User::count(mongo, user::filter! { name: "Kit" }).await?;`                                             | `db.collection('user').count({ name: { $eq: "Kit" } });`                                      |
 /// | `Entity::exists`                  | Returns true if at least one entity matches the filter.                          | `User::exists(mongo, user::filter! { name: "Kit" }).await?;`                                            | `db.collection('user').count({ name: { $eq: "Kit" } });`                                      |
+/// | `Entity::distinct`                | Finds the distinct values of a field among entities matching a filter.          | `User::distinct(mongo, user::fields::Name, user::filter! { active: true }).await?;`                    | `db.collection('user').distinct('name', { active: { $eq: true } });`                          |
 /// | `Selectable::find`                | Finds entities based on a filter.                                                | `User::find(mongo, user::filter! { name: "Kit" }).await?;`                                              | `db.collection('user').find({ name: { $eq: "Kit" } });`                                       |  
+/// | `Selectable::find_stream`         | Streams entities matching a filter from the cursor, without collecting them.     | `User::find_stream(mongo, user::filter! { name: "Kit" }, Some(1000)).try_next().await?;`               | `db.collection('user').find({ name: { $eq: "Kit" } }).batchSize(1000);`                       |
 /// | `Selectable::find_one`            | Finds a single entity based on a filter.                                         | `User::find_one(mongo, by_id(id)).await?;`                                                              | `db.collection('user').findOne({ _id: { $eq: id } });`                                        |
-/// | `Selectable::find_with_opts`      | Finds entities with options for skip, limit, and sorting.                        | `User::find_with_opts(user::filter! { name: "Kit" }), by_id(id), Some(10), Some(20), None).await?;`     | `db.collection('user').find({ name: { $eq: "Kit" } }).skip(10).limit(20);`                    |  
+/// | `Selectable::find_by_ids`        | Finds entities by ids, in the order of the ids, reporting the missing ones.      | `User::find_by_ids(mongo, [id_a, id_b]).await?.missing();`                                              | `db.collection('user').find({ _id: { $in: [id_a, id_b] } });`                                 |
+/// | `Selectable::query`               | Builds a query with sorting, skip, limit and other options, then runs it.        | `User::query(user::filter! { name: "Kit" }).sort(user::Fields::Name, Order::Asc).skip(10).limit(20).all(mongo).await?;` | `db.collection('user').find({ name: { $eq: "Kit" } }).sort({ name: 1 }).skip(10).limit(20);` |
+/// | `Query::page`                     | Fetches a page after or before a token, using sort keys instead of skip.         | `User::query(user::filter! { name: "Kit" }).sort(user::Fields::Name, Order::Asc).page(mongo, 20, token).await?;` | `db.collection('user').find({ $and: [{ name: { $eq: "Kit" } }, { $or: [{ name: { $gt: name } }, { name: { $eq: name }, _id: { $gt: id } }] }] }).sort({ name: 1, _id: 1 }).limit(21);` |
 /// | `Selectable::find_one_and_update` | Finds and updates a single entity based on a filter.                             | `User::find_one_and_update(mongo, by_id(id), user::update! { name: "Kit".into() }).await?;`             | `db.collection('user').findOneAndUpdate({ _id: id }, { $set: { name: "Kit" } });`             |
+/// | `Selectable::find_one_and_update_with_opts` | Finds and updates the first entity in sort order, returning it before or after the update. | `Job::find_one_and_update_with_opts(mongo, job::filter! { claimed: false }, job::update! { claimed: true }, ReturnDocument::After, Some(vec![(job::Fields::CreatedAt, Order::Asc)])).await?;` | `db.collection('job').findOneAndUpdate({ claimed: { $eq: false } }, { $set: { claimed: true } }, { sort: { created_at: 1 }, returnDocument: "after" });` |
+/// | `Selectable::find_one_and_upsert` | Finds and updates a single entity, or inserts it if none matches the filter.     | `User::find_one_and_upsert(mongo, user::filter! { name: "Kit" }, user::update! { password: "pass".into() }, user::update! { id: ObjectId::new() }).await?;` | `db.collection('user').findOneAndUpdate({ name: { $eq: "Kit" } }, { $set: { password: "pass" }, $setOnInsert: { _id: id } }, { upsert: true });` |
 /// | `Entity::update`                  | Updates multiple documents based on a filter.                                    | `User::update(mongo, user::filter! { name: "Kit" }, user::update! { password: "pass".into() }).await?;` | `db.collection('user').updateMany({ name: { $eq: "Kit" } }, { $set: { password: "pass" } });` |  
 /// | `Entity::update_one`              | Updates a single document based on a filter.                                     | `Entity::update_one(mongo, by_id(id), user::update! { password: "pass".into() }).await?;`               | `db.collection('user').updateOne({ _id: { $eq: id } }, { $set: { password: "pass" } });`      |
+/// | `Entity::upsert_one`              | Updates a single document, or inserts it if none matches the filter.             | `User::upsert_one(mongo, user::filter! { name: "Kit" }, user::update! { password: "pass".into() }, user::update! { id: ObjectId::new() }).await?;` | `db.collection('user').updateOne({ name: { $eq: "Kit" } }, { $set: { password: "pass" }, $setOnInsert: { _id: id } }, { upsert: true });` |
 /// | `SelectableWithId::patch`         | Applies a patch to an existing document based on its id, and updates the struct. | `user.patch(mongo, user::update! { password: "pass".into() }).await?;`                                  | `db.collection('user').updateOne({ _id: { $eq: user.id } }, { $set: { password: "pass" } });` |
 /// | `Entity::delete`                  | Deletes multiple documents based on a filter.                                    | `User::delete(mongo, user::filter! { name: "Kit" }).await?;`                                            | `db.collection('user').deleteMany({ name: { $eq: "Kit" } });`                                 |  
 /// | `Entity::delete_one`              | Deletes a single document based on a filter.                                     | `Entity::delete_one(mongo, by_id(id)).await?;`                                                          | `db.collection('user').deleteOne({ _id: { $eq: id } });`                                      |  
@@ -134,6 +141,10 @@ mod getting_started {}
 /// Inside that module, you’ll find:
 /// - A `TypedFilter` struct for building type-safe `MongoDB` filter documents
 /// - A `TypedUpdate` struct for building type-safe `MongoDB` update documents
+/// - A `Fields` enum listing the fields, used for sorting
+/// - A `fields` module with a [`TypedField`](crate::TypedField) type for each field, used
+///   by queries that return the values of a field, like
+///   [`distinct`](crate::Entity::distinct)
 ///
 /// These types are shaped after your entity, but each field is wrapped to
 /// represent optionality and filter/update semantics.
@@ -147,6 +158,10 @@ mod getting_started {}
 /// - [`FilterOperator`](crate::FilterOperator) represents a `MongoDB`
 ///   [comparison operator](https://www.mongodb.com/docs/manual/reference/operator/query/#comparison)
 ///   that should be applied to a field.
+/// - [`SetUpdateOperator`](crate::SetUpdateOperator) and other
+///   [`UpdateOperator`](crate::UpdateOperator)s represent a `MongoDB`
+///   [update operator](https://www.mongodb.com/docs/manual/reference/operator/update/#fields)
+///   that should be applied to a field.
 ///
 /// For example, for the following struct:
 ///
@@ -164,7 +179,7 @@ mod getting_started {}
 /// mod user {
 ///     pub struct TypedFilter {
 ///         id: Field<FilterOperator<ObjectId>>,
-///         name: Field<FilterOperator<str>>,
+///         name: Field<StrFilterOperator>,
 ///     }
 ///
 ///     impl Default for TypedFilter {
@@ -177,8 +192,8 @@ mod getting_started {}
 ///     }
 ///
 ///     pub struct TypedUpdate {
-///         id: Field<ObjectId>,
-///         name: Field<String>,
+///         id: Field<OrdUpdateOperator<ObjectId>>,
+///         name: Field<SetUpdateOperator<String>>,
 ///     }
 ///
 ///     impl Default for TypedUpdate {
@@ -201,7 +216,7 @@ mod getting_started {}
 ///
 /// ```
 /// let user = User::find(mongo, user::TypedFilter {
-///     name: Field::Set(FilterOperator::Eq("Kit")),
+///     name: Field::Set(StrFilterOperator::Eq("Kit".into())),
 ///     ..Default::default()
 /// }).await?;
 /// ```
@@ -215,11 +230,11 @@ mod getting_started {}
 /// ```
 /// User::update_one(mongo,
 ///     user::TypedFilter {
-///         name: Field::Set(FilterOperator::Eq("Kit")),
+///         name: Field::Set(StrFilterOperator::Eq("Kit".into())),
 ///         ..Default::default()
 ///     },
 ///     user::TypedUpdate {
-///         name: Field::Set("K.I.".to_string()),
+///         name: Field::Set(SetUpdateOperator::Set("K.I.".to_string())),
 ///         ..Default::default()
 ///     }
 /// ).await?;
@@ -245,7 +260,7 @@ mod getting_started {}
 /// Expands to:
 /// ```
 /// let filter = user::TypedFilter {
-///     name: Field::Set(FilterOperator::Eq("Kit")),
+///     name: Field::Set(StrFilterOperator::Eq("Kit".into())),
 ///     ..Default::default()
 /// };
 /// ```
@@ -263,7 +278,7 @@ mod getting_started {}
 /// Expands to:
 /// ```
 /// let filter = user::TypedFilter {
-///     name: Field::Set(FilterOperator::Ne("Kit")),
+///     name: Field::Set(StrFilterOperator::Ne("Kit".into())),
 ///     ..Default::default()
 /// };
 /// ```
@@ -278,11 +293,344 @@ mod getting_started {}
 /// Expands to:
 /// ```
 /// let update = user::TypedUpdate {
-///     name: Field::Set("Kit".to_string()),
+///     name: Field::Set(SetUpdateOperator::Set("Kit".to_string())),
 ///     ..Default::default()
 /// };
 /// ```
 ///
+/// By default, the `update!` macro uses the `$set` operator. Other update operators can be
+/// specified explicitly, and the macro groups the fields by operator:
+///
+/// ```no_run
+/// # pub use khan::*;
+/// # use khan::{mongodb::bson::{DateTime, oid::ObjectId}};
+/// # use serde::{Deserialize, Serialize};
+/// # #[derive(Serialize, Deserialize, Entity)]
+/// # pub struct User {
+/// #     #[serde(rename = "_id")]
+/// #     pub id: ObjectId,
+/// #     pub name: String,
+/// #     pub email: String,
+/// #     pub login_count: i32,
+/// #     pub tags: Vec<String>,
+/// #     pub nickname: Option<String>,
+/// #     pub created_at: DateTime,
+/// # }
+/// # fn main() {
+/// let update = user::update! {
+///     name: "Kit".to_string(),
+///     login_count: Inc(1),
+///     tags: Push("admin".to_string()),
+///     nickname: Unset,
+/// };
+/// # }
+/// ```
+///
+/// Equivalent `MongoDB` update:
+///
+/// ```mongodb
+/// { $set: { name: "Kit" }, $inc: { login_count: 1 }, $push: { tags: "admin" }, $unset: { nickname: "" } }
+/// ```
+///
+/// ### Operators by field type
+///
+/// The operators available for a field depend on its type, so that e.g. `Gt` on a `bool`
+/// field or `Size` on a `String` field is a compile error rather than a query that never
+/// matches:
+///
+/// | Field type                                    | Operator type                                              | Operators                                                              |
+/// |-----------------------------------------------|------------------------------------------------------------|------------------------------------------------------------------------|
+/// | numbers, dates, ids                           | [`FilterOperator`](crate::FilterOperator)                  | `Eq`, `Ne`, `Gt`, `Gte`, `Lt`, `Lte`, `In`, `Nin`, `Type`              |
+/// | `String`                                      | [`StrFilterOperator`](crate::StrFilterOperator)            | same as above, and `Regex`                                             |
+/// | `Vec<T>`                                      | [`ArrayFilterOperator`](crate::ArrayFilterOperator)        | `Eq`, `Ne`, `Contains`, `In`, `Nin`, `All`, `Size`, `ElemMatch`, `Type` |
+/// | `Option<T>`                                   | [`NullableFilterOperator`](crate::NullableFilterOperator)  | `Exists`, `Null`, and the operators of `T`                             |
+/// | anything else (`bool`, enums, embedded types) | [`EqFilterOperator`](crate::EqFilterOperator)              | `Eq`, `Ne`, `In`, `Nin`, `Type`                                        |
+///
+/// ```no_run
+/// # pub use khan::*;
+/// # use khan::{mongodb::bson::{DateTime, oid::ObjectId}};
+/// # use serde::{Deserialize, Serialize};
+/// # #[derive(Serialize, Deserialize, Entity)]
+/// # pub struct User {
+/// #     #[serde(rename = "_id")]
+/// #     pub id: ObjectId,
+/// #     pub name: String,
+/// #     pub email: String,
+/// #     pub login_count: i32,
+/// #     pub tags: Vec<String>,
+/// #     pub nickname: Option<String>,
+/// #     pub created_at: DateTime,
+/// # }
+/// # use khan::{mongodb::bson, types};
+/// # fn main() {
+/// # let name_regex = types::Regex(bson::Regex { pattern: "^Kit".into(), options: String::new() });
+/// let filter = user::filter! {
+///     name: Regex(&name_regex),
+///     tags: Contains("admin"),
+///     nickname: Exists(false),
+/// };
+/// # }
+/// ```
+///
+/// Equivalent `MongoDB` filter:
+///
+/// ```mongodb
+/// { name: { $regex: /^Kit/ }, tags: { $eq: "admin" }, nickname: { $exists: false } }
+/// ```
+///
+/// The type is detected by its name, so type aliases and newtypes fall into the last
+/// category.
+///
+/// `ElemMatch` takes a typed filter of the array element type. Such filters are generated
+/// for structs that derive [`Fields`](crate::Fields):
+///
+/// ```no_run
+/// # pub use khan::*;
+/// # use khan::{mongodb::bson::oid::ObjectId};
+/// # use serde::{Deserialize, Serialize};
+/// # #[derive(Serialize, Deserialize, Entity)]
+/// # pub struct Post {
+/// #     #[serde(rename = "_id")]
+/// #     pub id: ObjectId,
+/// #     pub comments: Vec<Comment>,
+/// # }
+/// #[derive(Debug, Serialize, Deserialize, Fields)]
+/// pub struct Comment {
+///     pub text: String,
+///     pub likes: i32,
+/// }
+///
+/// # fn main() {
+/// let filter = post::filter! {
+///     comments: ElemMatch(comment::filter! { likes: Gt(&10) })
+/// };
+/// # }
+/// ```
+///
+/// Equivalent `MongoDB` filter:
+///
+/// ```mongodb
+/// { comments: { $elemMatch: { likes: { $gt: 10 } } } }
+/// ```
+///
+/// ### Borrowed and owned values
+///
+/// Values passed to `filter!` can be either borrowed or moved into the filter. A filter
+/// that only holds owned values is `'static`, so it can be built in one place (e.g. a
+/// repository layer), returned, stored in a struct, or moved into a spawned task:
+///
+/// ```no_run
+/// # pub use khan::*;
+/// # use khan::{mongodb::bson::{DateTime, oid::ObjectId}};
+/// # use serde::{Deserialize, Serialize};
+/// # #[derive(Serialize, Deserialize, Entity)]
+/// # pub struct User {
+/// #     #[serde(rename = "_id")]
+/// #     pub id: ObjectId,
+/// #     pub name: String,
+/// #     pub email: String,
+/// #     pub login_count: i32,
+/// #     pub tags: Vec<String>,
+/// #     pub nickname: Option<String>,
+/// #     pub created_at: DateTime,
+/// # }
+/// # fn main() {}
+/// fn recent_users(email: String, ids: Vec<ObjectId>, since: DateTime) -> user::TypedFilter<'static> {
+///     user::filter! {
+///         email: email,
+///         id: In(ids),
+///         created_at: Gt(since),
+///     }
+/// }
+/// #
+/// ```
+///
+/// Borrowing avoids cloning when the filter is used right away:
+///
+/// ```no_run
+/// # pub use khan::*;
+/// # use khan::{mongodb::bson::{DateTime, oid::ObjectId}};
+/// # use serde::{Deserialize, Serialize};
+/// # #[derive(Serialize, Deserialize, Entity)]
+/// # pub struct User {
+/// #     #[serde(rename = "_id")]
+/// #     pub id: ObjectId,
+/// #     pub name: String,
+/// #     pub email: String,
+/// #     pub login_count: i32,
+/// #     pub tags: Vec<String>,
+/// #     pub nickname: Option<String>,
+/// #     pub created_at: DateTime,
+/// # }
+/// # fn main() {
+/// # let email = String::from("kit@example.com");
+/// # let (first_id, second_id) = (ObjectId::new(), ObjectId::new());
+/// let filter = user::filter! {
+///     email: &email,
+///     id: In([&first_id, &second_id]),
+/// };
+/// # }
+/// ```
+///
+/// Operands are stored as [`Operand`](crate::Operand) (or `Cow<str>` for strings), and
+/// the conversion is done by [`IntoOperand`](crate::IntoOperand).
+///
+/// ### Update operators by field type
+///
+/// Like filters, the update operators available for a field depend on its type:
+///
+/// | Field type                                         | Operator type                                               | Operators                                                    |
+/// |----------------------------------------------------|-------------------------------------------------------------|--------------------------------------------------------------|
+/// | numbers                                            | [`NumUpdateOperator`](crate::NumUpdateOperator)             | `Set`, `Inc`, `Mul`, `Min`, `Max`                            |
+/// | `bson::DateTime`, `types::DateTime`                | [`DateUpdateOperator`](crate::DateUpdateOperator)           | `Set`, `Min`, `Max`, `CurrentDate`                           |
+/// | other ordered scalars (ids, timestamps, `chrono`)  | [`OrdUpdateOperator`](crate::OrdUpdateOperator)             | `Set`, `Min`, `Max`                                          |
+/// | `Vec<T>`                                           | [`ArrayUpdateOperator`](crate::ArrayUpdateOperator)         | `Set`, `Push`, `Pull`, `AddToSet`, `PopFirst`, `PopLast`     |
+/// | `Option<T>`                                        | [`NullableUpdateOperator`](crate::NullableUpdateOperator)   | `Set`, `Unset`                                               |
+/// | anything else (`String`, `bool`, enums, embedded)  | [`SetUpdateOperator`](crate::SetUpdateOperator)             | `Set`                                                        |
+///
+/// Dates are recognized by their path, so write the type as `bson::DateTime` (or
+/// `mongodb::bson::DateTime`) or `types::DateTime` to get `CurrentDate`. A bare `DateTime`
+/// may be any type with that name, e.g. `chrono::DateTime` stored as a string, so it only
+/// gets `OrdUpdateOperator`.
+///
+/// [`patch`](crate::SelectableWithId::patch) applies the same operators to the struct in
+/// memory, e.g. `Inc(1)` increments the field and `Pull(tag)` removes all elements equal
+/// to `tag`.
+///
+/// ## Combining filters
+///
+/// Any two filters for the same entity can be combined with the `&`, `|` and `!`
+/// operators. The result is still a [`Filter<Entity>`](crate::Filter), so typed filters,
+/// [`by_id`](crate::by_id) and [`UntypedFilter`](crate::UntypedFilter) can be mixed freely:
+///
+/// ```no_run
+/// # pub use khan::*;
+/// # use khan::{mongodb::bson::{DateTime, oid::ObjectId}};
+/// # use serde::{Deserialize, Serialize};
+/// # #[derive(Serialize, Deserialize, Entity)]
+/// # pub struct User {
+/// #     #[serde(rename = "_id")]
+/// #     pub id: ObjectId,
+/// #     pub name: String,
+/// #     pub email: String,
+/// #     pub login_count: i32,
+/// #     pub tags: Vec<String>,
+/// #     pub nickname: Option<String>,
+/// #     pub created_at: DateTime,
+/// # }
+/// # fn main() {
+/// # let user_id = ObjectId::new();
+/// let filter = user::filter! { email: "kit@example.com" } | by_id::<User>(user_id);
+///
+/// let filter = user::filter! { name: "Kit" } & !user::filter! { email: "kit@example.com" };
+/// # }
+/// ```
+///
+/// Equivalent `MongoDB` filters:
+///
+/// ```mongodb
+/// { $or: [{ email: { $eq: "kit@example.com" } }, { _id: user_id }] }
+///
+/// { $and: [{ name: { $eq: "Kit" } }, { $nor: [{ email: { $eq: "kit@example.com" } }] }] }
+/// ```
+///
+/// Chained `&` and `|` operators are flattened into a single `$and` / `$or` array.
+/// `$nor` is available through the [`nor`](crate::nor) function. Since `MongoDB` doesn't
+/// support a top-level `$not`, negation is expressed as `$nor` with a single operand.
+///
+/// The `filter!` macro supports the same combinations with `and`, `or`, `nor` and `not`
+/// groups, which can be nested:
+///
+/// ```no_run
+/// # pub use khan::*;
+/// # use khan::{mongodb::bson::{DateTime, oid::ObjectId}};
+/// # use serde::{Deserialize, Serialize};
+/// # #[derive(Serialize, Deserialize, Entity)]
+/// # pub struct User {
+/// #     #[serde(rename = "_id")]
+/// #     pub id: ObjectId,
+/// #     pub name: String,
+/// #     pub email: String,
+/// #     pub login_count: i32,
+/// #     pub tags: Vec<String>,
+/// #     pub nickname: Option<String>,
+/// #     pub created_at: DateTime,
+/// # }
+/// # fn main() {
+/// # let cutoff = DateTime::now();
+/// let filter = user::filter! {
+///     or { email: "kit@example.com", name: "Kit" },
+///     not { created_at: Lt(&cutoff) },
+/// };
+/// # }
+/// ```
+///
+/// Equivalent `MongoDB` filter:
+///
+/// ```mongodb
+/// {
+///   $and: [
+///     { $or: [{ email: { $eq: "kit@example.com" } }, { name: { $eq: "Kit" } }] },
+///     { $nor: [{ created_at: { $lt: cutoff } }] }
+///   ]
+/// }
+/// ```
+///
+/// Each field inside `and`, `or` and `nor` is a separate operand, so the same field may
+/// appear more than once. Fields inside `not` are combined with `$and` before negation.
+///
+/// ## Embedded documents
+///
+/// Fields of embedded documents are reached with dot-paths, as long as the embedded
+/// struct derives [`Fields`](crate::Fields):
+///
+/// ```no_run
+/// # pub use khan::*;
+/// # use khan::{mongodb::bson::oid::ObjectId};
+/// # use serde::{Deserialize, Serialize};
+/// #
+/// #[derive(Debug, Serialize, Deserialize, Fields)]
+/// pub struct Address {
+///     #[serde(rename = "c")]
+///     pub city: String,
+///     pub zip: String,
+/// }
+///
+/// #[derive(Serialize, Deserialize, Entity)]
+/// pub struct User {
+///     #[serde(rename = "_id")]
+///     pub id: ObjectId,
+///     pub address: Address,
+///     pub previous_addresses: Vec<Address>,
+/// }
+///
+/// # async fn example(mongo: Mongo<'_>) -> mongodb::error::Result<()> {
+/// let filter = user::filter! { address.city: "Berlin", previous_addresses.zip: Ne("10115") };
+///
+/// User::update_one(mongo, filter, user::update! { address.zip: "10117".into() }).await?;
+/// # Ok(())
+/// # }
+/// # fn main() {}
+/// ```
+///
+/// Equivalent `MongoDB` filter and update:
+///
+/// ```mongodb
+/// { $and: [{ "address.c": { $eq: "Berlin" } }, { "previous_addresses.zip": { $ne: "10115" } }] }
+///
+/// { $set: { "address.zip": "10117" } }
+/// ```
+///
+/// Dot-paths can be as deep as needed, and `#[serde(rename = "...")]` is honored at every
+/// level. Operators are picked by the type of the embedded field, just like for top-level
+/// fields. The helper module of the embedded struct (`address` above) must be in scope
+/// wherever the `filter!` or `update!` macro is used.
+///
+/// Filtering through `Option<T>` and `Vec<T>` fields is supported, following the `MongoDB`
+/// semantics of dot-paths: a `Vec<T>` matches if any of its elements matches. Updates
+/// through `Vec<T>` fields aren't, since `MongoDB` needs positional operators to update
+/// array elements.
+///
 /// ## Untyped filters and updates
 ///
 /// While `TypedFilter` and `TypedUpdate` are recommended in most cases
@@ -292,13 +640,13 @@ mod getting_started {}
 /// for more complex use cases.
 ///
 /// When you need to use operators that are not covered by the typed API — such as
-/// `$slice`, `$elemMatch`, or computed expressions — you can construct an `UntypedFilter`
+/// `$text`, `$mod`, or computed expressions — you can construct an `UntypedFilter`
 /// directly from raw BSON:
 ///
 /// ```
 /// let filter = UntypedFilter::new(bson::doc! {
-///     "name": {
-///         "$regex": "^Kit$"
+///     "$text": {
+///         "$search": "Kit"
 ///     }
 /// });
 ///
@@ -306,8 +654,8 @@ mod getting_started {}
 /// ```
 ///
 /// Similarly, you can use `UntypedUpdate` for expressing complex update operations
-/// that go beyond basic `$set` — for example, `$push`, `$slice`, `$pop`, or updates
-/// on deeply nested fields:
+/// that aren't covered by the typed operators — for example, `$push` with `$slice`, or
+/// `$rename`. The document is passed to `MongoDB` as is, so it must contain the operators:
 ///
 /// ```
 /// let update = UntypedUpdate::new(bson::doc! {
@@ -319,6 +667,17 @@ mod getting_started {}
 /// User::update_one(mongo, user::filter! { id: user_id }, update).await?;
 /// ```
 ///
+/// Earlier versions wrapped the document of an `UntypedUpdate` in `$set`, so that
+/// `UntypedUpdate::new(doc! { "name": "Kit" })` set the name. Such a document would now
+/// replace the whole document, so updates fail with an error if any of the top-level keys
+/// isn't an operator. Wrap the fields in `$set` instead:
+///
+/// ```
+/// # use khan::{UntypedUpdate, mongodb::bson};
+/// # struct User;
+/// let update = UntypedUpdate::<User>::new(bson::doc! { "$set": { "name": "Kit" } });
+/// ```
+///
 /// ### `Columns` enum
 ///
 /// Every entity also gets a `Columns` enum generated inside its helper module. This enum
@@ -436,7 +795,7 @@ mod filters_and_updates {}
 /// These projection structs implement the [`Selectable`](crate::Selectable) trait, and
 /// support common query methods such as:
 /// - `find`
-/// - `find_with_opts`
+/// - `query`
 /// - `find_one`
 /// - `find_one_and_update`
 ///
@@ -466,6 +825,348 @@ mod filters_and_updates {}
 ///
 mod projections {}
 
+/// # Aggregations
+///
+/// [`Entity::aggregate`](crate::Entity::aggregate) starts a typed
+/// [`Pipeline`](crate::Pipeline) over the collection of the entity. Its stages take the
+/// same filters and `Fields` as queries do:
+///
+/// - `filter` adds a `$match` stage
+/// - `sort`, `skip`, and `limit` add `$sort`, `$skip`, and `$limit` stages
+/// - `unwind` adds an `$unwind` stage
+/// - `project::<S>()` adds a `$project` stage for a projection struct and returns `S`
+/// - `group::<T>(...)` adds a `$group` stage and returns `T`
+///
+/// The output of `$group` is a struct deriving [`Fields`](macro@crate::Fields), with the
+/// group key renamed to `_id`. The stages after it refer to the fields of that struct:
+///
+/// ```no_run
+/// # pub use khan::*;
+/// # use khan::mongodb::bson::oid::ObjectId;
+/// # use serde::{Deserialize, Serialize};
+/// # #[derive(Serialize, Deserialize, Entity)]
+/// # pub struct Post {
+/// #     #[serde(rename = "_id")]
+/// #     pub id: ObjectId,
+/// #     pub author: ObjectId,
+/// #     pub published: bool,
+/// #     pub likes: i64,
+/// # }
+/// #
+/// #[derive(Serialize, Deserialize, Fields)]
+/// pub struct AuthorStats {
+///     #[serde(rename = "_id")]
+///     pub author: ObjectId,
+///     pub posts: i64,
+///     pub likes: i64,
+/// }
+///
+/// # async fn example(mongo: Mongo<'_>) -> mongodb::error::Result<()> {
+/// let top_authors = Post::aggregate()
+///     .filter(post::filter! { published: true })
+///     .group::<AuthorStats>(
+///         Group::by(post::Fields::Author)
+///             .with(author_stats::Fields::Posts, Accumulator::Count)
+///             .with(author_stats::Fields::Likes, Accumulator::Sum(post::Fields::Likes)),
+///     )
+///     .filter(author_stats::filter! { posts: Gte(10) })
+///     .sort(author_stats::Fields::Likes, Order::Desc)
+///     .limit(10)
+///     .all(mongo)
+///     .await?;
+/// # Ok(())
+/// # }
+/// # fn main() {}
+/// ```
+///
+/// Equivalent `MongoDB` query:
+///
+/// ```js
+/// db.collection('post').aggregate([
+///     { $match: { published: { $eq: true } } },
+///     { $group: { _id: "$author", posts: { $sum: 1 }, likes: { $sum: "$likes" } } },
+///     { $match: { posts: { $gte: 10 } } },
+///     { $sort: { likes: -1 } },
+///     { $limit: 10 },
+/// ]);
+/// ```
+///
+/// Stages that change the shape of the documents in other ways, like `unwind`, keep
+/// referring to the original fields. Use `output::<T>()` to deserialize the results into a
+/// matching type.
+mod aggregations {}
+
+/// # Relations
+///
+/// Entities usually reference each other by id. Marking such a field with
+/// `#[entity(references = ...)]` declares a [`Relation`](crate::Relation), which can be used
+/// to load the referenced entities:
+///
+/// ```no_run
+/// # pub use khan::*;
+/// # use khan::mongodb::bson::oid::ObjectId;
+/// # use serde::{Deserialize, Serialize};
+/// # #[derive(Serialize, Deserialize, Entity)]
+/// # pub struct Post {
+/// #     #[serde(rename = "_id")]
+/// #     pub id: ObjectId,
+/// # }
+/// # #[derive(Clone, Serialize, Deserialize, Entity)]
+/// # #[entity(projections(PublicProfile(id, name)))]
+/// # pub struct User {
+/// #     #[serde(rename = "_id")]
+/// #     pub id: ObjectId,
+/// #     pub name: String,
+/// # }
+/// #
+/// #[derive(Serialize, Deserialize, Entity)]
+/// pub struct Comment {
+///     #[serde(rename = "_id")]
+///     pub id: ObjectId,
+///     #[entity(references = Post)]
+///     pub post_id: ObjectId,
+///     #[entity(references = User)]
+///     pub author_id: Option<ObjectId>,
+///     pub text: String,
+/// }
+/// # fn main() {}
+/// ```
+///
+/// The derive generates `comment::relations::PostId` and `comment::relations::AuthorId`.
+/// The field can be an `Option` of the id of the referenced entity.
+///
+/// There are two ways to load the referenced entities, both pairing each entity with the
+/// one it references in a [`WithRelated`](crate::WithRelated):
+///
+/// - [`Relation::load`](crate::Relation::load) takes entities that were already fetched, and
+///   fetches the referenced ones with a single `$in` query. Entities referenced more than
+///   once are cloned, so the selected type must implement `Clone`.
+/// - [`Pipeline::lookup`](crate::Pipeline::lookup) joins them on the server with `$lookup`
+///
+/// ```no_run
+/// # pub use khan::*;
+/// # use khan::mongodb::bson::oid::ObjectId;
+/// # use serde::{Deserialize, Serialize};
+/// # #[derive(Serialize, Deserialize, Entity)]
+/// # pub struct Post {
+/// #     #[serde(rename = "_id")]
+/// #     pub id: ObjectId,
+/// # }
+/// # #[derive(Clone, Serialize, Deserialize, Entity)]
+/// # #[entity(projections(PublicProfile(id, name)))]
+/// # pub struct User {
+/// #     #[serde(rename = "_id")]
+/// #     pub id: ObjectId,
+/// #     pub name: String,
+/// # }
+/// # #[derive(Serialize, Deserialize, Entity)]
+/// # pub struct Comment {
+/// #     #[serde(rename = "_id")]
+/// #     pub id: ObjectId,
+/// #     #[entity(references = Post)]
+/// #     pub post_id: ObjectId,
+/// #     #[entity(references = User)]
+/// #     pub author_id: Option<ObjectId>,
+/// #     pub text: String,
+/// # }
+/// # async fn example(mut mongo: Mongo<'_>, post: Post) -> mongodb::error::Result<()> {
+/// let comments = Comment::find(mongo.rb(), comment::filter! { post_id: post.id }).await?;
+/// let comments = comment::relations::AuthorId::load::<User>(mongo.rb(), comments).await?;
+///
+/// let comments = Comment::aggregate()
+///     .filter(comment::filter! { post_id: post.id })
+///     .lookup::<comment::relations::AuthorId>()
+///     .all(mongo)
+///     .await?;
+/// # Ok(())
+/// # }
+/// # fn main() {}
+/// ```
+mod relations {}
+
+/// # Indexes
+///
+/// Indexes are declared with the `indexes` attribute, each with a name and its keys in
+/// order, with `1` for ascending and `-1` for descending:
+///
+/// ```no_run
+/// # pub use khan::*;
+/// # use khan::mongodb::bson::{DateTime, oid::ObjectId};
+/// # use serde::{Deserialize, Serialize};
+/// #
+/// #[derive(Serialize, Deserialize, Entity)]
+/// #[entity(indexes(
+///     by_email(keys(email = 1), unique),
+///     by_org_and_name(keys(org_id = 1, name = 1)),
+///     by_invite_code(keys(invite_code = 1), unique, partial = user::filter! {
+///         invite_code: Exists(true)
+///     }),
+///     expire_sessions(keys(session_started_at = 1), expire_after = 86400),
+/// ))]
+/// pub struct User {
+///     #[serde(rename = "_id")]
+///     pub id: ObjectId,
+///     pub email: String,
+///     pub org_id: ObjectId,
+///     pub name: String,
+///     pub invite_code: Option<String>,
+///     pub session_started_at: DateTime,
+/// }
+/// # fn main() {}
+/// ```
+///
+/// Besides the keys, an index can have:
+/// - `unique` to reject documents with the same keys
+/// - `sparse` to skip documents missing the keys
+/// - `partial = <filter>` to only index the documents matching a filter of the entity
+/// - `expire_after = <seconds>` to delete documents when the date in the key is older than
+///   that. Such an index must have a single key.
+/// - `options = <IndexOptions>` for any other [options](mongodb::options::IndexOptions),
+///   which the attributes above are applied on top of
+///
+/// The indexes are returned by [`Entity::indexes`](crate::Entity::indexes). With the `meta`
+/// feature, every entity in the binary is registered in
+/// [`meta::entity_metadata`](crate::meta::entity_metadata), so a single call to
+/// [`meta::enforce_indexes`](crate::meta::enforce_indexes) on startup creates the indexes of
+/// all of them.
+///
+/// `enforce_indexes` only ever creates indexes. When an index is renamed, removed, or its keys
+/// or options change, [`meta::plan_indexes`](crate::meta::plan_indexes) compares the existing
+/// indexes of every registered collection with the declared ones and returns a plan of indexes
+/// to create, drop, and rebuild. Print it for a dry run, then apply it:
+///
+/// ```no_run
+/// # use khan::{Mongo, mongodb};
+/// # async fn example(mut mongo: Mongo<'_>) -> mongodb::error::Result<()> {
+/// let plan = khan::meta::plan_indexes(mongo.rb()).await?;
+/// println!("{plan}");
+/// plan.apply(mongo).await?;
+/// # Ok(())
+/// # }
+/// # fn main() {}
+/// ```
+mod indexes {}
+
+/// # Schema validation
+///
+/// With the `schema` feature, entities that also derive
+/// [`JsonSchema`](schemars::JsonSchema) expose their schema through
+/// [`EntityMetadata::try_json_schema`](crate::meta::EntityMetadata::try_json_schema).
+/// `MongoDB` doesn't support the `integer` type, so use [`types::Int32`](crate::types::Int32)
+/// and [`types::Int64`](crate::types::Int64) instead of std integer types in such entities.
+/// `try_json_schema` returns the keywords unsupported by `MongoDB` with their paths in the
+/// schema, or rewrites the fixable ones: `integer` becomes `bsonType: ["int", "long"]`, and
+/// `format`, `default` and `id` are stripped. The deprecated `json_schema` panics on them
+/// instead.
+///
+/// [`meta::enforce_validators`](crate::meta::enforce_validators) installs these schemas as
+/// `$jsonSchema` validators of the registered collections, creating the missing ones, so
+/// that the database itself rejects documents the entities couldn't have produced. Its
+/// `rewrite` argument is passed to `try_json_schema`, and the same value should be passed to
+/// the audit and snapshot functions below, so that they all see the installed schemas:
+///
+/// ```no_run
+/// # use khan::{Mongo, mongodb};
+/// # use khan::mongodb::options::{ValidationAction, ValidationLevel};
+/// # async fn example(mongo: Mongo<'_>) -> mongodb::error::Result<()> {
+/// khan::meta::enforce_validators(mongo, ValidationLevel::Strict, ValidationAction::Error, true)
+///     .await?;
+/// # Ok(())
+/// # }
+/// # fn main() {}
+/// ```
+///
+/// [`ValidationLevel::Moderate`](mongodb::options::ValidationLevel::Moderate) only validates
+/// inserts and updates of documents that are already valid, and
+/// [`ValidationAction::Warn`](mongodb::options::ValidationAction::Warn) logs violations instead
+/// of rejecting them.
+///
+/// Before turning validation on, [`EntityMetadata::audit`](crate::meta::EntityMetadata::audit)
+/// finds the existing documents that would fail it. It queries the documents that don't match
+/// the schema with `$jsonSchema`, so the server checks them exactly as the validator would,
+/// tries to deserialize the rest into the entity, and reports the number of documents per kind
+/// of failure, with some sample `_id`s:
+///
+/// ```no_run
+/// # use khan::{Mongo, mongodb};
+/// # async fn example(mut mongo: Mongo<'_>) -> mongodb::error::Result<()> {
+/// for metadata in khan::meta::entity_metadata() {
+///     println!("{}", metadata.audit(mongo.rb(), 5, true).await?);
+/// }
+/// # Ok(())
+/// # }
+/// # fn main() {}
+/// ```
+///
+/// To catch accidental schema changes in code review, commit a
+/// [`SchemaSnapshot`](crate::meta::SchemaSnapshot) of all entities, with their collections,
+/// fields and BSON types, indexes, and JSON schemas, and check it in a test. The drift between
+/// the snapshot and the registry is classified as `Index` if only indexes changed, `Additive`
+/// if existing documents remain valid, and `Breaking` if fields were removed, retyped, or made
+/// required, or if other constraints of the JSON schema changed:
+///
+/// ```no_run
+/// # use khan::meta::SchemaSnapshot;
+/// # fn main() {
+/// let snapshot = SchemaSnapshot::from_json(&std::fs::read_to_string("schema.json").unwrap()).unwrap();
+/// let drift = snapshot.drift(true).unwrap();
+/// // Update the snapshot with `SchemaSnapshot::current(true).unwrap().to_json()`
+/// assert!(drift.is_empty(), "{drift}");
+/// # }
+/// ```
+mod schema_validation {}
+
+/// # Migrations
+///
+/// When an entity changes, stored documents are evolved with migrations, registered next to
+/// the entity with [`migration!`](crate::migration). Each one has a version, unique per entity,
+/// and a name, and updates the documents matching a filter, updates them with an aggregation
+/// pipeline, or runs any function taking a [`Mongo`](crate::Mongo):
+///
+/// ```no_run
+/// # pub use khan::*;
+/// # use khan::mongodb::bson::{doc, oid::ObjectId};
+/// # use serde::{Deserialize, Serialize};
+/// # #[derive(Serialize, Deserialize, Entity)]
+/// # pub struct User {
+/// #     #[serde(rename = "_id")]
+/// #     pub id: ObjectId,
+/// #     pub email: String,
+/// #     pub nickname: Option<String>,
+/// # }
+/// #
+/// khan::migration!(User, 1, "add_nickname", update(user::filter! {}, user::update! {
+///     nickname: None
+/// }));
+/// khan::migration!(User, 2, "lowercase_email", pipeline(user::filter! {}, [
+///     doc! { "$set": { "email": { "$toLower": "$email" } } },
+/// ]));
+/// # fn main() {}
+/// ```
+///
+/// [`meta::Migrator`](crate::meta::Migrator) runs the pending ones on startup, and records
+/// them in the `khan_migrations` collection under the path of the entity type, e.g.
+/// `app::models::User`, so moving an entity to another module makes its migrations run
+/// again. It holds a lock in that collection while running,
+/// so that when several instances start at once, only one of them runs the migrations and the
+/// others fail. Migrations are forward-only: adding one with a version lower than an applied
+/// migration of the same entity is an error. A dry run lists the pending migrations without
+/// running them:
+///
+/// ```no_run
+/// # use khan::{Mongo, mongodb};
+/// # use khan::meta::Migrator;
+/// # async fn example(mut mongo: Mongo<'_>) -> mongodb::error::Result<()> {
+/// for migration in Migrator::new().dry_run(true).run(mongo.rb()).await? {
+///     println!("pending: {migration}");
+/// }
+/// Migrator::new().run(mongo).await?;
+/// # Ok(())
+/// # }
+/// # fn main() {}
+/// ```
+mod migrations {}
+
 /// # Transactions and locking
 ///
 /// All methods on [`Entity`](crate::Entity), [`Selectable`](crate::Selectable), and
@@ -555,7 +1256,7 @@ mod projections {}
 ///             )
 ///             .await?;
 ///
-///             if (!result.matched()) {
+///             if result.matched() == 0 {
 ///                 return Error::custom("Post is not found");
 ///             }
 ///
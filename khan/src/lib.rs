@@ -63,27 +63,53 @@
     clippy::missing_errors_doc
 )]
 
-use futures_util::{FutureExt, TryStreamExt, future::BoxFuture};
+use futures_util::{
+    FutureExt, StreamExt, TryFutureExt, TryStreamExt,
+    future::BoxFuture,
+    stream::{self, BoxStream},
+};
 use mongodb::{
     ClientSession, Collection, Database, IndexModel,
-    bson::{self, Bson, Document, bson, doc, oid::ObjectId},
+    action::{Action, CountDocuments, Find, FindOne},
+    bson::{self, Bson, Document, bson, doc, oid::ObjectId, spec::ElementType},
     error::Result,
+    options::{Collation, FindOptions, Hint},
 };
 use serde::{Serialize, de::DeserializeOwned};
-use std::{collections::BTreeMap, fmt::Display, marker::PhantomData, sync::LazyLock};
+use std::{
+    borrow::Cow, collections::BTreeMap, fmt::Display, marker::PhantomData, sync::LazyLock,
+    time::Duration,
+};
 
-pub use khan_macros::Entity;
+pub use khan_macros::{Entity, Fields};
 #[doc(hidden)]
 pub use khan_macros::{construct_filter, construct_update};
 
+/// Called by `#[derive(Entity)]`. Without the `meta` feature there is no registry, so it
+/// does nothing.
+#[cfg(not(feature = "meta"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! register_entity {
+    ($entity:ty) => {};
+}
+
+pub use mongodb;
+
 pub mod guides;
 #[cfg(feature = "meta")]
 pub mod meta;
 #[cfg(feature = "meta")]
+#[doc(hidden)]
+pub use inventory;
+#[cfg(feature = "schema")]
+#[doc(hidden)]
+pub use schemars;
+
 pub mod types;
 
 pub trait Entity: SelectableWithId<Self> + Serialize {
-    type Id: Copy + Serialize + Send + 'static;
+    type Id: Copy + Serialize + DeserializeOwned + Send + 'static;
 
     type Fields: Display + Send + 'static;
 
@@ -97,6 +123,11 @@ pub trait Entity: SelectableWithId<Self> + Serialize {
         &[]
     }
 
+    /// Starts an aggregation pipeline over the collection.
+    fn aggregate() -> Pipeline<Self> {
+        Pipeline::new()
+    }
+
     fn count<'a>(mongo: Mongo<'a>, filter: impl Filter<Self> + 'a) -> BoxFuture<'a, Result<u64>> {
         async move {
             let Mongo { db, session } = mongo;
@@ -119,6 +150,53 @@ pub trait Entity: SelectableWithId<Self> + Serialize {
         .boxed()
     }
 
+    /// Finds the distinct values of the field among the entities matching the filter. For
+    /// array fields, these are the distinct elements of the arrays. `null` is skipped.
+    ///
+    /// ```no_run
+    /// # pub use khan::*;
+    /// # use khan::{mongodb::{bson::oid::ObjectId, error::Result}};
+    /// # use serde::{Deserialize, Serialize};
+    /// # #[derive(Serialize, Deserialize, Entity)]
+    /// # pub struct User {
+    /// #     #[serde(rename = "_id")]
+    /// #     pub id: ObjectId,
+    /// #     pub city: String,
+    /// #     pub active: bool,
+    /// # }
+    /// # async fn example(mongo: Mongo<'_>) -> Result<()> {
+    /// let cities: Vec<String> = User::distinct(mongo, user::fields::City, user::filter! {
+    ///     active: true
+    /// }).await?;
+    /// # Ok(())
+    /// # }
+    /// # fn main() {}
+    /// ```
+    fn distinct<'a, F: TypedField<Entity = Self>>(
+        mongo: Mongo<'a>,
+        field: F,
+        filter: impl Filter<Self> + 'a,
+    ) -> BoxFuture<'a, Result<Vec<F::Value>>> {
+        let name = field.name();
+
+        async move {
+            let Mongo { db, session } = mongo;
+            let collection = Self::collection(db);
+
+            let values =
+                with_session!(collection.distinct(name, filter.to_document()), session).await?;
+
+            let values = values
+                .into_iter()
+                .filter(|value| *value != Bson::Null)
+                .map(bson::from_bson)
+                .collect::<std::result::Result<_, _>>()?;
+
+            Ok(values)
+        }
+        .boxed()
+    }
+
     fn insert<'a>(&'a self, mongo: Mongo<'a>) -> BoxFuture<'a, Result<()>> {
         async move {
             let Mongo { db, session } = mongo;
@@ -140,14 +218,17 @@ pub trait Entity: SelectableWithId<Self> + Serialize {
         .boxed()
     }
 
-    fn insert_many<'a>(mongo: Mongo<'a>, entities: &'a [Self]) -> BoxFuture<'a, Result<()>> {
+    fn insert_many<'a>(
+        mongo: Mongo<'a>,
+        entities: &'a [Self],
+    ) -> BoxFuture<'a, Result<InsertManyResult<Self::Id>>> {
         async move {
             let Mongo { db, session } = mongo;
             let collection = Self::collection(db);
 
-            with_session!(collection.insert_many(entities), session).await?;
+            let result = with_session!(collection.insert_many(entities), session).await?;
 
-            Ok(())
+            InsertManyResult::try_from(result)
         }
         .boxed()
     }
@@ -168,18 +249,18 @@ pub trait Entity: SelectableWithId<Self> + Serialize {
         mongo: Mongo<'a>,
         filter: impl Filter<Self> + 'a,
         update: impl Update<Self> + 'a,
-    ) -> BoxFuture<'a, Result<()>> {
+    ) -> BoxFuture<'a, Result<UpdateResult<Self::Id>>> {
         async move {
             let Mongo { db, session } = mongo;
             let collection = Self::collection(db);
 
-            with_session!(
-                collection.update_many(filter.to_document(), doc! { "$set": update.to_document() }),
+            let result = with_session!(
+                collection.update_many(filter.to_document(), update_document(&update)?),
                 session
             )
             .await?;
 
-            Ok(())
+            UpdateResult::try_from(result)
         }
         .boxed()
     }
@@ -188,18 +269,50 @@ pub trait Entity: SelectableWithId<Self> + Serialize {
         mongo: Mongo<'a>,
         filter: impl Filter<Self> + 'a,
         update: impl Update<Self> + 'a,
-    ) -> BoxFuture<'a, Result<()>> {
+    ) -> BoxFuture<'a, Result<UpdateResult<Self::Id>>> {
         async move {
             let Mongo { db, session } = mongo;
             let collection = Self::collection(db);
 
-            with_session!(
-                collection.update_one(filter.to_document(), doc! { "$set": update.to_document() }),
+            let result = with_session!(
+                collection.update_one(filter.to_document(), update_document(&update)?),
                 session
             )
             .await?;
 
-            Ok(())
+            UpdateResult::try_from(result)
+        }
+        .boxed()
+    }
+
+    /// Updates a single document based on a filter, or inserts a new document if none
+    /// matches.
+    ///
+    /// `on_insert` is only applied when a document is inserted (`$setOnInsert`), and must
+    /// only set fields. The inserted document consists of the equality conditions of the
+    /// filter, `update` and `on_insert`, so together they must set every field of the
+    /// entity except `_id`. A field can't be set by both `update` and `on_insert`.
+    fn upsert_one<'a>(
+        mongo: Mongo<'a>,
+        filter: impl Filter<Self> + 'a,
+        update: impl Update<Self> + 'a,
+        on_insert: impl Update<Self> + 'a,
+    ) -> BoxFuture<'a, Result<UpdateResult<Self::Id>>> {
+        async move {
+            let Mongo { db, session } = mongo;
+            let collection = Self::collection(db);
+
+            let update = upsert_document(update_document(&update)?, update_document(&on_insert)?)?;
+
+            let result = with_session!(
+                collection
+                    .update_one(filter.to_document(), update)
+                    .upsert(true),
+                session
+            )
+            .await?;
+
+            UpdateResult::try_from(result)
         }
         .boxed()
     }
@@ -225,14 +338,18 @@ pub trait Entity: SelectableWithId<Self> + Serialize {
         )
     }
 
-    fn delete<'a>(mongo: Mongo<'a>, filter: impl Filter<Self> + 'a) -> BoxFuture<'a, Result<()>> {
+    fn delete<'a>(
+        mongo: Mongo<'a>,
+        filter: impl Filter<Self> + 'a,
+    ) -> BoxFuture<'a, Result<DeleteResult>> {
         async move {
             let Mongo { db, session } = mongo;
             let collection = Self::collection(db);
 
-            with_session!(collection.delete_many(filter.to_document()), session).await?;
+            let result =
+                with_session!(collection.delete_many(filter.to_document()), session).await?;
 
-            Ok(())
+            Ok(DeleteResult::from(result))
         }
         .boxed()
     }
@@ -240,14 +357,15 @@ pub trait Entity: SelectableWithId<Self> + Serialize {
     fn delete_one<'a>(
         mongo: Mongo<'a>,
         filter: impl Filter<Self> + 'a,
-    ) -> BoxFuture<'a, Result<()>> {
+    ) -> BoxFuture<'a, Result<DeleteResult>> {
         async move {
             let Mongo { db, session } = mongo;
             let collection = Self::collection(db);
 
-            with_session!(collection.delete_one(filter.to_document()), session).await?;
+            let result =
+                with_session!(collection.delete_one(filter.to_document()), session).await?;
 
-            Ok(())
+            Ok(DeleteResult::from(result))
         }
         .boxed()
     }
@@ -285,66 +403,31 @@ pub trait Selectable<E: Entity>: DeserializeOwned + Send + Sync + 'static {
         })
     }
 
-    fn find_with_opts<'a>(
+    /// Starts a query for entities matching a filter, which can be refined with sorting,
+    /// pagination and other options before running it.
+    fn query(filter: impl Filter<E>) -> Query<E, Self> {
+        Query::new(&filter)
+    }
+
+    /// Finds entities based on a filter, streaming them from the cursor instead of
+    /// collecting them into a `Vec`. `batch_size` limits the number of documents fetched
+    /// from the server at once.
+    fn find_stream<'a>(
         mongo: Mongo<'a>,
         filter: impl Filter<E> + 'a,
-        skip: Option<u64>,
-        limit: Option<i64>,
-        sort: Option<BTreeMap<E::Fields, Order>>,
-    ) -> BoxFuture<'a, Result<Vec<Self>>> {
-        async move {
-            let Mongo { db, session } = mongo;
-            let collection = db.collection(E::COLLECTION_NAME);
-
-            let mut query = collection.find(filter.to_document());
-
-            if let Some(projection) = Self::projection() {
-                query = query.projection(projection);
-            }
-
-            if let Some(skip) = skip {
-                query = query.skip(skip);
-            }
+        batch_size: Option<u32>,
+    ) -> BoxStream<'a, Result<Self>> {
+        let mut query = Self::query(filter);
 
-            if let Some(limit) = limit {
-                query = query.limit(limit);
-            }
-
-            if let Some(sort) = sort {
-                let sort_doc = sort
-                    .into_iter()
-                    .map(|(k, v)| {
-                        (
-                            k.to_string(),
-                            match v {
-                                Order::Asc => bson!(1),
-                                Order::Desc => bson!(-1),
-                            },
-                        )
-                    })
-                    .collect();
-                query = query.sort(sort_doc);
-            }
-
-            let entities = match session {
-                Some(session) => {
-                    query
-                        .session(&mut *session)
-                        .await?
-                        .stream(&mut *session)
-                        .try_collect()
-                        .await
-                }
-                None => query.await?.try_collect().await,
-            }?;
-
-            Ok(entities)
+        if let Some(batch_size) = batch_size {
+            query = query.batch_size(batch_size);
         }
-        .boxed()
+
+        query.stream(mongo)
     }
 
     fn find<'a>(mongo: Mongo<'a>, filter: impl Filter<E> + 'a) -> BoxFuture<'a, Result<Vec<Self>>> {
-        Self::find_with_opts(mongo, filter, None, None, None)
+        Self::query(filter).all(mongo)
     }
 
     fn find_one<'a>(
@@ -367,6 +450,39 @@ pub trait Selectable<E: Entity>: DeserializeOwned + Send + Sync + 'static {
         .boxed()
     }
 
+    /// Finds entities by their ids with a single query, returning an entry for each id in
+    /// the order they were given, and `None` for the ids that weren't found.
+    fn find_by_ids(
+        mongo: Mongo<'_>,
+        ids: impl IntoIterator<Item = E::Id>,
+    ) -> BoxFuture<'_, Result<FoundByIds<E::Id, Self>>> {
+        let ids = ids.into_iter().collect::<Vec<_>>();
+
+        async move {
+            let Mongo { db, session } = mongo;
+            let collection = db.collection::<Document>(E::COLLECTION_NAME);
+
+            // `_id` is needed to match the documents with the ids, even if the projection
+            // leaves it out
+            let projection = Self::projection().map(|mut projection| {
+                projection.insert("_id", 1);
+                projection
+            });
+
+            let query = collection
+                .find(by_ids::<E>(ids.iter().copied()).to_document())
+                .optional(projection, Find::projection);
+
+            let documents = cursor_stream(query, session)
+                .await?
+                .try_collect::<Vec<_>>()
+                .await?;
+
+            FoundByIds::match_documents(ids, documents)
+        }
+        .boxed()
+    }
+
     fn find_one_and_lock<'a>(
         trx: Transaction<'a>,
         filter: impl Filter<E> + 'a,
@@ -382,13 +498,60 @@ pub trait Selectable<E: Entity>: DeserializeOwned + Send + Sync + 'static {
         mongo: Mongo<'a>,
         filter: impl Filter<E> + 'a,
         update: impl Update<E> + 'a,
+    ) -> BoxFuture<'a, Result<Option<Self>>> {
+        Self::find_one_and_update_with_opts(mongo, filter, update, ReturnDocument::Before, None)
+    }
+
+    /// Finds and updates a single entity, returning it as it was before or after the
+    /// update. If several documents match the filter, the first one in `sort` order is
+    /// updated, e.g. the oldest job of a queue.
+    fn find_one_and_update_with_opts<'a>(
+        mongo: Mongo<'a>,
+        filter: impl Filter<E> + 'a,
+        update: impl Update<E> + 'a,
+        return_document: ReturnDocument,
+        sort: Option<Vec<(E::Fields, Order)>>,
+    ) -> BoxFuture<'a, Result<Option<Self>>> {
+        async move {
+            let Mongo { db, session } = mongo;
+            let collection = db.collection(E::COLLECTION_NAME);
+
+            let mut query = collection
+                .find_one_and_update(filter.to_document(), update_document(&update)?)
+                .return_document(return_document.into());
+
+            if let Some(projection) = Self::projection() {
+                query = query.projection(projection);
+            }
+
+            if let Some(sort) = sort {
+                query = query.sort(sort_document(sort));
+            }
+
+            let entity = with_session!(query, session).await?;
+
+            Ok(entity)
+        }
+        .boxed()
+    }
+
+    /// Like [`Entity::upsert_one`], but returns the document as it was before the update,
+    /// or `None` if the document was inserted.
+    fn find_one_and_upsert<'a>(
+        mongo: Mongo<'a>,
+        filter: impl Filter<E> + 'a,
+        update: impl Update<E> + 'a,
+        on_insert: impl Update<E> + 'a,
     ) -> BoxFuture<'a, Result<Option<Self>>> {
         async move {
             let Mongo { db, session } = mongo;
             let collection = db.collection(E::COLLECTION_NAME);
 
-            let mut query =
-                collection.find_one_and_update(filter.to_document(), update.to_document());
+            let update = upsert_document(update_document(&update)?, update_document(&on_insert)?)?;
+
+            let mut query = collection
+                .find_one_and_update(filter.to_document(), update)
+                .upsert(true);
             if let Some(projection) = Self::projection() {
                 query = query.projection(projection);
             }
@@ -460,138 +623,1690 @@ pub trait SelectableWithId<E: Entity>: Selectable<E> {
     }
 }
 
-#[derive(Debug)]
-pub struct Mongo<'a> {
-    pub db: &'a Database,
-    pub session: Option<&'a mut ClientSession>,
+/// The document of an update, which must consist of update operators. `update` and
+/// `update_one` used to wrap [`UntypedUpdate`] documents in `$set`, so a document of plain
+/// fields is rejected here, instead of being taken by the server as a replacement.
+fn update_document<E>(update: &impl Update<E>) -> Result<Document> {
+    let document = update.to_document();
+
+    if let Some(key) = document.keys().find(|key| !key.starts_with('$')) {
+        return Err(mongodb::error::Error::custom(format!(
+            "update documents must only contain update operators, found `{key}`. Wrap the \
+             fields in `$set`, e.g. `{{ $set: {{ {key}: ... }} }}`"
+        )));
+    }
+
+    Ok(document)
 }
 
-impl<'a> Mongo<'a> {
-    pub fn new(db: &'a Database) -> Self {
-        Self { db, session: None }
+/// Adds the fields set by `on_insert` to the update as `$setOnInsert`.
+fn upsert_document(mut update: Document, on_insert: Document) -> Result<Document> {
+    let mut on_insert = on_insert.into_iter();
+
+    match (on_insert.next(), on_insert.next()) {
+        (None, _) => {}
+        (Some((operator, Bson::Document(changes))), None) if operator == "$set" => {
+            // The server rejects updates where two operators touch the same field, or a
+            // field and one of its parents
+            let conflict = changes.keys().find(|key| {
+                update.values().any(|fields| match fields {
+                    Bson::Document(fields) => fields.keys().any(|path| paths_overlap(path, key)),
+                    _ => false,
+                })
+            });
+
+            if let Some(key) = conflict {
+                return Err(mongodb::error::Error::custom(format!(
+                    "`{key}` of an upsert is set by both `update` and `on_insert`"
+                )));
+            }
+
+            update.insert("$setOnInsert", changes);
+        }
+        _ => {
+            return Err(mongodb::error::Error::custom(
+                "`on_insert` of an upsert can only set fields".to_owned(),
+            ));
+        }
     }
 
-    pub fn new_with_session(db: &'a Database, session: &'a mut ClientSession) -> Self {
+    Ok(update)
+}
+
+/// Whether two dotted paths are the same field, or one is a parent of the other.
+fn paths_overlap(a: &str, b: &str) -> bool {
+    is_within(a, b) || is_within(b, a)
+}
+
+/// Whether the dotted `path` is `parent` or one of its fields.
+fn is_within(path: &str, parent: &str) -> bool {
+    path.strip_prefix(parent)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+}
+
+/// A query for entities of `E`, selected as `S`. Constructed with [`Selectable::query`].
+///
+/// ```no_run
+/// # pub use khan::*;
+/// # use khan::{Order, Selectable, mongodb::{bson::{DateTime, oid::ObjectId}, error::Result}};
+/// # use serde::{Deserialize, Serialize};
+/// # #[derive(Serialize, Deserialize, Entity)]
+/// # pub struct User {
+/// #     #[serde(rename = "_id")]
+/// #     pub id: ObjectId,
+/// #     pub active: bool,
+/// #     pub created_at: DateTime,
+/// # }
+/// # async fn example(mongo: Mongo<'_>) -> Result<()> {
+/// let users = User::query(user::filter! { active: true })
+///     .sort(user::Fields::CreatedAt, Order::Desc)
+///     .skip(10)
+///     .limit(20)
+///     .all(mongo)
+///     .await?;
+/// # Ok(())
+/// # }
+/// # fn main() {}
+/// ```
+pub struct Query<E, S> {
+    filter: Document,
+    sort: Document,
+    skip: Option<u64>,
+    limit: Option<u64>,
+    collation: Option<Collation>,
+    hint: Option<Hint>,
+    max_time: Option<Duration>,
+    batch_size: Option<u32>,
+    _marker: PhantomData<fn() -> (E, S)>,
+}
+
+impl<E: Entity, S: Selectable<E>> Query<E, S> {
+    pub fn new(filter: &impl Filter<E>) -> Self {
         Self {
-            db,
-            session: Some(session),
+            filter: filter.to_document(),
+            sort: Document::new(),
+            skip: None,
+            limit: None,
+            collation: None,
+            hint: None,
+            max_time: None,
+            batch_size: None,
+            _marker: PhantomData,
         }
     }
 
-    pub fn rb(&mut self) -> Mongo<'_> {
-        Mongo {
-            db: self.db,
-            session: self.session.as_deref_mut(),
-        }
+    /// Sorts by the field. Calling it several times sorts by each field in the order of
+    /// the calls.
+    pub fn sort(mut self, field: E::Fields, order: Order) -> Self {
+        self.sort.extend(sort_document([(field, order)]));
+        self
     }
-}
 
-impl<'a> From<&'a Database> for Mongo<'a> {
-    fn from(value: &'a Database) -> Self {
-        Self::new(value)
+    pub fn skip(mut self, skip: u64) -> Self {
+        self.skip = Some(skip);
+        self
     }
-}
 
-impl<'a> From<(&'a Database, &'a mut ClientSession)> for Mongo<'a> {
-    fn from(value: (&'a Database, &'a mut ClientSession)) -> Self {
-        Self::new_with_session(value.0, value.1)
+    pub fn limit(mut self, limit: u64) -> Self {
+        self.limit = Some(limit);
+        self
     }
-}
 
-#[derive(Debug)]
-pub struct Transaction<'a> {
-    pub db: &'a Database,
-    pub session: &'a mut ClientSession,
-}
+    pub fn collation(mut self, collation: Collation) -> Self {
+        self.collation = Some(collation);
+        self
+    }
 
-impl<'a> Transaction<'a> {
-    pub fn new(db: &'a Database, session: &'a mut ClientSession) -> Self {
-        Self { db, session }
+    /// Forces the query to use the given index.
+    pub fn hint(mut self, hint: Hint) -> Self {
+        self.hint = Some(hint);
+        self
     }
 
-    pub fn rb(&mut self) -> Transaction<'_> {
-        Transaction {
-            db: self.db,
-            session: &mut *self.session,
+    /// Aborts the query on the server if it runs longer than `max_time`.
+    pub fn max_time(mut self, max_time: Duration) -> Self {
+        self.max_time = Some(max_time);
+        self
+    }
+
+    /// Limits the number of documents fetched from the server at once.
+    pub fn batch_size(mut self, batch_size: u32) -> Self {
+        self.batch_size = Some(batch_size);
+        self
+    }
+
+    /// The filter of the query, as sent to the server.
+    pub fn filter(&self) -> &Document {
+        &self.filter
+    }
+
+    /// The options of the query, as sent to the server by [`stream`](Self::stream) and
+    /// [`all`](Self::all).
+    pub fn options(&self) -> FindOptions {
+        let mut options = FindOptions::default();
+        options.sort = (!self.sort.is_empty()).then(|| self.sort.clone());
+        options.projection = S::projection();
+        options.skip = self.skip;
+        options.limit = self
+            .limit
+            .map(|limit| i64::try_from(limit).unwrap_or(i64::MAX));
+        options.collation.clone_from(&self.collation);
+        options.hint.clone_from(&self.hint);
+        options.max_time = self.max_time;
+        options.batch_size = self.batch_size;
+        options
+    }
+
+    pub fn stream(self, mongo: Mongo<'_>) -> BoxStream<'_, Result<S>> {
+        async move {
+            let Mongo { db, session } = mongo;
+            let collection = db.collection(E::COLLECTION_NAME);
+
+            let options = self.options();
+            let query = collection.find(self.filter).with_options(options);
+
+            cursor_stream(query, session).await
         }
+        .try_flatten_stream()
+        .boxed()
     }
-}
 
-impl<'a> From<(&'a Database, &'a mut ClientSession)> for Transaction<'a> {
-    fn from(value: (&'a Database, &'a mut ClientSession)) -> Self {
-        Self::new(value.0, value.1)
+    pub fn all(self, mongo: Mongo<'_>) -> BoxFuture<'_, Result<Vec<S>>> {
+        self.stream(mongo).try_collect().boxed()
     }
-}
 
-impl<'a> From<Transaction<'a>> for Mongo<'a> {
-    fn from(value: Transaction<'a>) -> Self {
-        Mongo {
-            db: value.db,
-            session: Some(value.session),
+    pub fn one(self, mongo: Mongo<'_>) -> BoxFuture<'_, Result<Option<S>>> {
+        async move {
+            let Mongo { db, session } = mongo;
+            let collection = db.collection(E::COLLECTION_NAME);
+
+            let query = collection
+                .find_one(self.filter)
+                .optional((!self.sort.is_empty()).then_some(self.sort), FindOne::sort)
+                .optional(S::projection(), FindOne::projection)
+                .optional(self.skip, FindOne::skip)
+                .optional(self.collation, FindOne::collation)
+                .optional(self.hint, FindOne::hint)
+                .optional(self.max_time, FindOne::max_time);
+
+            let entity = with_session!(query, session).await?;
+
+            Ok(entity)
         }
+        .boxed()
     }
-}
 
-#[macro_export]
-macro_rules! with_session {
-    ($query: expr, $session: expr) => {
-        match $session {
-            Some(session) => $query.session(session),
-            None => $query,
+    /// Counts the matching documents, taking `skip` and `limit` into account.
+    pub fn count(self, mongo: Mongo<'_>) -> BoxFuture<'_, Result<u64>> {
+        async move {
+            let Mongo { db, session } = mongo;
+            let collection = E::collection(db);
+
+            let query = collection
+                .count_documents(self.filter)
+                .optional(self.skip, CountDocuments::skip)
+                .optional(self.limit, CountDocuments::limit)
+                .optional(self.collation, CountDocuments::collation)
+                .optional(self.hint, CountDocuments::hint)
+                .optional(self.max_time, CountDocuments::max_time);
+
+            let count = with_session!(query, session).await?;
+
+            Ok(count)
         }
-    };
-}
+        .boxed()
+    }
 
-pub trait Filter<E>: Send {
-    fn to_document(&self) -> Document;
-}
+    /// Fetches a page of at most `size` entities following the position in `token`, or
+    /// the first page if there is no token.
+    ///
+    /// Unlike `skip`, which makes the server walk through every skipped document, pages
+    /// are located by the sort keys of the last entity of the previous page, with `_id`
+    /// telling apart entities with equal keys. The `skip` and `limit` of the query are
+    /// ignored, and a token can only be used with a query sorted the same way as the one
+    /// that returned it.
+    ///
+    /// Sort keys may be fields of embedded documents, and may be `null` or missing, in
+    /// which case the entities are ordered the way `MongoDB` sorts them: before any other
+    /// value.
+    ///
+    /// Fetching a previous page takes a second query, to check whether there still is
+    /// anything after it.
+    ///
+    /// ```no_run
+    /// # pub use khan::*;
+    /// # use khan::{Order, Selectable, mongodb::{bson::{DateTime, oid::ObjectId}, error::Result}};
+    /// # use serde::{Deserialize, Serialize};
+    /// # #[derive(Serialize, Deserialize, Entity)]
+    /// # pub struct User {
+    /// #     #[serde(rename = "_id")]
+    /// #     pub id: ObjectId,
+    /// #     pub active: bool,
+    /// #     pub created_at: DateTime,
+    /// # }
+    /// # async fn example(mut mongo: Mongo<'_>) -> Result<()> {
+    /// let query = || User::query(user::filter! { active: true })
+    ///     .sort(user::Fields::CreatedAt, Order::Desc);
+    ///
+    /// let first = query().page(mongo.rb(), 20, None).await?;
+    /// let second = query().page(mongo.rb(), 20, first.next().cloned()).await?;
+    /// let first_again = query().page(mongo, 20, second.prev().cloned()).await?;
+    /// # Ok(())
+    /// # }
+    /// # fn main() {}
+    /// ```
+    pub fn page(
+        self,
+        mongo: Mongo<'_>,
+        size: u32,
+        token: Option<PageToken>,
+    ) -> BoxFuture<'_, Result<Page<S>>> {
+        async move {
+            let Mongo { db, session } = mongo;
+            let collection = db.collection::<Document>(E::COLLECTION_NAME);
 
-#[derive(Debug)]
-pub struct FilterById<E: Entity>(E::Id, PhantomData<E>);
+            let mut sort = self.sort.clone();
 
-pub fn by_id<E: Entity>(id: E::Id) -> FilterById<E> {
-    FilterById(id, PhantomData)
+            if !sort.contains_key("_id") {
+                let order = sort.values().last().cloned().unwrap_or(Bson::Int32(1));
+                sort.insert("_id", order);
+            }
+
+            let direction = token
+                .as_ref()
+                .map_or(PageDirection::Next, |token| token.direction);
+
+            let filter = match &token {
+                Some(token) if !token.position.keys().eq(sort.keys()) => {
+                    return Err(mongodb::error::Error::custom(
+                        "the page token was returned by a query with a different sort".to_owned(),
+                    ));
+                }
+                Some(token) => doc! {
+                    "$and": [self.filter.clone(), keyset_filter(&sort, &token.position, direction)],
+                },
+                None => self.filter.clone(),
+            };
+
+            // Previous pages are fetched backwards from the token and reversed afterwards
+            let query_sort = match direction {
+                PageDirection::Next => sort.clone(),
+                PageDirection::Prev => reverse_sort(&sort),
+            };
+
+            // The sort keys are needed for the tokens even if the projection leaves them out
+            let projection = S::projection().map(|projection| project_sort_keys(projection, &sort));
+
+            // One extra document tells whether there is anything past this page
+            let mut session = session;
+            let mut documents = self
+                .find_documents(
+                    &collection,
+                    filter,
+                    query_sort,
+                    i64::from(size) + 1,
+                    projection,
+                    session.as_deref_mut(),
+                )
+                .await?;
+
+            let has_more = documents.len() > size as usize;
+            documents.truncate(size as usize);
+
+            let (has_next, has_prev) = match direction {
+                PageDirection::Next => (has_more, token.is_some()),
+                PageDirection::Prev => {
+                    documents.reverse();
+
+                    // The entities after this page may have changed since the token was
+                    // returned, so look for one past the last entity of the page
+                    let has_next = match documents.last() {
+                        Some(last) => {
+                            let filter = doc! {
+                                "$and": [
+                                    self.filter.clone(),
+                                    keyset_filter(&sort, &sort_position(&sort, last), PageDirection::Next),
+                                ],
+                            };
+                            let projection = Some(doc! { "_id": 1 });
+
+                            !self
+                                .find_documents(
+                                    &collection,
+                                    filter,
+                                    sort.clone(),
+                                    1,
+                                    projection,
+                                    session,
+                                )
+                                .await?
+                                .is_empty()
+                        }
+                        None => false,
+                    };
+
+                    (has_next, has_more)
+                }
+            };
+
+            let token = |direction, document: Option<&Document>| {
+                document.map(|document| PageToken {
+                    direction,
+                    position: sort_position(&sort, document),
+                })
+            };
+
+            let next = has_next
+                .then(|| token(PageDirection::Next, documents.last()))
+                .flatten();
+            let prev = has_prev
+                .then(|| token(PageDirection::Prev, documents.first()))
+                .flatten();
+
+            let items = documents
+                .into_iter()
+                .map(bson::from_document)
+                .collect::<std::result::Result<_, _>>()?;
+
+            Ok(Page { items, next, prev })
+        }
+        .boxed()
+    }
+
+    /// Runs a find with the collation, hint and max time of the query, for [`Query::page`].
+    async fn find_documents(
+        &self,
+        collection: &Collection<Document>,
+        filter: Document,
+        sort: Document,
+        limit: i64,
+        projection: Option<Document>,
+        session: Option<&mut ClientSession>,
+    ) -> Result<Vec<Document>> {
+        let query = collection
+            .find(filter)
+            .sort(sort)
+            .limit(limit)
+            .optional(projection, Find::projection)
+            .optional(self.collation.clone(), Find::collation)
+            .optional(self.hint.clone(), Find::hint)
+            .optional(self.max_time, Find::max_time);
+
+        cursor_stream(query, session).await?.try_collect().await
+    }
 }
 
-impl<E: Entity> Filter<E> for FilterById<E> {
-    fn to_document(&self) -> Document {
-        doc! { "_id": bson::to_bson(&self.0).unwrap() }
+impl<E, S> std::fmt::Debug for Query<E, S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Query")
+            .field("filter", &self.filter)
+            .field("sort", &self.sort)
+            .field("skip", &self.skip)
+            .field("limit", &self.limit)
+            .field("collation", &self.collation)
+            .field("hint", &self.hint)
+            .field("max_time", &self.max_time)
+            .field("batch_size", &self.batch_size)
+            .finish()
     }
 }
 
-#[derive(Debug)]
-pub struct UntypedFilter<E: Send>(Document, PhantomData<E>);
+/// A page of entities returned by [`Query::page`].
+#[derive(Debug, Clone)]
+pub struct Page<S> {
+    items: Vec<S>,
+    next: Option<PageToken>,
+    prev: Option<PageToken>,
+}
 
-impl<E: Send> UntypedFilter<E> {
-    pub fn new(document: Document) -> Self {
-        Self(document, PhantomData)
+impl<S> Page<S> {
+    pub fn items(&self) -> &[S] {
+        &self.items
     }
-}
 
-impl<E: Send> Filter<E> for UntypedFilter<E> {
-    fn to_document(&self) -> Document {
-        self.0.clone()
+    pub fn into_items(self) -> Vec<S> {
+        self.items
+    }
+
+    /// The token for the page after this one, if there is one.
+    pub fn next(&self) -> Option<&PageToken> {
+        self.next.as_ref()
+    }
+
+    /// The token for the page before this one, if there is one.
+    pub fn prev(&self) -> Option<&PageToken> {
+        self.prev.as_ref()
     }
 }
 
-#[derive(Debug)]
-pub enum FilterOperator<'a, T: Serialize + ?Sized> {
-    Eq(&'a T),
-    Ne(&'a T),
-    Gt(&'a T),
-    Gte(&'a T),
-    Lt(&'a T),
-    Lte(&'a T),
-    In(&'a [&'a T]),
-    Nin(&'a [&'a T]),
+/// An opaque position in the results of a [`Query`], pointing at the page after or before
+/// a [`Page`].
+///
+/// Tokens can be stored with serde, or passed around as strings, e.g. in a URL, with the
+/// `Display` and `FromStr` impls.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PageToken {
+    direction: PageDirection,
+    /// The sort keys of the entity at the edge of the page, in sort order
+    position: Document,
 }
 
-impl<T: Serialize + ?Sized> FilterOperator<'_, T> {
-    pub fn to_document(&self) -> Document {
-        fn to_bson<T: Serialize>(val: &T) -> Bson {
-            bson::to_bson(val).unwrap()
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PageDirection {
+    Next,
+    Prev,
+}
+
+impl PageToken {
+    fn to_document(&self) -> Document {
+        doc! {
+            "d": match self.direction {
+                PageDirection::Next => "next",
+                PageDirection::Prev => "prev",
+            },
+            "p": &self.position,
         }
+    }
 
+    fn from_document(document: &Document) -> Option<Self> {
+        let direction = match document.get_str("d").ok()? {
+            "next" => PageDirection::Next,
+            "prev" => PageDirection::Prev,
+            _ => return None,
+        };
+        let position = document.get_document("p").ok()?.clone();
+
+        Some(Self {
+            direction,
+            position,
+        })
+    }
+}
+
+impl Serialize for PageToken {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        self.to_document().serialize(serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for PageToken {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        let document = Document::deserialize(deserializer)?;
+        Self::from_document(&document).ok_or_else(|| serde::de::Error::custom("invalid page token"))
+    }
+}
+
+impl Display for PageToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let bytes = bson::to_vec(&self.to_document()).map_err(|_| std::fmt::Error)?;
+        bytes.iter().try_for_each(|byte| write!(f, "{byte:02x}"))
+    }
+}
+
+impl std::str::FromStr for PageToken {
+    type Err = mongodb::error::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || mongodb::error::Error::custom("invalid page token".to_owned());
+
+        let bytes = (0..s.len())
+            .step_by(2)
+            .map(|i| {
+                s.get(i..i + 2)
+                    .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(invalid)?;
+        let document = bson::from_slice::<Document>(&bytes).map_err(|_| invalid())?;
+
+        Self::from_document(&document).ok_or_else(invalid)
+    }
+}
+
+/// `sort` with every order flipped.
+fn reverse_sort(sort: &Document) -> Document {
+    sort.iter()
+        .map(|(key, order)| {
+            (
+                key.clone(),
+                Bson::Int32(if is_ascending(order) { -1 } else { 1 }),
+            )
+        })
+        .collect()
+}
+
+/// The values of the sort keys in a document, `null` for missing ones.
+fn sort_position(sort: &Document, document: &Document) -> Document {
+    sort.keys()
+        .map(|key| {
+            (
+                key.clone(),
+                get_path(document, key).cloned().unwrap_or(Bson::Null),
+            )
+        })
+        .collect()
+}
+
+/// Adds the sort keys to an inclusion projection. Keys already projected through a parent
+/// document are left alone, and projected children of a key are replaced by the key, as
+/// the server rejects projections of both a document and one of its fields.
+fn project_sort_keys(mut projection: Document, sort: &Document) -> Document {
+    for key in sort.keys() {
+        if projection.keys().any(|projected| is_within(key, projected)) {
+            continue;
+        }
+
+        let children = projection
+            .keys()
+            .filter(|projected| is_within(projected, key))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for child in children {
+            projection.remove(&child);
+        }
+
+        projection.insert(key, 1);
+    }
+
+    projection
+}
+
+/// Matches the documents after `position` in `sort` order, or before it for previous
+/// pages, e.g. for `{ a: 1, _id: 1 }` the filter is
+/// `{ $or: [{ a: { $gt: a } }, { a: { $eq: a }, _id: { $gt: id } }] }`.
+///
+/// `MongoDB` sorts `null` and missing fields before any other value, which `$gt` and `$lt`
+/// don't match, so the branches account for them: everything that isn't `null` comes
+/// after `null`, and `null` comes before everything else.
+fn keyset_filter(sort: &Document, position: &Document, direction: PageDirection) -> Document {
+    let value = |key: &str| position.get(key).cloned().unwrap_or(Bson::Null);
+
+    let branches = sort
+        .iter()
+        .enumerate()
+        .filter_map(|(i, (key, order))| {
+            let mut branch = sort
+                .keys()
+                .take(i)
+                .map(|key| (key.clone(), bson!({ "$eq": value(key) })))
+                .collect::<Document>();
+
+            let after = is_ascending(order) == (direction == PageDirection::Next);
+
+            match (after, value(key)) {
+                (true, Bson::Null) => {
+                    branch.insert(key, doc! { "$ne": Bson::Null });
+                }
+                (true, value) => {
+                    branch.insert(key, doc! { "$gt": value });
+                }
+                // Nothing comes before `null`
+                (false, Bson::Null) => return None,
+                (false, value) => {
+                    branch.insert(
+                        "$or",
+                        vec![
+                            doc! { key: { "$lt": value } },
+                            doc! { key: { "$eq": Bson::Null } },
+                        ],
+                    );
+                }
+            }
+
+            Some(Bson::Document(branch))
+        })
+        .collect::<Vec<_>>();
+
+    doc! { "$or": branches }
+}
+
+fn is_ascending(order: &Bson) -> bool {
+    match order {
+        Bson::Int32(order) => *order >= 0,
+        Bson::Int64(order) => *order >= 0,
+        Bson::Double(order) => *order >= 0.0,
+        _ => true,
+    }
+}
+
+/// Follows a dotted path (e.g. `address.city`) through embedded documents.
+fn get_path<'a>(document: &'a Document, path: &str) -> Option<&'a Bson> {
+    match path.split_once('.') {
+        Some((key, rest)) => get_path(document.get_document(key).ok()?, rest),
+        None => document.get(path),
+    }
+}
+
+/// A field of an entity as a type, carrying the Rust type of its values. The derive
+/// generates one for each field in the `fields` module of the helper module, e.g.
+/// `user::fields::Email`.
+pub trait TypedField: Send + 'static {
+    type Entity: Entity;
+
+    /// The type of the values of the field, or of the elements for array fields. For
+    /// `Option<T>` fields, it's the type of the values of `T`.
+    type Value: DeserializeOwned + Send + 'static;
+
+    /// The name of the field in the document.
+    fn name(&self) -> &'static str;
+}
+
+/// The path of an entity type, e.g. `app::models::User`, which identifies it in the registry
+/// of the `meta` feature and in the migration records, where the bare type name could be
+/// shared by entities of different modules. Implemented by `#[derive(Entity)]`.
+pub trait EntityName {
+    const ENTITY_NAME: &'static str;
+}
+
+/// Types with a helper module listing their fields, i.e. entities and structs deriving
+/// [`Fields`](macro@Fields).
+pub trait HasFields {
+    type Fields: Display + Send + 'static;
+}
+
+/// An aggregation pipeline over the collection of `E`. Constructed with
+/// [`Entity::aggregate`].
+///
+/// `D` is the shape of the documents at the current stage, which the typed stages refer to,
+/// and `O` is the type the results are deserialized into. Both start as `E`, and change with
+/// [`project`](Self::project), [`group`](Self::group) and [`output`](Self::output).
+///
+/// ```no_run
+/// # pub use khan::*;
+/// # use khan::{Accumulator, Entity, Fields, Group, Mongo, Order, mongodb::{bson::oid::ObjectId, error::Result}};
+/// # use serde::{Deserialize, Serialize};
+/// # #[derive(Serialize, Deserialize, Entity)]
+/// # pub struct Purchase {
+/// #     #[serde(rename = "_id")]
+/// #     pub id: ObjectId,
+/// #     pub customer: String,
+/// #     amount: i64,
+/// #     pub paid: bool,
+/// # }
+/// # #[derive(Serialize, Deserialize, Fields)]
+/// # pub struct CustomerTotal {
+/// #     #[serde(rename = "_id")]
+/// #     pub customer: String,
+/// #     total: i64,
+/// # }
+/// # async fn example(mongo: Mongo<'_>) -> Result<()> {
+/// let totals = Purchase::aggregate()
+///     .filter(purchase::filter! { paid: true })
+///     .group::<CustomerTotal>(
+///         Group::by(purchase::Fields::Customer)
+///             .with(customer_total::Fields::Total, Accumulator::Sum(purchase::Fields::Amount)),
+///     )
+///     .sort(customer_total::Fields::Total, Order::Desc)
+///     .limit(10)
+///     .all(mongo)
+///     .await?;
+/// # Ok(())
+/// # }
+/// # fn main() {}
+/// ```
+pub struct Pipeline<E, D = E, O = D> {
+    stages: Vec<Document>,
+    _marker: PhantomData<(E, D, O)>,
+}
+
+impl<E, D, O> Pipeline<E, D, O> {
+    fn new() -> Self {
+        Self {
+            stages: Vec::new(),
+            _marker: PhantomData,
+        }
+    }
+
+    fn stage(mut self, stage: Document) -> Self {
+        self.stages.push(stage);
+        self
+    }
+
+    fn retype<D2, O2>(self) -> Pipeline<E, D2, O2> {
+        Pipeline {
+            stages: self.stages,
+            _marker: PhantomData,
+        }
+    }
+
+    /// The stages of the pipeline, as sent to the server.
+    pub fn stages(&self) -> &[Document] {
+        &self.stages
+    }
+}
+
+impl<E: Entity, D: HasFields, O: DeserializeOwned + Send + Sync + 'static> Pipeline<E, D, O> {
+    /// Keeps the documents matching the filter (`$match`).
+    #[allow(clippy::needless_pass_by_value)]
+    pub fn filter(self, filter: impl Filter<D>) -> Self {
+        self.stage(doc! { "$match": filter.to_document() })
+    }
+
+    /// Sorts the documents by the field (`$sort`). Consecutive calls sort by each field in
+    /// the order of the calls.
+    pub fn sort(mut self, field: D::Fields, order: Order) -> Self {
+        let sort = sort_document([(field, order)]);
+
+        match self
+            .stages
+            .last_mut()
+            .and_then(|stage| stage.get_document_mut("$sort").ok())
+        {
+            Some(stage) => stage.extend(sort),
+            None => self.stages.push(doc! { "$sort": sort }),
+        }
+
+        self
+    }
+
+    pub fn skip(self, skip: u64) -> Self {
+        self.stage(doc! { "$skip": i64::try_from(skip).unwrap_or(i64::MAX) })
+    }
+
+    pub fn limit(self, limit: u64) -> Self {
+        self.stage(doc! { "$limit": i64::try_from(limit).unwrap_or(i64::MAX) })
+    }
+
+    /// Outputs a document for each element of the array field, with the field set to the
+    /// element (`$unwind`). Documents where the array is missing or empty are dropped.
+    ///
+    /// The field keeps its array type in `D`, so use [`output`](Self::output) to
+    /// deserialize the results into a type with the element in its place.
+    pub fn unwind(self, field: D::Fields) -> Self {
+        self.stage(doc! { "$unwind": field_path(field) })
+    }
+
+    /// Groups the documents into documents of `T` (`$group`). The `_id` of the group goes
+    /// into the `_id` field of `T`, and the accumulators into the other fields.
+    pub fn group<T: HasFields + DeserializeOwned + Send + Sync + 'static>(
+        self,
+        group: Group<D::Fields, T::Fields>,
+    ) -> Pipeline<E, T> {
+        self.stage(doc! { "$group": group.into_document() })
+            .retype()
+    }
+
+    /// Deserializes the results into `T` instead of `O`.
+    pub fn output<T: DeserializeOwned + Send + Sync + 'static>(self) -> Pipeline<E, D, T> {
+        self.retype()
+    }
+
+    /// Joins the entity referenced by the relation (`$lookup`), pairing each result with it.
+    ///
+    /// The results are no longer shaped like `D`, so it has to be the last stage. The field of
+    /// the relation has to be kept by the previous stages, including `project`.
+    pub fn lookup<R: Relation<From = D>>(self) -> Pipeline<E, WithRelated<O, R::To>> {
+        self.stage(doc! {
+            "$lookup": {
+                "from": R::To::COLLECTION_NAME,
+                "localField": R::FIELD,
+                "foreignField": "_id",
+                "as": "_related",
+            },
+        })
+        .stage(doc! {
+            "$replaceRoot": {
+                "newRoot": {
+                    "entity": "$$ROOT",
+                    "related": { "$arrayElemAt": ["$_related", 0] },
+                },
+            },
+        })
+        .stage(doc! { "$project": { "entity._related": 0 } })
+        .retype()
+    }
+}
+
+impl<E: Entity, D, O: DeserializeOwned + Send + Sync + 'static> Pipeline<E, D, O> {
+    pub fn stream(self, mongo: Mongo<'_>) -> BoxStream<'_, Result<O>> {
+        async move {
+            let Mongo { db, session } = mongo;
+            let collection = E::collection(db);
+
+            let aggregate = collection.aggregate(self.stages).with_type::<O>();
+
+            aggregate_stream(aggregate, session).await
+        }
+        .try_flatten_stream()
+        .boxed()
+    }
+
+    pub fn all(self, mongo: Mongo<'_>) -> BoxFuture<'_, Result<Vec<O>>> {
+        self.stream(mongo).try_collect().boxed()
+    }
+}
+
+impl<T: Entity, O> Pipeline<T, T, O> {
+    /// Keeps only the fields of `S` (`$project`) and deserializes the results into it.
+    ///
+    /// Later stages still refer to the fields of the entity, but only the projected ones are
+    /// left.
+    pub fn project<S: Selectable<T>>(self) -> Pipeline<T, T, S> {
+        match S::projection() {
+            Some(projection) => self.stage(doc! { "$project": projection }).retype(),
+            None => self.retype(),
+        }
+    }
+}
+
+impl<E, D, O> std::fmt::Debug for Pipeline<E, D, O> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pipeline")
+            .field("stages", &self.stages)
+            .finish()
+    }
+}
+
+/// A field of [`From`](Self::From) holding the id of a [`To`](Self::To), declared with
+/// `#[entity(references = To)]` on the field.
+///
+/// The derive implements it for a type named after the field in the `relations` module of
+/// the helper module, e.g. `comment::relations::PostId` for `post_id`.
+pub trait Relation: 'static {
+    type From: Entity;
+    type To: Entity;
+
+    /// The name of the field in the document.
+    const FIELD: &'static str;
+
+    fn id(entity: &Self::From) -> Option<<Self::To as Entity>::Id>;
+
+    /// Loads the entities referenced by `entities` with a single query, and pairs each entity
+    /// with the one it references, if it exists.
+    ///
+    /// ```no_run
+    /// # pub use khan::*;
+    /// # use khan::{Relation, Selectable, WithRelated, mongodb::{bson::oid::ObjectId, error::Result}};
+    /// # use serde::{Deserialize, Serialize};
+    /// # #[derive(Clone, Serialize, Deserialize, Entity)]
+    /// # pub struct Post {
+    /// #     #[serde(rename = "_id")]
+    /// #     pub id: ObjectId,
+    /// # }
+    /// # #[derive(Serialize, Deserialize, Entity)]
+    /// # pub struct Comment {
+    /// #     #[serde(rename = "_id")]
+    /// #     pub id: ObjectId,
+    /// #     pub author_id: ObjectId,
+    /// #     #[entity(references = Post)]
+    /// #     pub post_id: ObjectId,
+    /// # }
+    /// # async fn example(mut mongo: Mongo<'_>, author_id: ObjectId) -> Result<()> {
+    /// let comments = Comment::find(mongo.rb(), comment::filter! { author_id: author_id }).await?;
+    ///
+    /// for WithRelated { entity: comment, related: post } in
+    ///     comment::relations::PostId::load::<Post>(mongo, comments).await?
+    /// {
+    ///     // ...
+    /// }
+    /// # Ok(())
+    /// # }
+    /// # fn main() {}
+    /// ```
+    #[allow(clippy::type_complexity)]
+    fn load<T: SelectableWithId<Self::To> + Clone>(
+        mongo: Mongo<'_>,
+        entities: Vec<Self::From>,
+    ) -> BoxFuture<'_, Result<Vec<WithRelated<Self::From, T>>>> {
+        async move {
+            let ids = entities.iter().filter_map(Self::id).collect::<Vec<_>>();
+
+            let mut related = BTreeMap::new();
+
+            if !ids.is_empty() {
+                for entity in T::find(mongo, by_ids(ids)).await? {
+                    related.insert(id_key(&entity.id())?, entity);
+                }
+            }
+
+            entities
+                .into_iter()
+                .map(|entity| {
+                    let related = match Self::id(&entity) {
+                        Some(id) => related.get(&id_key(&id)?).cloned(),
+                        None => None,
+                    };
+
+                    Ok(WithRelated { entity, related })
+                })
+                .collect()
+        }
+        .boxed()
+    }
+}
+
+/// The result of [`Selectable::find_by_ids`], with an entry for each of the ids in the
+/// order they were given.
+#[derive(Debug, Clone)]
+pub struct FoundByIds<Id, S> {
+    ids: Vec<Id>,
+    entities: Vec<Option<S>>,
+}
+
+impl<Id: Serialize, S: DeserializeOwned> FoundByIds<Id, S> {
+    /// Pairs each id with the document that has it as `_id`, in any order.
+    fn match_documents(ids: Vec<Id>, documents: Vec<Document>) -> Result<Self> {
+        let documents = documents
+            .into_iter()
+            .filter_map(|document| Some((bson_key(document.get("_id")?), document)))
+            .collect::<BTreeMap<_, _>>();
+
+        let entities = ids
+            .iter()
+            .map(|id| {
+                documents
+                    .get(&id_key(id)?)
+                    .map(|document| bson::from_document(document.clone()))
+                    .transpose()
+                    .map_err(Into::into)
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self { ids, entities })
+    }
+}
+
+impl<Id: Copy, S> FoundByIds<Id, S> {
+    /// The entity for each id, or `None` if it wasn't found.
+    pub fn entities(&self) -> &[Option<S>] {
+        &self.entities
+    }
+
+    pub fn into_entities(self) -> Vec<Option<S>> {
+        self.entities
+    }
+
+    /// Each id paired with its entity.
+    pub fn iter(&self) -> impl Iterator<Item = (Id, Option<&S>)> {
+        self.ids
+            .iter()
+            .copied()
+            .zip(self.entities.iter().map(Option::as_ref))
+    }
+
+    /// The ids that weren't found.
+    pub fn missing(&self) -> impl Iterator<Item = Id> {
+        self.iter()
+            .filter_map(|(id, entity)| entity.is_none().then_some(id))
+    }
+}
+
+/// An entity paired with the one it references through a [`Relation`], returned by
+/// [`Relation::load`] and [`Pipeline::lookup`]. `related` is `None` if the reference is
+/// empty, or the referenced entity doesn't exist.
+#[derive(Debug, Clone)]
+pub struct WithRelated<O, T> {
+    pub entity: O,
+    pub related: Option<T>,
+}
+
+impl<'de, O: DeserializeOwned, T: DeserializeOwned> serde::Deserialize<'de> for WithRelated<O, T> {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        use serde::de::Error;
+
+        let mut document = Document::deserialize(deserializer)?;
+
+        let entity = document
+            .remove("entity")
+            .ok_or_else(|| D::Error::missing_field("entity"))?;
+
+        let related = document
+            .remove("related")
+            .filter(|related| *related != Bson::Null);
+
+        Ok(Self {
+            entity: bson::from_bson(entity).map_err(D::Error::custom)?,
+            related: related
+                .map(bson::from_bson)
+                .transpose()
+                .map_err(D::Error::custom)?,
+        })
+    }
+}
+
+/// A `$group` stage of a [`Pipeline`], grouping documents with fields `F` into documents
+/// with fields `G`.
+pub struct Group<F, G> {
+    id: Bson,
+    accumulators: Document,
+    _marker: PhantomData<fn() -> (F, G)>,
+}
+
+impl<F: Display, G: Display> Group<F, G> {
+    fn new(id: Bson) -> Self {
+        Self {
+            id,
+            accumulators: Document::new(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Groups the documents by the value of the field.
+    pub fn by(field: F) -> Self {
+        Self::new(Bson::String(field_path(field)))
+    }
+
+    /// Groups the documents by the values of several fields, with the `_id` of the group
+    /// being a document of them.
+    pub fn by_fields(fields: impl IntoIterator<Item = F>) -> Self {
+        Self::new(Bson::Document(
+            fields
+                .into_iter()
+                .map(|field| (field.to_string(), Bson::String(field_path(field))))
+                .collect(),
+        ))
+    }
+
+    /// Puts all the documents into a single group with a `null` `_id`.
+    pub fn all() -> Self {
+        Self::new(Bson::Null)
+    }
+
+    /// Computes the output field with the accumulator.
+    pub fn with(mut self, field: G, accumulator: Accumulator<F>) -> Self {
+        self.accumulators
+            .insert(field.to_string(), accumulator.into_document());
+        self
+    }
+
+    fn into_document(self) -> Document {
+        let mut document = doc! { "_id": self.id };
+        document.extend(self.accumulators);
+        document
+    }
+}
+
+impl<F, G> std::fmt::Debug for Group<F, G> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Group")
+            .field("id", &self.id)
+            .field("accumulators", &self.accumulators)
+            .finish()
+    }
+}
+
+/// How a [`Group`] computes an output field out of the documents in the group.
+#[derive(Debug)]
+pub enum Accumulator<F> {
+    /// The number of documents (`$sum: 1`).
+    Count,
+    Sum(F),
+    Avg(F),
+    Min(F),
+    Max(F),
+    /// The value of the first document, in the order of the previous `$sort`.
+    First(F),
+    /// The value of the last document, in the order of the previous `$sort`.
+    Last(F),
+    /// An array of the values of all the documents.
+    Push(F),
+    /// An array of the distinct values of all the documents.
+    AddToSet(F),
+}
+
+impl<F: Display> Accumulator<F> {
+    fn into_document(self) -> Document {
+        let (operator, field) = match self {
+            Self::Count => return doc! { "$sum": 1 },
+            Self::Sum(field) => ("$sum", field),
+            Self::Avg(field) => ("$avg", field),
+            Self::Min(field) => ("$min", field),
+            Self::Max(field) => ("$max", field),
+            Self::First(field) => ("$first", field),
+            Self::Last(field) => ("$last", field),
+            Self::Push(field) => ("$push", field),
+            Self::AddToSet(field) => ("$addToSet", field),
+        };
+
+        doc! { operator: field_path(field) }
+    }
+}
+
+/// Refers to the value of the field in an aggregation expression, e.g. `$age`.
+fn field_path(field: impl Display) -> String {
+    format!("${field}")
+}
+
+/// Runs a find query, streaming the results through the session if there is one.
+async fn cursor_stream<'a, T: DeserializeOwned + Send + Sync + 'a>(
+    query: mongodb::action::Find<'_, T>,
+    session: Option<&'a mut ClientSession>,
+) -> Result<BoxStream<'a, Result<T>>> {
+    match session {
+        Some(session) => {
+            let cursor = query.session(&mut *session).await?;
+            Ok(session_cursor_stream(cursor, session))
+        }
+        None => Ok(query.await?.boxed()),
+    }
+}
+
+/// Runs an aggregation, streaming the results through the session if there is one.
+async fn aggregate_stream<'a, T: DeserializeOwned + Send + Sync + 'a>(
+    aggregate: mongodb::action::Aggregate<'_, mongodb::action::ImplicitSession, T>,
+    session: Option<&'a mut ClientSession>,
+) -> Result<BoxStream<'a, Result<T>>> {
+    match session {
+        Some(session) => {
+            let cursor = aggregate.session(&mut *session).await?;
+            Ok(session_cursor_stream(cursor, session))
+        }
+        None => Ok(aggregate.await?.boxed()),
+    }
+}
+
+fn session_cursor_stream<'a, T: DeserializeOwned + Send + Sync + 'a>(
+    cursor: mongodb::SessionCursor<T>,
+    session: &'a mut ClientSession,
+) -> BoxStream<'a, Result<T>> {
+    // `SessionCursor` needs the session for every batch, so it's kept in the state
+    stream::try_unfold((cursor, session), |(mut cursor, session)| async move {
+        match cursor.next(session).await {
+            Some(entity) => Ok(Some((entity?, (cursor, session)))),
+            None => Ok(None),
+        }
+    })
+    .boxed()
+}
+
+#[derive(Debug)]
+pub struct Mongo<'a> {
+    pub db: &'a Database,
+    pub session: Option<&'a mut ClientSession>,
+}
+
+impl<'a> Mongo<'a> {
+    pub fn new(db: &'a Database) -> Self {
+        Self { db, session: None }
+    }
+
+    pub fn new_with_session(db: &'a Database, session: &'a mut ClientSession) -> Self {
+        Self {
+            db,
+            session: Some(session),
+        }
+    }
+
+    pub fn rb(&mut self) -> Mongo<'_> {
+        Mongo {
+            db: self.db,
+            session: self.session.as_deref_mut(),
+        }
+    }
+}
+
+impl<'a> From<&'a Database> for Mongo<'a> {
+    fn from(value: &'a Database) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<'a> From<(&'a Database, &'a mut ClientSession)> for Mongo<'a> {
+    fn from(value: (&'a Database, &'a mut ClientSession)) -> Self {
+        Self::new_with_session(value.0, value.1)
+    }
+}
+
+#[derive(Debug)]
+pub struct Transaction<'a> {
+    pub db: &'a Database,
+    pub session: &'a mut ClientSession,
+}
+
+impl<'a> Transaction<'a> {
+    pub fn new(db: &'a Database, session: &'a mut ClientSession) -> Self {
+        Self { db, session }
+    }
+
+    pub fn rb(&mut self) -> Transaction<'_> {
+        Transaction {
+            db: self.db,
+            session: &mut *self.session,
+        }
+    }
+}
+
+impl<'a> From<(&'a Database, &'a mut ClientSession)> for Transaction<'a> {
+    fn from(value: (&'a Database, &'a mut ClientSession)) -> Self {
+        Self::new(value.0, value.1)
+    }
+}
+
+impl<'a> From<Transaction<'a>> for Mongo<'a> {
+    fn from(value: Transaction<'a>) -> Self {
+        Mongo {
+            db: value.db,
+            session: Some(value.session),
+        }
+    }
+}
+
+#[macro_export]
+macro_rules! with_session {
+    ($query: expr, $session: expr) => {
+        match $session {
+            Some(session) => $query.session(session),
+            None => $query,
+        }
+    };
+}
+
+pub trait Filter<E>: Send {
+    fn to_document(&self) -> Document;
+}
+
+#[derive(Debug)]
+pub struct FilterById<E: Entity>(E::Id, PhantomData<E>);
+
+pub fn by_id<E: Entity>(id: E::Id) -> FilterById<E> {
+    FilterById(id, PhantomData)
+}
+
+impl<E: Entity> Filter<E> for FilterById<E> {
+    fn to_document(&self) -> Document {
+        doc! { "_id": bson::to_bson(&self.0).unwrap() }
+    }
+}
+
+#[derive(Debug)]
+pub struct FilterByIds<E: Entity>(Vec<E::Id>, PhantomData<E>);
+
+/// Matches the entities with any of the ids (`$in`).
+pub fn by_ids<E: Entity>(ids: impl IntoIterator<Item = E::Id>) -> FilterByIds<E> {
+    FilterByIds(ids.into_iter().collect(), PhantomData)
+}
+
+impl<E: Entity> Filter<E> for FilterByIds<E> {
+    fn to_document(&self) -> Document {
+        doc! { "_id": { "$in": bson::to_bson(&self.0).unwrap() } }
+    }
+}
+
+/// Turns an id into a key that is equal for ids the server considers equal. Numbers are
+/// compared by value, so e.g. `1` and `1_i64` give the same key.
+fn id_key(id: &impl Serialize) -> Result<String> {
+    Ok(bson_key(&bson::to_bson(id)?))
+}
+
+fn bson_key(value: &Bson) -> String {
+    match *value {
+        Bson::Int32(value) => Bson::Int64(value.into()).to_string(),
+        #[allow(clippy::cast_possible_truncation)]
+        Bson::Double(value) if value.fract() == 0.0 && value.abs() < 2f64.powi(63) => {
+            Bson::Int64(value as i64).to_string()
+        }
+        ref value => value.to_string(),
+    }
+}
+
+#[derive(Debug)]
+pub struct UntypedFilter<E: Send>(Document, PhantomData<E>);
+
+impl<E: Send> UntypedFilter<E> {
+    pub fn new(document: Document) -> Self {
+        Self(document, PhantomData)
+    }
+}
+
+impl<E: Send> Filter<E> for UntypedFilter<E> {
+    fn to_document(&self) -> Document {
+        self.0.clone()
+    }
+}
+
+/// Matches documents that match both filters (`$and`).
+///
+/// Usually constructed with the `&` operator: `filter_a & filter_b`.
+#[derive(Debug)]
+pub struct FilterAnd<A, B>(pub A, pub B);
+
+pub fn and<A, B>(lhs: A, rhs: B) -> FilterAnd<A, B> {
+    FilterAnd(lhs, rhs)
+}
+
+impl<E, A: Filter<E>, B: Filter<E>> Filter<E> for FilterAnd<A, B> {
+    fn to_document(&self) -> Document {
+        combine_filters("$and", "$and", [self.0.to_document(), self.1.to_document()])
+    }
+}
+
+/// Matches documents that match at least one of the filters (`$or`).
+///
+/// Usually constructed with the `|` operator: `filter_a | filter_b`.
+#[derive(Debug)]
+pub struct FilterOr<A, B>(pub A, pub B);
+
+pub fn or<A, B>(lhs: A, rhs: B) -> FilterOr<A, B> {
+    FilterOr(lhs, rhs)
+}
+
+impl<E, A: Filter<E>, B: Filter<E>> Filter<E> for FilterOr<A, B> {
+    fn to_document(&self) -> Document {
+        combine_filters("$or", "$or", [self.0.to_document(), self.1.to_document()])
+    }
+}
+
+/// Matches documents that match neither of the filters (`$nor`).
+#[derive(Debug)]
+pub struct FilterNor<A, B>(pub A, pub B);
+
+pub fn nor<A, B>(lhs: A, rhs: B) -> FilterNor<A, B> {
+    FilterNor(lhs, rhs)
+}
+
+impl<E, A: Filter<E>, B: Filter<E>> Filter<E> for FilterNor<A, B> {
+    fn to_document(&self) -> Document {
+        combine_filters("$nor", "$or", [self.0.to_document(), self.1.to_document()])
+    }
+}
+
+/// Matches documents that don't match the filter.
+///
+/// `MongoDB` only allows `$not` on individual fields, so a negated filter is expressed
+/// as `{ $nor: [filter] }`. Usually constructed with the `!` operator: `!filter`.
+#[derive(Debug)]
+pub struct FilterNot<F>(pub F);
+
+pub fn not<F>(filter: F) -> FilterNot<F> {
+    FilterNot(filter)
+}
+
+impl<E, F: Filter<E>> Filter<E> for FilterNot<F> {
+    fn to_document(&self) -> Document {
+        combine_filters("$nor", "$or", [self.0.to_document()])
+    }
+}
+
+/// Applies a filter of an embedded document to the field `key`, e.g. `{ "address.city": "Berlin" }`.
+///
+/// Usually constructed by the `filter!` macro from dot-paths: `filter! { address.city: "Berlin" }`.
+/// Keys of the nested filter are prefixed with `key`, including the ones inside `$and`, `$or`
+/// and `$nor`. Other top-level operators (e.g. `$text` or `$expr`) can't be scoped to a field
+/// and are passed through unchanged.
+pub struct NestedFilter<E, T, F> {
+    key: String,
+    filter: F,
+    _marker: PhantomData<fn() -> (E, T)>,
+}
+
+impl<E, T, F> NestedFilter<E, T, F> {
+    pub fn new(key: impl Into<String>, filter: F) -> Self {
+        Self {
+            key: key.into(),
+            filter,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<E, T, F: std::fmt::Debug> std::fmt::Debug for NestedFilter<E, T, F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NestedFilter")
+            .field("key", &self.key)
+            .field("filter", &self.filter)
+            .finish()
+    }
+}
+
+impl<E, T, F: Filter<T>> Filter<E> for NestedFilter<E, T, F> {
+    fn to_document(&self) -> Document {
+        prefix_filter(&self.key, self.filter.to_document())
+    }
+}
+
+fn prefix_filter(prefix: &str, document: Document) -> Document {
+    document
+        .into_iter()
+        .map(|(key, value)| match value {
+            Bson::Array(operands) if key == "$and" || key == "$or" || key == "$nor" => {
+                let operands = operands
+                    .into_iter()
+                    .map(|operand| match operand {
+                        Bson::Document(operand) => Bson::Document(prefix_filter(prefix, operand)),
+                        operand => operand,
+                    })
+                    .collect::<Vec<_>>();
+
+                (key, Bson::Array(operands))
+            }
+            value if key.starts_with('$') => (key, value),
+            value => (format!("{prefix}.{key}"), value),
+        })
+        .collect()
+}
+
+/// Builds `{ operator: [operands] }`, inlining operands of the form `{ flatten: [...] }`.
+///
+/// `$and` and `$or` inline themselves, while `$nor` inlines `$or`, since
+/// `$nor: [a, { $or: [b, c] }]` is equivalent to `$nor: [a, b, c]`.
+fn combine_filters(
+    operator: &str,
+    flatten: &str,
+    operands: impl IntoIterator<Item = Document>,
+) -> Document {
+    let mut flattened = Vec::new();
+
+    for operand in operands {
+        match operand.get_array(flatten) {
+            Ok(nested) if operand.len() == 1 => flattened.extend(nested.clone()),
+            _ => flattened.push(Bson::Document(operand)),
+        }
+    }
+
+    doc! { operator: flattened }
+}
+
+/// Implements `&`, `|` and `!` for a filter type, producing [`FilterAnd`], [`FilterOr`]
+/// and [`FilterNot`] respectively.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! impl_filter_ops {
+    ([$( $generics: tt )*] $ty: ty) => {
+        impl<$( $generics )* Rhs> ::std::ops::BitAnd<Rhs> for $ty {
+            type Output = $crate::FilterAnd<Self, Rhs>;
+
+            fn bitand(self, rhs: Rhs) -> Self::Output {
+                $crate::FilterAnd(self, rhs)
+            }
+        }
+
+        impl<$( $generics )* Rhs> ::std::ops::BitOr<Rhs> for $ty {
+            type Output = $crate::FilterOr<Self, Rhs>;
+
+            fn bitor(self, rhs: Rhs) -> Self::Output {
+                $crate::FilterOr(self, rhs)
+            }
+        }
+
+        impl<$( $generics )*> ::std::ops::Not for $ty {
+            type Output = $crate::FilterNot<Self>;
+
+            fn not(self) -> Self::Output {
+                $crate::FilterNot(self)
+            }
+        }
+    };
+}
+
+impl_filter_ops!([E: Entity,] FilterById<E>);
+impl_filter_ops!([E: Send,] UntypedFilter<E>);
+impl_filter_ops!([A, B,] FilterAnd<A, B>);
+impl_filter_ops!([A, B,] FilterOr<A, B>);
+impl_filter_ops!([A, B,] FilterNor<A, B>);
+impl_filter_ops!([F,] FilterNot<F>);
+impl_filter_ops!([E, T, F,] NestedFilter<E, T, F>);
+
+/// A filter applied to a single field, e.g. `{ $gt: 5 }`.
+///
+/// The `Entity` and `Fields` derives pick the operator type for each field based on its
+/// type, so that only the operators applicable to the field can be used:
+/// - [`FilterOperator`] for ordered scalars (numbers, dates, ids)
+/// - [`StrFilterOperator`] for strings
+/// - [`EqFilterOperator`] for other scalars (`bool`, enums, embedded documents)
+/// - [`ArrayFilterOperator`] for `Vec<T>`
+/// - [`NullableFilterOperator`] for `Option<T>`, wrapping the operator of `T`
+pub trait Operator: Send {
+    fn to_document(&self) -> Document;
+}
+
+fn to_bson<T: Serialize + ?Sized>(val: &T) -> Bson {
+    bson::to_bson(val).unwrap()
+}
+
+/// An operand of a filter operator, either borrowed or owned.
+///
+/// Filters built from owned operands aren't tied to the scope that built them, so they
+/// can be returned from functions, stored, or moved to other tasks.
+#[derive(Debug, Clone)]
+pub enum Operand<'a, T> {
+    Borrowed(&'a T),
+    Owned(T),
+}
+
+impl<T> std::ops::Deref for Operand<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Self::Borrowed(val) => val,
+            Self::Owned(val) => val,
+        }
+    }
+}
+
+impl<T: Serialize> Serialize for Operand<'_, T> {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        T::serialize(self, serializer)
+    }
+}
+
+/// Conversion of `filter!` values into operands: references are borrowed, and values are
+/// moved into the filter.
+pub trait IntoOperand<O> {
+    fn into_operand(self) -> O;
+}
+
+impl<'a, T> IntoOperand<Operand<'a, T>> for T {
+    fn into_operand(self) -> Operand<'a, T> {
+        Operand::Owned(self)
+    }
+}
+
+impl<'a, T> IntoOperand<Operand<'a, T>> for &'a T {
+    fn into_operand(self) -> Operand<'a, T> {
+        Operand::Borrowed(self)
+    }
+}
+
+impl<'a, T> IntoOperand<Operand<'a, T>> for &'a &T {
+    fn into_operand(self) -> Operand<'a, T> {
+        Operand::Borrowed(*self)
+    }
+}
+
+impl<'a> IntoOperand<Operand<'a, String>> for &str {
+    fn into_operand(self) -> Operand<'a, String> {
+        Operand::Owned(self.to_owned())
+    }
+}
+
+impl<'a> IntoOperand<Operand<'a, String>> for &&str {
+    fn into_operand(self) -> Operand<'a, String> {
+        Operand::Owned((*self).to_owned())
+    }
+}
+
+impl<'a> IntoOperand<Cow<'a, str>> for &'a str {
+    fn into_operand(self) -> Cow<'a, str> {
+        Cow::Borrowed(self)
+    }
+}
+
+impl<'a> IntoOperand<Cow<'a, str>> for &'a &str {
+    fn into_operand(self) -> Cow<'a, str> {
+        Cow::Borrowed(*self)
+    }
+}
+
+impl<'a> IntoOperand<Cow<'a, str>> for &'a String {
+    fn into_operand(self) -> Cow<'a, str> {
+        Cow::Borrowed(self)
+    }
+}
+
+impl<'a> IntoOperand<Cow<'a, str>> for String {
+    fn into_operand(self) -> Cow<'a, str> {
+        Cow::Owned(self)
+    }
+}
+
+impl<O, I: IntoIterator<Item: IntoOperand<O>>> IntoOperand<Vec<O>> for I {
+    fn into_operand(self) -> Vec<O> {
+        self.into_iter().map(IntoOperand::into_operand).collect()
+    }
+}
+
+#[derive(Debug)]
+pub enum FilterOperator<'a, T: Serialize> {
+    Eq(Operand<'a, T>),
+    Ne(Operand<'a, T>),
+    Gt(Operand<'a, T>),
+    Gte(Operand<'a, T>),
+    Lt(Operand<'a, T>),
+    Lte(Operand<'a, T>),
+    In(Vec<Operand<'a, T>>),
+    Nin(Vec<Operand<'a, T>>),
+    /// Matches documents where the field has the given BSON type.
+    Type(ElementType),
+}
+
+impl<T: Serialize + Send + Sync> Operator for FilterOperator<'_, T> {
+    fn to_document(&self) -> Document {
+        let (operator, bson) = match self {
+            Self::Eq(val) => ("$eq", to_bson(val)),
+            Self::Ne(val) => ("$ne", to_bson(val)),
+            Self::Gt(val) => ("$gt", to_bson(val)),
+            Self::Gte(val) => ("$gte", to_bson(val)),
+            Self::Lt(val) => ("$lt", to_bson(val)),
+            Self::Lte(val) => ("$lte", to_bson(val)),
+            Self::In(vals) => ("$in", to_bson(vals)),
+            Self::Nin(vals) => ("$nin", to_bson(vals)),
+            Self::Type(element_type) => ("$type", element_type_to_bson(*element_type)),
+        };
+
+        doc! { operator: bson }
+    }
+}
+
+#[derive(Debug)]
+pub enum StrFilterOperator<'a> {
+    Eq(Cow<'a, str>),
+    Ne(Cow<'a, str>),
+    Gt(Cow<'a, str>),
+    Gte(Cow<'a, str>),
+    Lt(Cow<'a, str>),
+    Lte(Cow<'a, str>),
+    In(Vec<Cow<'a, str>>),
+    Nin(Vec<Cow<'a, str>>),
+    /// Matches documents where the field has the given BSON type.
+    Type(ElementType),
+    /// Matches the field against a regular expression.
+    Regex(Operand<'a, types::Regex>),
+}
+
+impl Operator for StrFilterOperator<'_> {
+    fn to_document(&self) -> Document {
         let (operator, bson) = match self {
             Self::Eq(val) => ("$eq", to_bson(val)),
             Self::Ne(val) => ("$ne", to_bson(val)),
@@ -601,59 +2316,675 @@ impl<T: Serialize + ?Sized> FilterOperator<'_, T> {
             Self::Lte(val) => ("$lte", to_bson(val)),
             Self::In(vals) => ("$in", to_bson(vals)),
             Self::Nin(vals) => ("$nin", to_bson(vals)),
+            Self::Type(element_type) => ("$type", element_type_to_bson(*element_type)),
+            Self::Regex(regex) => ("$regex", to_bson(regex)),
+        };
+
+        doc! { operator: bson }
+    }
+}
+
+#[derive(Debug)]
+pub enum EqFilterOperator<'a, T: Serialize> {
+    Eq(Operand<'a, T>),
+    Ne(Operand<'a, T>),
+    In(Vec<Operand<'a, T>>),
+    Nin(Vec<Operand<'a, T>>),
+    /// Matches documents where the field has the given BSON type.
+    Type(ElementType),
+}
+
+impl<T: Serialize + Send + Sync> Operator for EqFilterOperator<'_, T> {
+    fn to_document(&self) -> Document {
+        let (operator, bson) = match self {
+            Self::Eq(val) => ("$eq", to_bson(val)),
+            Self::Ne(val) => ("$ne", to_bson(val)),
+            Self::In(vals) => ("$in", to_bson(vals)),
+            Self::Nin(vals) => ("$nin", to_bson(vals)),
+            Self::Type(element_type) => ("$type", element_type_to_bson(*element_type)),
+        };
+
+        doc! { operator: bson }
+    }
+}
+
+/// Operators for array fields. `T` is the type of the array elements.
+#[derive(Debug)]
+pub enum ArrayFilterOperator<'a, T: Serialize> {
+    /// Matches arrays equal to the given array.
+    Eq(Vec<Operand<'a, T>>),
+    Ne(Vec<Operand<'a, T>>),
+    /// Matches arrays that contain the given element.
+    Contains(Operand<'a, T>),
+    /// Matches arrays that contain at least one of the given elements.
+    In(Vec<Operand<'a, T>>),
+    Nin(Vec<Operand<'a, T>>),
+    /// Matches arrays that contain all of the given elements.
+    All(Vec<Operand<'a, T>>),
+    /// Matches arrays with exactly the given number of elements.
+    Size(u32),
+    /// Matches arrays that contain at least one element matching the filter.
+    ElemMatch(Box<dyn ElementFilter<T> + 'a>),
+    /// Matches documents where the field has the given BSON type.
+    Type(ElementType),
+}
+
+impl<T: Serialize + Send + Sync> Operator for ArrayFilterOperator<'_, T> {
+    fn to_document(&self) -> Document {
+        let (operator, bson) = match self {
+            Self::Eq(vals) => ("$eq", to_bson(vals)),
+            Self::Ne(vals) => ("$ne", to_bson(vals)),
+            Self::Contains(val) => ("$eq", to_bson(val)),
+            Self::In(vals) => ("$in", to_bson(vals)),
+            Self::Nin(vals) => ("$nin", to_bson(vals)),
+            Self::All(vals) => ("$all", to_bson(vals)),
+            Self::Size(size) => ("$size", Bson::Int64(i64::from(*size))),
+            Self::ElemMatch(filter) => ("$elemMatch", Bson::Document(filter.to_document())),
+            Self::Type(element_type) => ("$type", element_type_to_bson(*element_type)),
+        };
+
+        doc! { operator: bson }
+    }
+}
+
+/// Operators for `Option<T>` fields: presence checks, and the operators of `T`.
+#[derive(Debug)]
+pub enum NullableFilterOperator<O> {
+    /// Matches documents that contain (`true`) or don't contain (`false`) the field.
+    Exists(bool),
+    /// Matches documents where the field is `null` or missing.
+    Null,
+    Value(O),
+}
+
+impl<O> From<O> for NullableFilterOperator<O> {
+    fn from(value: O) -> Self {
+        Self::Value(value)
+    }
+}
+
+impl<O: Operator> Operator for NullableFilterOperator<O> {
+    fn to_document(&self) -> Document {
+        match self {
+            Self::Exists(exists) => doc! { "$exists": *exists },
+            Self::Null => doc! { "$eq": Bson::Null },
+            Self::Value(operator) => operator.to_document(),
+        }
+    }
+}
+
+fn element_type_to_bson(element_type: ElementType) -> Bson {
+    Bson::Int32(i32::from(element_type as u8))
+}
+
+/// A filter applied to the elements of an array of `T` with `$elemMatch`.
+///
+/// Implemented for every [`Filter<T>`](Filter), so typed filters of structs deriving
+/// `Fields` can be used for arrays of these structs.
+pub trait ElementFilter<T: ?Sized>: Send + Sync {
+    fn to_document(&self) -> Document;
+}
+
+impl<T, F: Filter<T> + Sync> ElementFilter<T> for F {
+    fn to_document(&self) -> Document {
+        Filter::to_document(self)
+    }
+}
+
+impl<T: ?Sized> std::fmt::Debug for dyn ElementFilter<T> + '_ {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ElementFilter")
+            .field(&self.to_document())
+            .finish()
+    }
+}
+
+/// An update of documents of `E`.
+///
+/// [`to_document`](Update::to_document) returns the whole update document, with the
+/// changes grouped by update operator, e.g. `{ $set: { name: "Kit" }, $inc: { visits: 1 } }`.
+pub trait Update<E>: Send {
+    fn to_document(&self) -> Document;
+}
+
+/// An update built from a raw document, which includes the update operators, e.g.
+/// `{ $push: { messages: { $each: ["hi"], $slice: -10 } } }`.
+#[derive(Debug)]
+pub struct UntypedUpdate<E>(Document, PhantomData<E>);
+
+impl<E> UntypedUpdate<E> {
+    pub fn new(document: Document) -> Self {
+        Self(document, PhantomData)
+    }
+}
+
+impl<E: Send> Update<E> for UntypedUpdate<E> {
+    fn to_document(&self) -> Document {
+        self.0.clone()
+    }
+}
+
+pub trait UpdateApply<S> {
+    fn apply(self, selectable: &mut S) -> Result<()>;
+}
+
+#[derive(Debug)]
+pub struct UntypedUpdateApply<E: Entity, S: Selectable<E>, F: Fn(&mut S) + Send>(
+    Document,
+    F,
+    PhantomData<(E, S)>,
+);
+
+impl<E: Entity, S: Selectable<E>, F: Fn(&mut S) + Send> UntypedUpdateApply<E, S, F> {
+    pub fn new(document: Document, apply: F) -> Self {
+        Self(document, apply, PhantomData)
+    }
+}
+
+impl<E: Entity, S: Selectable<E>, F: Fn(&mut S) + Send> Update<E> for UntypedUpdateApply<E, S, F> {
+    fn to_document(&self) -> Document {
+        self.0.clone()
+    }
+}
+
+impl<E: Entity, S: Selectable<E>, F: Fn(&mut S) + Send> UpdateApply<S>
+    for UntypedUpdateApply<E, S, F>
+{
+    fn apply(self, selectable: &mut S) -> Result<()> {
+        self.1(selectable);
+        Ok(())
+    }
+}
+
+/// Applies both updates.
+///
+/// Constructed by the `update!` macro when it mixes top-level fields with dot-paths.
+#[derive(Debug)]
+pub struct CombinedUpdate<A, B>(pub A, pub B);
+
+impl<E, A: Update<E>, B: Update<E>> Update<E> for CombinedUpdate<A, B> {
+    fn to_document(&self) -> Document {
+        let mut document = self.0.to_document();
+
+        for (operator, changes) in self.1.to_document() {
+            match (document.get_mut(&operator), changes) {
+                (Some(Bson::Document(existing)), Bson::Document(changes)) => {
+                    existing.extend(changes);
+                }
+                (_, changes) => {
+                    document.insert(operator, changes);
+                }
+            }
+        }
+
+        document
+    }
+}
+
+impl<S, A: UpdateApply<S>, B: UpdateApply<S>> UpdateApply<S> for CombinedUpdate<A, B> {
+    fn apply(self, selectable: &mut S) -> Result<()> {
+        self.0.apply(selectable)?;
+        self.1.apply(selectable)
+    }
+}
+
+/// Applies an update of an embedded document to the field `key`, e.g. `{ "address.zip": "10115" }`.
+///
+/// Usually constructed by the `update!` macro from dot-paths: `update! { address.zip: "10115".into() }`.
+/// Keys of every operator of the nested update are prefixed with `key`.
+/// Applying it to a selectable round-trips the selectable through BSON, so the selectable
+/// must implement `Serialize` (projections generated by the `Entity` derive do). Updating a
+/// field of an embedded document that is `null` fails, just like it does in `MongoDB`.
+pub struct NestedUpdate<E, T, U> {
+    key: String,
+    update: U,
+    _marker: PhantomData<fn() -> (E, T)>,
+}
+
+impl<E, T, U> NestedUpdate<E, T, U> {
+    pub fn new(key: impl Into<String>, update: U) -> Self {
+        Self {
+            key: key.into(),
+            update,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<E, T, U: std::fmt::Debug> std::fmt::Debug for NestedUpdate<E, T, U> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NestedUpdate")
+            .field("key", &self.key)
+            .field("update", &self.update)
+            .finish()
+    }
+}
+
+impl<E, T, U: Update<T>> Update<E> for NestedUpdate<E, T, U> {
+    fn to_document(&self) -> Document {
+        self.update
+            .to_document()
+            .into_iter()
+            .map(|(operator, changes)| match changes {
+                Bson::Document(changes) => {
+                    let changes = changes
+                        .into_iter()
+                        .map(|(key, value)| (format!("{}.{key}", self.key), value))
+                        .collect::<Document>();
+
+                    (operator, Bson::Document(changes))
+                }
+                changes => (operator, changes),
+            })
+            .collect()
+    }
+}
+
+impl<E, T, U, S> UpdateApply<S> for NestedUpdate<E, T, U>
+where
+    T: Serialize + DeserializeOwned,
+    U: UpdateApply<T>,
+    S: Serialize + DeserializeOwned,
+{
+    fn apply(self, selectable: &mut S) -> Result<()> {
+        let mut document = bson::to_document(selectable)?;
+
+        // Projections that don't include the field are left as is
+        let Some(embedded) = document.get_mut(&self.key) else {
+            return Ok(());
         };
 
-        doc! { operator: bson }
+        let mut value = bson::from_bson::<T>(embedded.clone())?;
+        self.update.apply(&mut value)?;
+        *embedded = bson::to_bson(&value)?;
+
+        *selectable = bson::from_document(document)?;
+
+        Ok(())
     }
 }
 
-pub trait Update<E>: Send {
-    fn to_document(&self) -> Document;
+/// An update applied to a single field, e.g. `$inc: 1`.
+///
+/// The `Entity` and `Fields` derives pick the operator type for each field based on its
+/// type, so that only the operators applicable to the field can be used:
+/// - [`NumUpdateOperator`] for numbers
+/// - [`DateUpdateOperator`] for `bson` dates
+/// - [`OrdUpdateOperator`] for other ordered scalars (ids, timestamps, `chrono` dates)
+/// - [`ArrayUpdateOperator`] for `Vec<T>`
+/// - [`NullableUpdateOperator`] for `Option<T>`
+/// - [`SetUpdateOperator`] for everything else
+///
+/// Each operator is also applied to the struct in memory by
+/// [`patch`](SelectableWithId::patch), mirroring what `MongoDB` does to the document.
+pub trait UpdateOperator: Send {
+    /// The type of the field.
+    type Value;
+
+    /// Returns the update operator (e.g. `$inc`) and the operand for the field.
+    fn to_operator(&self) -> (&'static str, Bson);
+
+    /// Applies the operator to the field in memory. Fails if `MongoDB` would fail to
+    /// apply it, e.g. when `$inc` overflows the field.
+    fn apply(self, value: &mut Self::Value) -> Result<()>;
+}
+
+/// Adds the operator of a field to an update document, grouping it with other fields
+/// updated by the same operator.
+#[doc(hidden)]
+pub fn add_update_operator(document: &mut Document, key: &str, operator: &impl UpdateOperator) {
+    let (operator, operand) = operator.to_operator();
+
+    match document.get_mut(operator) {
+        Some(Bson::Document(changes)) => {
+            changes.insert(key, operand);
+        }
+        _ => {
+            document.insert(operator, doc! { key: operand });
+        }
+    }
 }
 
 #[derive(Debug)]
-pub struct UntypedUpdate<E>(Document, PhantomData<E>);
+pub enum SetUpdateOperator<T> {
+    Set(T),
+}
 
-impl<E> UntypedUpdate<E> {
-    fn new(document: Document) -> Self {
-        Self(document, PhantomData)
+impl<T: Serialize + Send> UpdateOperator for SetUpdateOperator<T> {
+    type Value = T;
+
+    fn to_operator(&self) -> (&'static str, Bson) {
+        match self {
+            Self::Set(val) => ("$set", to_bson(val)),
+        }
+    }
+
+    fn apply(self, value: &mut T) -> Result<()> {
+        match self {
+            Self::Set(val) => *value = val,
+        }
+
+        Ok(())
     }
 }
 
-impl<E: Send> Update<E> for UntypedUpdate<E> {
-    fn to_document(&self) -> Document {
-        self.0.clone()
+#[derive(Debug)]
+pub enum NumUpdateOperator<T> {
+    Set(T),
+    /// Increments the field by the given amount.
+    Inc(T),
+    /// Multiplies the field by the given number.
+    Mul(T),
+    /// Sets the field to the given value if it's less than the current one.
+    Min(T),
+    /// Sets the field to the given value if it's greater than the current one.
+    Max(T),
+}
+
+impl<T: Serialize + Send + PartialOrd + Number> UpdateOperator for NumUpdateOperator<T> {
+    type Value = T;
+
+    fn to_operator(&self) -> (&'static str, Bson) {
+        match self {
+            Self::Set(val) => ("$set", to_bson(val)),
+            Self::Inc(val) => ("$inc", to_bson(val)),
+            Self::Mul(val) => ("$mul", to_bson(val)),
+            Self::Min(val) => ("$min", to_bson(val)),
+            Self::Max(val) => ("$max", to_bson(val)),
+        }
+    }
+
+    fn apply(self, value: &mut T) -> Result<()> {
+        match self {
+            Self::Set(val) => *value = val,
+            Self::Inc(val) => {
+                *value = value.checked_add(val).ok_or_else(|| overflow("$inc"))?;
+            }
+            Self::Mul(val) => {
+                *value = value.checked_mul(val).ok_or_else(|| overflow("$mul"))?;
+            }
+            Self::Min(val) => {
+                if val < *value {
+                    *value = val;
+                }
+            }
+            Self::Max(val) => {
+                if val > *value {
+                    *value = val;
+                }
+            }
+        }
+
+        Ok(())
     }
 }
 
-pub trait UpdateApply<S> {
-    fn apply(self, selectable: &mut S) -> Result<()>;
+fn overflow(operator: &str) -> mongodb::error::Error {
+    mongodb::error::Error::custom(format!("{operator} overflows the field"))
+}
+
+/// A number that `$inc` and `$mul` can be applied to in memory.
+///
+/// Integers fail on overflow, since `MongoDB` can't store the result in the field either,
+/// while floats follow IEEE 754.
+pub trait Number: Copy {
+    fn checked_add(self, rhs: Self) -> Option<Self>;
+
+    fn checked_mul(self, rhs: Self) -> Option<Self>;
+}
+
+macro_rules! impl_number {
+    (integer: $( $ty: ty ),*; float: $( $float: ty ),*) => {
+        $(
+            impl Number for $ty {
+                fn checked_add(self, rhs: Self) -> Option<Self> {
+                    <$ty>::checked_add(self, rhs)
+                }
+
+                fn checked_mul(self, rhs: Self) -> Option<Self> {
+                    <$ty>::checked_mul(self, rhs)
+                }
+            }
+        )*
+
+        $(
+            impl Number for $float {
+                fn checked_add(self, rhs: Self) -> Option<Self> {
+                    Some(self + rhs)
+                }
+
+                fn checked_mul(self, rhs: Self) -> Option<Self> {
+                    Some(self * rhs)
+                }
+            }
+        )*
+    };
 }
 
+impl_number!(integer: i8, i16, i32, i64, isize, u8, u16, u32, u64, usize; float: f32, f64);
+
 #[derive(Debug)]
-pub struct UntypedUpdateApply<E: Entity, S: Selectable<E>, F: Fn(&mut S) + Send>(
-    Document,
-    F,
-    PhantomData<(E, S)>,
-);
+pub enum OrdUpdateOperator<T> {
+    Set(T),
+    /// Sets the field to the given value if it's less than the current one.
+    Min(T),
+    /// Sets the field to the given value if it's greater than the current one.
+    Max(T),
+}
 
-impl<E: Entity, S: Selectable<E>, F: Fn(&mut S) + Send> UntypedUpdateApply<E, S, F> {
-    pub fn new(document: Document, apply: F) -> Self {
-        Self(document, apply, PhantomData)
+impl<T: Serialize + Send + PartialOrd> UpdateOperator for OrdUpdateOperator<T> {
+    type Value = T;
+
+    fn to_operator(&self) -> (&'static str, Bson) {
+        match self {
+            Self::Set(val) => ("$set", to_bson(val)),
+            Self::Min(val) => ("$min", to_bson(val)),
+            Self::Max(val) => ("$max", to_bson(val)),
+        }
+    }
+
+    fn apply(self, value: &mut T) -> Result<()> {
+        match self {
+            Self::Set(val) => *value = val,
+            Self::Min(val) => {
+                if val < *value {
+                    *value = val;
+                }
+            }
+            Self::Max(val) => {
+                if val > *value {
+                    *value = val;
+                }
+            }
+        }
+
+        Ok(())
     }
 }
 
-impl<E: Entity, S: Selectable<E>, F: Fn(&mut S) + Send> Update<E> for UntypedUpdateApply<E, S, F> {
-    fn to_document(&self) -> Document {
-        self.0.clone()
+/// A date that can be set to the current date with `$currentDate`.
+pub trait CurrentDate {
+    fn now() -> Self;
+}
+
+impl CurrentDate for bson::DateTime {
+    fn now() -> Self {
+        bson::DateTime::now()
     }
 }
 
-impl<E: Entity, S: Selectable<E>, F: Fn(&mut S) + Send> UpdateApply<S>
-    for UntypedUpdateApply<E, S, F>
-{
-    fn apply(self, selectable: &mut S) -> Result<()> {
-        self.1(selectable);
+impl CurrentDate for types::DateTime {
+    fn now() -> Self {
+        types::DateTime(bson::DateTime::now())
+    }
+}
+
+#[derive(Debug)]
+pub enum DateUpdateOperator<T> {
+    Set(T),
+    /// Sets the field to the given date if it's earlier than the current one.
+    Min(T),
+    /// Sets the field to the given date if it's later than the current one.
+    Max(T),
+    /// Sets the field to the current date of the server (`$currentDate`). The struct in
+    /// memory is set to the current date of the client.
+    CurrentDate,
+}
+
+impl<T: Serialize + Send + PartialOrd + CurrentDate> UpdateOperator for DateUpdateOperator<T> {
+    type Value = T;
+
+    fn to_operator(&self) -> (&'static str, Bson) {
+        match self {
+            Self::Set(val) => ("$set", to_bson(val)),
+            Self::Min(val) => ("$min", to_bson(val)),
+            Self::Max(val) => ("$max", to_bson(val)),
+            Self::CurrentDate => ("$currentDate", Bson::Boolean(true)),
+        }
+    }
+
+    fn apply(self, value: &mut T) -> Result<()> {
+        match self {
+            Self::Set(val) => *value = val,
+            Self::Min(val) => {
+                if val < *value {
+                    *value = val;
+                }
+            }
+            Self::Max(val) => {
+                if val > *value {
+                    *value = val;
+                }
+            }
+            Self::CurrentDate => *value = T::now(),
+        }
+
+        Ok(())
+    }
+}
+
+/// Operators for array fields. `T` is the type of the array elements.
+///
+/// Elements are compared by their BSON representation, as `MongoDB` does, so `T` doesn't
+/// need to implement `PartialEq`.
+#[derive(Debug)]
+pub enum ArrayUpdateOperator<T> {
+    Set(Vec<T>),
+    /// Appends the element to the array.
+    Push(T),
+    /// Removes all occurrences of the element from the array. As in `MongoDB`, an element
+    /// that serializes to a document is a condition rather than a value: it removes the
+    /// documents whose fields match its fields, in any order, with `null` matching missing
+    /// fields and array fields matching any of their elements. Query operators such as `$gte`
+    /// aren't supported in memory, so [`apply`](UpdateOperator::apply) fails on them.
+    Pull(T),
+    /// Appends the element to the array, unless it's already there.
+    AddToSet(T),
+    /// Removes the first element of the array.
+    PopFirst,
+    /// Removes the last element of the array.
+    PopLast,
+}
+
+impl<T: Serialize + Send> UpdateOperator for ArrayUpdateOperator<T> {
+    type Value = Vec<T>;
+
+    fn to_operator(&self) -> (&'static str, Bson) {
+        match self {
+            Self::Set(vals) => ("$set", to_bson(vals)),
+            Self::Push(val) => ("$push", to_bson(val)),
+            Self::Pull(val) => ("$pull", to_bson(val)),
+            Self::AddToSet(val) => ("$addToSet", to_bson(val)),
+            Self::PopFirst => ("$pop", Bson::Int32(-1)),
+            Self::PopLast => ("$pop", Bson::Int32(1)),
+        }
+    }
+
+    fn apply(self, value: &mut Vec<T>) -> Result<()> {
+        match self {
+            Self::Set(vals) => *value = vals,
+            Self::Push(val) => value.push(val),
+            Self::Pull(val) => {
+                let condition = to_bson(&val);
+                let operators = match &condition {
+                    Bson::Document(condition) => condition.keys().any(|key| key.starts_with('$')),
+                    _ => false,
+                };
+                if operators {
+                    return Err(mongodb::error::Error::custom(format!(
+                        "`$pull` with the condition {condition} can't be applied in memory"
+                    )));
+                }
+
+                value.retain(|element| !pull_matches(&to_bson(element), &condition));
+            }
+            Self::AddToSet(val) => {
+                let bson = to_bson(&val);
+                if !value.iter().any(|element| to_bson(element) == bson) {
+                    value.push(val);
+                }
+            }
+            Self::PopFirst => {
+                if !value.is_empty() {
+                    value.remove(0);
+                }
+            }
+            Self::PopLast => {
+                value.pop();
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Whether `$pull` removes the element, a document condition matching documents by their fields
+fn pull_matches(element: &Bson, condition: &Bson) -> bool {
+    match (element, condition) {
+        (Bson::Document(element), Bson::Document(condition)) => {
+            condition
+                .iter()
+                .all(|(path, expected)| match get_path(element, path) {
+                    Some(Bson::Array(values)) if !matches!(expected, Bson::Array(_)) => {
+                        values.contains(expected)
+                    }
+                    Some(value) => value == expected,
+                    None => *expected == Bson::Null,
+                })
+        }
+        // A condition on fields doesn't match other values
+        (_, Bson::Document(_)) => false,
+        (element, value) => element == value,
+    }
+}
+
+/// Operators for `Option<T>` fields.
+///
+/// Other operators aren't available, since `MongoDB` treats `null` and missing fields
+/// differently (e.g. `$inc` fails on `null`, but sets a missing field).
+#[derive(Debug)]
+pub enum NullableUpdateOperator<T> {
+    Set(Option<T>),
+    /// Removes the field from the document.
+    Unset,
+}
+
+impl<T: Serialize + Send> UpdateOperator for NullableUpdateOperator<T> {
+    type Value = Option<T>;
+
+    fn to_operator(&self) -> (&'static str, Bson) {
+        match self {
+            Self::Set(val) => ("$set", to_bson(val)),
+            Self::Unset => ("$unset", Bson::String(String::new())),
+        }
+    }
+
+    fn apply(self, value: &mut Option<T>) -> Result<()> {
+        match self {
+            Self::Set(val) => *value = val,
+            Self::Unset => *value = None,
+        }
+
         Ok(())
     }
 }
@@ -664,9 +2995,43 @@ pub enum Order {
     Desc,
 }
 
-#[derive(Debug)]
+fn sort_document<F: Display>(sort: impl IntoIterator<Item = (F, Order)>) -> Document {
+    sort.into_iter()
+        .map(|(field, order)| {
+            (
+                field.to_string(),
+                match order {
+                    Order::Asc => bson!(1),
+                    Order::Desc => bson!(-1),
+                },
+            )
+        })
+        .collect()
+}
+
+/// Which version of the document [`Selectable::find_one_and_update_with_opts`] returns.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum ReturnDocument {
+    /// The document as it was before the update.
+    #[default]
+    Before,
+    /// The document as it is after the update.
+    After,
+}
+
+impl From<ReturnDocument> for mongodb::options::ReturnDocument {
+    fn from(value: ReturnDocument) -> Self {
+        match value {
+            ReturnDocument::Before => Self::Before,
+            ReturnDocument::After => Self::After,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
 pub enum Field<T> {
     Set(T),
+    #[default]
     Omit,
 }
 
@@ -679,12 +3044,6 @@ impl<T> Field<T> {
     }
 }
 
-impl<T> Default for Field<T> {
-    fn default() -> Self {
-        Self::Omit
-    }
-}
-
 #[derive(Debug)]
 pub struct Lock<T>(T);
 
@@ -708,6 +3067,102 @@ impl<T> std::ops::DerefMut for Lock<T> {
     }
 }
 
+/// The result of [`Entity::update`], [`Entity::update_one`] and [`Entity::upsert_one`].
+#[derive(Debug, Clone, Copy)]
+pub struct UpdateResult<Id> {
+    matched: u64,
+    modified: u64,
+    upserted_id: Option<Id>,
+}
+
+impl<Id: DeserializeOwned> TryFrom<mongodb::results::UpdateResult> for UpdateResult<Id> {
+    type Error = mongodb::error::Error;
+
+    /// Fails if the upserted id doesn't deserialize into `Id`.
+    fn try_from(result: mongodb::results::UpdateResult) -> Result<Self> {
+        Ok(Self {
+            matched: result.matched_count,
+            modified: result.modified_count,
+            upserted_id: result.upserted_id.map(bson::from_bson).transpose()?,
+        })
+    }
+}
+
+impl<Id: Copy> UpdateResult<Id> {
+    /// The number of documents that matched the filter.
+    pub fn matched(&self) -> u64 {
+        self.matched
+    }
+
+    /// The number of documents that were actually changed. Documents that already had
+    /// the values of the update are matched, but not modified.
+    pub fn modified(&self) -> u64 {
+        self.modified
+    }
+
+    /// The id of the document inserted by an upsert, if any.
+    pub fn upserted_id(&self) -> Option<Id> {
+        self.upserted_id
+    }
+
+    /// Whether an upsert inserted a new document instead of updating an existing one.
+    pub fn created(&self) -> bool {
+        self.upserted_id.is_some()
+    }
+}
+
+/// The result of [`Entity::delete`] and [`Entity::delete_one`].
+#[derive(Debug, Clone, Copy)]
+pub struct DeleteResult {
+    deleted: u64,
+}
+
+impl DeleteResult {
+    /// The number of documents that were deleted.
+    pub fn deleted(&self) -> u64 {
+        self.deleted
+    }
+}
+
+impl From<mongodb::results::DeleteResult> for DeleteResult {
+    fn from(result: mongodb::results::DeleteResult) -> Self {
+        Self {
+            deleted: result.deleted_count,
+        }
+    }
+}
+
+/// The result of [`Entity::insert_many`].
+#[derive(Debug, Clone)]
+pub struct InsertManyResult<Id> {
+    inserted_ids: Vec<Id>,
+}
+
+impl<Id: DeserializeOwned> TryFrom<mongodb::results::InsertManyResult> for InsertManyResult<Id> {
+    type Error = mongodb::error::Error;
+
+    /// Orders the ids by the index of their entity, and fails if one doesn't deserialize
+    /// into `Id`.
+    fn try_from(result: mongodb::results::InsertManyResult) -> Result<Self> {
+        let inserted_ids = result
+            .inserted_ids
+            .into_iter()
+            .collect::<BTreeMap<_, _>>()
+            .into_values()
+            .map(bson::from_bson)
+            .collect::<std::result::Result<_, _>>()?;
+
+        Ok(Self { inserted_ids })
+    }
+}
+
+impl<Id> InsertManyResult<Id> {
+    /// The ids of the inserted documents, in the order of the inserted entities.
+    pub fn inserted_ids(&self) -> &[Id] {
+        &self.inserted_ids
+    }
+}
+
 mod example {
     // use super::{Entity, Mongo, Result, Selectable, by_id};
     // use mongodb::bson::oid::ObjectId;
@@ -756,3 +3211,235 @@ mod example {
     //     Ok(())
     // }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(direction: PageDirection) -> PageToken {
+        PageToken {
+            direction,
+            position: doc! { "age": 30, "_id": 1 },
+        }
+    }
+
+    #[test]
+    fn page_token_round_trip() {
+        for token in [token(PageDirection::Next), token(PageDirection::Prev)] {
+            assert_eq!(token.to_string().parse::<PageToken>().unwrap(), token);
+
+            let bson = bson::to_bson(&token).unwrap();
+            assert_eq!(bson::from_bson::<PageToken>(bson).unwrap(), token);
+        }
+
+        assert!("".parse::<PageToken>().is_err());
+        assert!("zz".parse::<PageToken>().is_err());
+        assert!(bson::from_document::<PageToken>(doc! { "d": "up", "p": {} }).is_err());
+    }
+
+    #[test]
+    fn keyset_filter_next() {
+        let sort = doc! { "age": -1, "_id": 1 };
+        let position = doc! { "age": 30, "_id": 1 };
+
+        assert_eq!(
+            keyset_filter(&sort, &position, PageDirection::Next),
+            doc! {
+                "$or": [
+                    { "$or": [{ "age": { "$lt": 30 } }, { "age": { "$eq": null } }] },
+                    { "age": { "$eq": 30 }, "_id": { "$gt": 1 } },
+                ]
+            }
+        );
+    }
+
+    #[test]
+    fn keyset_filter_prev() {
+        let sort = doc! { "age": -1, "_id": 1 };
+        let position = doc! { "age": 30, "_id": 1 };
+
+        assert_eq!(
+            keyset_filter(&sort, &position, PageDirection::Prev),
+            doc! {
+                "$or": [
+                    { "age": { "$gt": 30 } },
+                    {
+                        "age": { "$eq": 30 },
+                        "$or": [{ "_id": { "$lt": 1 } }, { "_id": { "$eq": null } }],
+                    },
+                ]
+            }
+        );
+    }
+
+    #[test]
+    fn keyset_filter_null() {
+        let sort = doc! { "nickname": 1, "_id": 1 };
+        let position = doc! { "nickname": null, "_id": 1 };
+
+        assert_eq!(
+            keyset_filter(&sort, &position, PageDirection::Next),
+            doc! {
+                "$or": [
+                    { "nickname": { "$ne": null } },
+                    { "nickname": { "$eq": null }, "_id": { "$gt": 1 } },
+                ]
+            }
+        );
+
+        assert_eq!(
+            keyset_filter(&sort, &position, PageDirection::Prev),
+            doc! {
+                "$or": [
+                    {
+                        "nickname": { "$eq": null },
+                        "$or": [{ "_id": { "$lt": 1 } }, { "_id": { "$eq": null } }],
+                    },
+                ]
+            }
+        );
+    }
+
+    #[test]
+    fn page_projection() {
+        let sort = doc! { "address.city": 1, "_id": 1 };
+
+        assert_eq!(
+            project_sort_keys(doc! { "name": 1 }, &sort),
+            doc! { "name": 1, "address.city": 1, "_id": 1 }
+        );
+        assert_eq!(
+            project_sort_keys(doc! { "address": 1, "_id": 1 }, &sort),
+            doc! { "address": 1, "_id": 1 }
+        );
+        assert_eq!(
+            project_sort_keys(doc! { "address.city.name": 1, "addresses": 1 }, &sort),
+            doc! { "addresses": 1, "address.city": 1, "_id": 1 }
+        );
+    }
+
+    #[test]
+    fn sort_order() {
+        assert!(is_ascending(&Bson::Int32(1)));
+        assert!(is_ascending(&Bson::Int64(1)));
+        assert!(!is_ascending(&Bson::Int32(-1)));
+        assert!(!is_ascending(&Bson::Int64(-1)));
+        assert!(!is_ascending(&Bson::Double(-1.0)));
+    }
+
+    struct Users;
+
+    #[test]
+    fn update_documents_need_operators() {
+        let update = UntypedUpdate::<Users>::new(doc! { "$set": { "name": "Kit" } });
+        assert_eq!(
+            update_document(&update).unwrap(),
+            doc! { "$set": { "name": "Kit" } }
+        );
+
+        let update = UntypedUpdate::<Users>::new(doc! { "name": "Kit" });
+        let error = update_document(&update).unwrap_err();
+        assert!(error.to_string().contains("found `name`"), "{error}");
+
+        // The driver only checks the first key
+        let update = UntypedUpdate::<Users>::new(doc! { "$set": { "name": "Kit" }, "age": 30 });
+        assert!(update_document(&update).is_err());
+    }
+
+    #[test]
+    fn upsert_documents() {
+        let update = doc! { "$set": { "name": "Kit" }, "$inc": { "logins": 1 } };
+
+        assert_eq!(
+            upsert_document(update.clone(), doc! {}).unwrap(),
+            update.clone()
+        );
+        assert_eq!(
+            upsert_document(
+                update.clone(),
+                doc! { "$set": { "created": 1, "tags": [] } }
+            )
+            .unwrap(),
+            doc! {
+                "$set": { "name": "Kit" },
+                "$inc": { "logins": 1 },
+                "$setOnInsert": { "created": 1, "tags": [] },
+            }
+        );
+
+        let error = upsert_document(update.clone(), doc! { "$inc": { "logins": 1 } }).unwrap_err();
+        assert!(error.to_string().contains("can only set fields"), "{error}");
+
+        let on_insert = doc! { "$set": { "created": 1 }, "$unset": { "tags": "" } };
+        assert!(upsert_document(update.clone(), on_insert).is_err());
+    }
+
+    #[test]
+    fn upsert_documents_reject_conflicts() {
+        let update = doc! { "$set": { "name": "Kit" }, "$inc": { "stats.logins": 1 } };
+
+        for on_insert in [
+            doc! { "$set": { "name": "Kat" } },
+            doc! { "$set": { "stats.logins": 0 } },
+            doc! { "$set": { "stats": {} } },
+            doc! { "$set": { "name.first": "Kat" } },
+        ] {
+            let error = upsert_document(update.clone(), on_insert).unwrap_err();
+            assert!(error.to_string().contains("set by both"), "{error}");
+        }
+
+        assert!(
+            upsert_document(update, doc! { "$set": { "names": [], "stats.visits": 0 } }).is_ok()
+        );
+    }
+
+    #[test]
+    fn id_keys() {
+        assert_eq!(id_key(&1_i32).unwrap(), id_key(&1_i64).unwrap());
+        assert_eq!(id_key(&1.0_f64).unwrap(), id_key(&1_i64).unwrap());
+        assert_ne!(id_key(&1.5_f64).unwrap(), id_key(&1_i64).unwrap());
+        assert_ne!(id_key(&1_i64).unwrap(), id_key(&"1").unwrap());
+
+        let id = ObjectId::new();
+        assert_eq!(id_key(&id).unwrap(), bson_key(&Bson::ObjectId(id)));
+    }
+
+    #[test]
+    fn found_by_ids() {
+        // The server may return the documents in any order, and with ids of another
+        // numeric type than the requested ones
+        let found = FoundByIds::<i32, Document>::match_documents(
+            vec![3, 1, 2, 1],
+            vec![
+                doc! { "_id": 1_i64, "name": "Kit" },
+                doc! { "_id": 3.0, "name": "Tom" },
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            found.iter().collect::<Vec<_>>(),
+            [
+                (3, Some(&doc! { "_id": 3.0, "name": "Tom" })),
+                (1, Some(&doc! { "_id": 1_i64, "name": "Kit" })),
+                (2, None),
+                (1, Some(&doc! { "_id": 1_i64, "name": "Kit" })),
+            ]
+        );
+        assert_eq!(found.missing().collect::<Vec<_>>(), [2]);
+        assert_eq!(found.into_entities().len(), 4);
+    }
+
+    #[test]
+    fn dotted_path() {
+        let document = doc! { "address": { "city": "Berlin" }, "name": "Kit" };
+
+        assert_eq!(get_path(&document, "name"), Some(&Bson::from("Kit")));
+        assert_eq!(
+            get_path(&document, "address.city"),
+            Some(&Bson::from("Berlin"))
+        );
+        assert_eq!(get_path(&document, "address.zip"), None);
+        assert_eq!(get_path(&document, "name.first"), None);
+    }
+}
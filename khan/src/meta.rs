@@ -1,19 +1,152 @@
 use crate::Mongo;
-use mongodb::{IndexModel, bson::Document, error::Result};
+use futures_util::{
+    FutureExt, TryStreamExt,
+    future::{self, BoxFuture, Either},
+};
+#[cfg(feature = "schema")]
+use mongodb::options::{ValidationAction, ValidationLevel};
+use mongodb::{
+    Collection, Database, IndexModel,
+    bson::{self, Bson, DateTime, Document, RawDocumentBuf, doc, oid::ObjectId},
+    error::{Error, ErrorKind, Result, WriteFailure},
+    options::IndexOptions,
+    results::UpdateResult,
+};
+#[cfg(feature = "schema")]
+use std::collections::BTreeSet;
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::Infallible,
+    fmt::{self, Display, Formatter},
+    pin::pin,
+    time::{Duration, SystemTime},
+};
 
 #[doc(hidden)]
 pub struct EntityMetadataWrapper(pub EntityMetadata);
 
 inventory::collect!(EntityMetadataWrapper);
 
+/// Submits the metadata of an entity to the registry. Called by `#[derive(Entity)]`.
+#[cfg(feature = "schema")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! register_entity {
+    ($entity:ty) => {
+        $crate::inventory::submit! {
+            $crate::meta::EntityMetadataWrapper($crate::meta::EntityMetadata::new(
+                <$entity as $crate::EntityName>::ENTITY_NAME,
+                <$entity as $crate::Entity>::COLLECTION_NAME,
+                <$entity as $crate::Entity>::indexes,
+                {
+                    fn deserialize(
+                        bytes: &[u8],
+                    ) -> ::std::result::Result<(), $crate::mongodb::bson::de::Error> {
+                        $crate::mongodb::bson::from_slice::<$entity>(bytes).map(::std::mem::drop)
+                    }
+
+                    deserialize
+                },
+                {
+                    fn json_schema(
+                        generator: &mut $crate::schemars::r#gen::SchemaGenerator,
+                    ) -> ::std::option::Option<$crate::schemars::schema::Schema> {
+                        #[allow(unused_imports)]
+                        use $crate::meta::{WithJsonSchema, WithoutJsonSchema};
+
+                        (&$crate::meta::JsonSchemaProbe::<$entity>(::std::marker::PhantomData))
+                            .json_schema(generator)
+                    }
+
+                    json_schema
+                },
+            ))
+        }
+    };
+}
+
+/// Submits the metadata of an entity to the registry. Called by `#[derive(Entity)]`.
+#[cfg(not(feature = "schema"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! register_entity {
+    ($entity:ty) => {
+        $crate::inventory::submit! {
+            $crate::meta::EntityMetadataWrapper($crate::meta::EntityMetadata::new(
+                <$entity as $crate::EntityName>::ENTITY_NAME,
+                <$entity as $crate::Entity>::COLLECTION_NAME,
+                <$entity as $crate::Entity>::indexes,
+                {
+                    fn deserialize(
+                        bytes: &[u8],
+                    ) -> ::std::result::Result<(), $crate::mongodb::bson::de::Error> {
+                        $crate::mongodb::bson::from_slice::<$entity>(bytes).map(::std::mem::drop)
+                    }
+
+                    deserialize
+                },
+            ))
+        }
+    };
+}
+
+/// Metadata of an entity, submitted to the registry by `#[derive(Entity)]`.
 pub struct EntityMetadata {
+    entity_name: &'static str,
     collection_name: &'static str,
     indexes_ptr: fn() -> &'static [IndexModel],
+    deserialize_ptr: DeserializePtr,
+    #[cfg(feature = "schema")]
+    json_schema_ptr: JsonSchemaPtr,
+}
+
+type DeserializePtr = fn(&[u8]) -> std::result::Result<(), mongodb::bson::de::Error>;
+
+#[cfg(feature = "schema")]
+type JsonSchemaPtr = fn(&mut schemars::r#gen::SchemaGenerator) -> Option<schemars::schema::Schema>;
+
+impl EntityMetadata {
     #[cfg(feature = "schema")]
-    json_schema_ptr: fn(&mut schemars::r#gen::SchemaGenerator) -> schemars::schema::Schema,
+    #[doc(hidden)]
+    pub const fn new(
+        entity_name: &'static str,
+        collection_name: &'static str,
+        indexes_ptr: fn() -> &'static [IndexModel],
+        deserialize_ptr: DeserializePtr,
+        json_schema_ptr: JsonSchemaPtr,
+    ) -> Self {
+        Self {
+            entity_name,
+            collection_name,
+            indexes_ptr,
+            deserialize_ptr,
+            json_schema_ptr,
+        }
+    }
+
+    #[cfg(not(feature = "schema"))]
+    #[doc(hidden)]
+    pub const fn new(
+        entity_name: &'static str,
+        collection_name: &'static str,
+        indexes_ptr: fn() -> &'static [IndexModel],
+        deserialize_ptr: DeserializePtr,
+    ) -> Self {
+        Self {
+            entity_name,
+            collection_name,
+            indexes_ptr,
+            deserialize_ptr,
+        }
+    }
 }
 
 impl EntityMetadata {
+    /// The path of the entity type, see [`EntityName`](crate::EntityName).
+    pub fn entity_name(&self) -> &'static str {
+        self.entity_name
+    }
+
     pub fn collection_name(&self) -> &'static str {
         self.collection_name
     }
@@ -22,64 +155,350 @@ impl EntityMetadata {
         (self.indexes_ptr)()
     }
 
+    /// The JSON schema of the entity, or `None` if it doesn't implement
+    /// [`JsonSchema`](schemars::JsonSchema).
+    ///
+    /// # Panics
+    ///
+    /// Panics if the schema uses keywords unsupported by `MongoDB` schema validation. Use
+    /// [`EntityMetadata::try_json_schema`] to handle this case.
     #[cfg(feature = "schema")]
-    pub fn json_schema(&self) -> schemars::schema::Schema {
-        #[derive(Debug, Clone)]
-        struct Visitor;
+    #[deprecated(
+        note = "use `try_json_schema`, which returns the unsupported keywords instead of panicking"
+    )]
+    pub fn json_schema(&self) -> Option<schemars::schema::Schema> {
+        self.try_json_schema(false)
+            .unwrap_or_else(|error| panic!("{error}"))
+    }
 
-        impl schemars::visit::Visitor for Visitor {
-            fn visit_schema_object(&mut self, schema: &mut schemars::schema::SchemaObject) {
-                assert!(
-                    schema.reference.is_none(),
-                    "`$ref` keyword is not supported by MongoDB schema validation. Make sure your entities don't contain recursive types"
-                );
-                assert!(
-                    schema
-                        .metadata
-                        .as_ref()
-                        .and_then(|metadata| metadata.default.as_ref())
-                        .is_none(),
-                    "`default` keyword is not supported by MongoDB schema validation"
-                );
-                assert!(
-                    schema.format.is_none(),
-                    "`format` keyword is not supported by MongoDB schema validation"
-                );
-                assert!(
-                    schema
-                        .metadata
-                        .as_ref()
-                        .and_then(|metadata| metadata.id.as_ref())
-                        .is_none(),
-                    "`id` keyword is not supported by MongoDB schema validation"
-                );
-                assert!(
-                    if let Some(typ) = &schema.instance_type {
-                        match typ {
-                            schemars::schema::SingleOrVec::Single(typ) => {
-                                **typ != schemars::schema::InstanceType::Integer
-                            }
-                            schemars::schema::SingleOrVec::Vec(types) => types
-                                .iter()
-                                .all(|typ| *typ != schemars::schema::InstanceType::Integer),
-                        }
-                    } else {
-                        true
-                    },
-                    "`integer` type is not supported by MongoDB schema validation. Use `khan::types::Int` instead of std integer types"
-                );
+    /// The JSON schema of the entity, or `None` if it doesn't implement
+    /// [`JsonSchema`](schemars::JsonSchema). Fails with every keyword unsupported by `MongoDB`
+    /// schema validation. With `rewrite`, the fixable ones are rewritten instead: `integer`
+    /// types become `bsonType: ["int", "long"]`, and `format`, `default` and `id` are stripped.
+    #[cfg(feature = "schema")]
+    pub fn try_json_schema(
+        &self,
+        rewrite: bool,
+    ) -> std::result::Result<Option<schemars::schema::Schema>, SchemaError> {
+        let mut generator = schemars::r#gen::SchemaGenerator::new(
+            schemars::r#gen::SchemaSettings::default().with(|s| s.inline_subschemas = true),
+        );
+        let Some(mut schema) = (self.json_schema_ptr)(&mut generator) else {
+            return Ok(None);
+        };
 
-                schemars::visit::visit_schema_object(self, schema);
-            }
+        let mut incompatibilities = Vec::new();
+        check_schema(&mut schema, "#", rewrite, &mut incompatibilities);
+
+        if incompatibilities.is_empty() {
+            Ok(Some(schema))
+        } else {
+            Err(SchemaError {
+                entity_name: self.entity_name,
+                incompatibilities,
+            })
         }
+    }
+}
 
-        let mut generator = schemars::r#gen::SchemaGenerator::new(
-            schemars::r#gen::SchemaSettings::default().with(|s| {
-                s.inline_subschemas = true;
-                s.visitors = vec![Box::new(Visitor)];
-            }),
+/// Keywords of an entity schema which are unsupported by `MongoDB` schema validation,
+/// returned by [`EntityMetadata::try_json_schema`].
+#[cfg(feature = "schema")]
+#[derive(Debug, Clone)]
+pub struct SchemaError {
+    entity_name: &'static str,
+    incompatibilities: Vec<SchemaIncompatibility>,
+}
+
+#[cfg(feature = "schema")]
+impl SchemaError {
+    /// The name of the entity type with the incompatible schema.
+    pub fn entity_name(&self) -> &'static str {
+        self.entity_name
+    }
+
+    pub fn incompatibilities(&self) -> &[SchemaIncompatibility] {
+        &self.incompatibilities
+    }
+}
+
+#[cfg(feature = "schema")]
+impl Display for SchemaError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "schema of `{}` is not supported by `MongoDB` schema validation:",
+            self.entity_name
+        )?;
+        for incompatibility in &self.incompatibilities {
+            write!(f, "\n  {incompatibility}")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "schema")]
+impl std::error::Error for SchemaError {}
+
+/// A keyword unsupported by `MongoDB` schema validation, found at `path` in the schema.
+#[cfg(feature = "schema")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaIncompatibility {
+    path: String,
+    keyword: SchemaKeyword,
+}
+
+#[cfg(feature = "schema")]
+impl SchemaIncompatibility {
+    /// JSON pointer to the subschema containing the keyword, e.g. `#/properties/age`.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn keyword(&self) -> SchemaKeyword {
+        self.keyword
+    }
+}
+
+#[cfg(feature = "schema")]
+impl Display for SchemaIncompatibility {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.keyword)
+    }
+}
+
+#[cfg(feature = "schema")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SchemaKeyword {
+    /// `$ref`, produced by recursive types.
+    Ref,
+    Default,
+    Format,
+    Id,
+    /// `integer` type, produced by std integer types.
+    Integer,
+}
+
+#[cfg(feature = "schema")]
+impl SchemaKeyword {
+    /// Whether [`EntityMetadata::try_json_schema`] can rewrite the keyword.
+    pub fn is_fixable(self) -> bool {
+        !matches!(self, Self::Ref)
+    }
+}
+
+#[cfg(feature = "schema")]
+impl Display for SchemaKeyword {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ref => write!(
+                f,
+                "`$ref` keyword is not supported. Make sure your entities don't contain recursive types"
+            ),
+            Self::Default => write!(f, "`default` keyword is not supported"),
+            Self::Format => write!(f, "`format` keyword is not supported"),
+            Self::Id => write!(f, "`id` keyword is not supported"),
+            Self::Integer => write!(
+                f,
+                "`integer` type is not supported. Use `khan::types::Int32` or `khan::types::Int64` instead of std integer types"
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "schema")]
+fn check_schema(
+    schema: &mut schemars::schema::Schema,
+    path: &str,
+    rewrite: bool,
+    incompatibilities: &mut Vec<SchemaIncompatibility>,
+) {
+    use schemars::schema::{InstanceType, Schema, SingleOrVec};
+
+    let Schema::Object(schema) = schema else {
+        return;
+    };
+
+    let mut found = |keyword: SchemaKeyword| {
+        let fixed = rewrite && keyword.is_fixable();
+        if !fixed {
+            incompatibilities.push(SchemaIncompatibility {
+                path: path.to_owned(),
+                keyword,
+            });
+        }
+        fixed
+    };
+
+    if schema.reference.is_some() {
+        found(SchemaKeyword::Ref);
+    }
+    if schema.format.is_some() && found(SchemaKeyword::Format) {
+        schema.format = None;
+    }
+    if let Some(metadata) = &mut schema.metadata {
+        if metadata.default.is_some() && found(SchemaKeyword::Default) {
+            metadata.default = None;
+        }
+        if metadata.id.is_some() && found(SchemaKeyword::Id) {
+            metadata.id = None;
+        }
+    }
+
+    let types = match &schema.instance_type {
+        Some(SingleOrVec::Single(typ)) => vec![**typ],
+        Some(SingleOrVec::Vec(types)) => types.clone(),
+        None => Vec::new(),
+    };
+    // `type` and `bsonType` can't be used together, so all types are moved to `bsonType`
+    if types.contains(&InstanceType::Integer) && found(SchemaKeyword::Integer) {
+        let bson_types = types
+            .into_iter()
+            .flat_map(|typ| bson_type_aliases(typ).iter().copied())
+            .collect::<Vec<_>>();
+        schema.instance_type = None;
+        schema
+            .extensions
+            .insert("bsonType".to_owned(), bson_types.into());
+    }
+
+    for (segment, schema) in subschemas(schema) {
+        check_schema(
+            schema,
+            &format!("{path}/{segment}"),
+            rewrite,
+            incompatibilities,
         );
-        (self.json_schema_ptr)(&mut generator)
+    }
+}
+
+// Direct subschemas of a schema, with their JSON pointer segments
+#[cfg(feature = "schema")]
+fn subschemas(
+    schema: &mut schemars::schema::SchemaObject,
+) -> Vec<(String, &mut schemars::schema::Schema)> {
+    use schemars::schema::SingleOrVec;
+
+    let mut subschemas = Vec::new();
+
+    if let Some(validation) = &mut schema.subschemas {
+        for (keyword, schemas) in [
+            ("allOf", &mut validation.all_of),
+            ("anyOf", &mut validation.any_of),
+            ("oneOf", &mut validation.one_of),
+        ] {
+            for (i, schema) in schemas.iter_mut().flatten().enumerate() {
+                subschemas.push((format!("{keyword}/{i}"), schema));
+            }
+        }
+        for (keyword, schema) in [
+            ("not", &mut validation.not),
+            ("if", &mut validation.if_schema),
+            ("then", &mut validation.then_schema),
+            ("else", &mut validation.else_schema),
+        ] {
+            if let Some(schema) = schema {
+                subschemas.push((keyword.to_owned(), &mut **schema));
+            }
+        }
+    }
+
+    if let Some(validation) = &mut schema.array {
+        match &mut validation.items {
+            Some(SingleOrVec::Single(schema)) => {
+                subschemas.push(("items".to_owned(), &mut **schema));
+            }
+            Some(SingleOrVec::Vec(schemas)) => {
+                for (i, schema) in schemas.iter_mut().enumerate() {
+                    subschemas.push((format!("items/{i}"), schema));
+                }
+            }
+            None => {}
+        }
+        for (keyword, schema) in [
+            ("additionalItems", &mut validation.additional_items),
+            ("contains", &mut validation.contains),
+        ] {
+            if let Some(schema) = schema {
+                subschemas.push((keyword.to_owned(), &mut **schema));
+            }
+        }
+    }
+
+    if let Some(validation) = &mut schema.object {
+        for (name, schema) in &mut validation.properties {
+            subschemas.push((format!("properties/{}", escape_pointer(name)), schema));
+        }
+        for (pattern, schema) in &mut validation.pattern_properties {
+            subschemas.push((
+                format!("patternProperties/{}", escape_pointer(pattern)),
+                schema,
+            ));
+        }
+        for (keyword, schema) in [
+            (
+                "additionalProperties",
+                &mut validation.additional_properties,
+            ),
+            ("propertyNames", &mut validation.property_names),
+        ] {
+            if let Some(schema) = schema {
+                subschemas.push((keyword.to_owned(), &mut **schema));
+            }
+        }
+    }
+
+    subschemas
+}
+
+#[cfg(feature = "schema")]
+fn escape_pointer(segment: &str) -> String {
+    segment.replace('~', "~0").replace('/', "~1")
+}
+
+// Picks `WithJsonSchema` for entities implementing `JsonSchema`, and falls back to
+// `WithoutJsonSchema` otherwise, as method resolution only autorefs `&JsonSchemaProbe<T>`
+// when `JsonSchemaProbe<T>` doesn't implement `WithJsonSchema`
+#[cfg(feature = "schema")]
+#[doc(hidden)]
+pub struct JsonSchemaProbe<T>(pub std::marker::PhantomData<T>);
+
+#[cfg(feature = "schema")]
+#[doc(hidden)]
+pub trait WithJsonSchema {
+    fn json_schema(
+        &self,
+        generator: &mut schemars::r#gen::SchemaGenerator,
+    ) -> Option<schemars::schema::Schema>;
+}
+
+#[cfg(feature = "schema")]
+impl<T: schemars::JsonSchema> WithJsonSchema for JsonSchemaProbe<T> {
+    fn json_schema(
+        &self,
+        generator: &mut schemars::r#gen::SchemaGenerator,
+    ) -> Option<schemars::schema::Schema> {
+        Some(T::json_schema(generator))
+    }
+}
+
+#[cfg(feature = "schema")]
+#[doc(hidden)]
+pub trait WithoutJsonSchema {
+    fn json_schema(
+        &self,
+        generator: &mut schemars::r#gen::SchemaGenerator,
+    ) -> Option<schemars::schema::Schema>;
+}
+
+#[cfg(feature = "schema")]
+impl<T> WithoutJsonSchema for &JsonSchemaProbe<T> {
+    fn json_schema(
+        &self,
+        _generator: &mut schemars::r#gen::SchemaGenerator,
+    ) -> Option<schemars::schema::Schema> {
+        None
     }
 }
 
@@ -91,6 +510,10 @@ pub fn entity_metadata() -> impl Iterator<Item = &'static EntityMetadata> {
 
 pub async fn enforce_indexes(mongo: Mongo<'_>) -> Result<()> {
     for metadata in entity_metadata() {
+        if metadata.indexes().is_empty() {
+            continue;
+        }
+
         mongo
             .db
             .collection::<Document>(metadata.collection_name())
@@ -100,3 +523,2637 @@ pub async fn enforce_indexes(mongo: Mongo<'_>) -> Result<()> {
 
     Ok(())
 }
+
+/// Installs the JSON schemas of registered entities as `$jsonSchema` validators, so that the
+/// database rejects documents the entities couldn't have produced. Missing collections are
+/// created with the validator, existing ones are updated with `collMod`. When several entities
+/// share a collection, a document has to match any of their schemas. Entities that don't
+/// implement [`JsonSchema`](schemars::JsonSchema) are skipped. With `rewrite`, fixable
+/// keywords are rewritten as in [`EntityMetadata::try_json_schema`]. Fails with a custom
+/// [`SchemaError`] before any change if a schema is unsupported by `MongoDB`. The commands run
+/// in the session of `mongo`, if it has one.
+#[cfg(feature = "schema")]
+pub async fn enforce_validators(
+    mongo: Mongo<'_>,
+    level: ValidationLevel,
+    action: ValidationAction,
+    rewrite: bool,
+) -> Result<()> {
+    let mut schemas = BTreeMap::<&'static str, Vec<Document>>::new();
+    for metadata in entity_metadata() {
+        if let Some(schema) = metadata.try_json_schema(rewrite).map_err(Error::custom)? {
+            schemas
+                .entry(metadata.collection_name())
+                .or_default()
+                .push(mongodb::bson::to_document(&schema)?);
+        }
+    }
+
+    let Mongo { db, mut session } = mongo;
+
+    let query = db.list_collection_names();
+    let existing = match session.as_deref_mut() {
+        Some(session) => query.session(session).await?,
+        None => query.await?,
+    };
+
+    for (collection, schemas) in schemas {
+        let command = if existing.iter().any(|name| name == collection) {
+            "collMod"
+        } else {
+            "create"
+        };
+        let command = validator_command(command, collection, schemas, &level, &action)?;
+
+        crate::with_session!(db.run_command(command), session.as_deref_mut()).await?;
+    }
+
+    Ok(())
+}
+
+/// The `create` or `collMod` command installing the schemas of the entities of a collection as
+/// its validator.
+#[cfg(feature = "schema")]
+fn validator_command(
+    command: &str,
+    collection: &str,
+    mut schemas: Vec<Document>,
+    level: &ValidationLevel,
+    action: &ValidationAction,
+) -> Result<Document> {
+    let schema = if schemas.len() == 1 {
+        schemas.remove(0)
+    } else {
+        doc! { "anyOf": schemas }
+    };
+
+    Ok(doc! {
+        command: collection,
+        "validator": { "$jsonSchema": schema },
+        "validationLevel": mongodb::bson::to_bson(level)?,
+        "validationAction": mongodb::bson::to_bson(action)?,
+    })
+}
+
+/// A change to the indexes of a collection, planned by [`plan_indexes`].
+#[derive(Debug, Clone)]
+pub enum IndexChange {
+    /// The index is declared by an entity, but doesn't exist in the collection.
+    Create {
+        collection: &'static str,
+        model: IndexModel,
+    },
+    /// The index exists in the collection, but isn't declared by any entity.
+    Drop {
+        collection: &'static str,
+        name: String,
+    },
+    /// The index exists in the collection, but its keys or options differ from the declared ones.
+    Rebuild {
+        collection: &'static str,
+        model: IndexModel,
+    },
+}
+
+impl IndexChange {
+    pub fn collection(&self) -> &'static str {
+        match self {
+            Self::Create { collection, .. }
+            | Self::Drop { collection, .. }
+            | Self::Rebuild { collection, .. } => collection,
+        }
+    }
+
+    /// The name of the affected index.
+    pub fn name(&self) -> String {
+        match self {
+            Self::Create { model, .. } | Self::Rebuild { model, .. } => index_name(model),
+            Self::Drop { name, .. } => name.clone(),
+        }
+    }
+}
+
+impl Display for IndexChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Create { collection, model } => {
+                write!(
+                    f,
+                    "{collection}: create {} {}",
+                    index_name(model),
+                    model.keys
+                )
+            }
+            Self::Drop { collection, name } => write!(f, "{collection}: drop {name}"),
+            Self::Rebuild { collection, model } => {
+                write!(
+                    f,
+                    "{collection}: rebuild {} {}",
+                    index_name(model),
+                    model.keys
+                )
+            }
+        }
+    }
+}
+
+/// Changes required to bring the indexes of registered collections in line with
+/// the ones declared by entities. Printing the plan gives a dry run, [`IndexPlan::apply`]
+/// executes it.
+#[derive(Debug, Clone, Default)]
+pub struct IndexPlan {
+    changes: Vec<IndexChange>,
+}
+
+impl IndexPlan {
+    pub fn changes(&self) -> &[IndexChange] {
+        &self.changes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Executes the plan. New indexes are created before stale ones are dropped, so that a
+    /// renamed index keeps serving queries under its old name until the new one is built.
+    /// Changed indexes are dropped and recreated after that, since an index can't be
+    /// modified in place. Each one is first built under a temporary name, so that a build
+    /// that fails (e.g. a new `unique` option with duplicate keys) leaves the old index in
+    /// place. If recreating it still fails, the error names the dropped index.
+    ///
+    /// `MongoDB` doesn't allow two indexes with the same keys and options, so an index that is
+    /// only renamed is created once the old one is dropped.
+    pub async fn apply(&self, mongo: Mongo<'_>) -> Result<()> {
+        let collection = |name: &str| mongo.db.collection::<Document>(name);
+
+        let mut conflicting = Vec::new();
+        for change in &self.changes {
+            if let IndexChange::Create {
+                collection: name,
+                model,
+            } = change
+            {
+                match collection(name).create_index(model.clone()).await {
+                    Ok(_) => {}
+                    Err(error) if is_index_conflict(&error) => conflicting.push((name, model)),
+                    Err(error) => return Err(error),
+                }
+            }
+        }
+
+        for change in &self.changes {
+            if let IndexChange::Drop {
+                collection: name, ..
+            } = change
+            {
+                collection(name).drop_index(change.name()).await?;
+            }
+        }
+
+        for change in &self.changes {
+            if let IndexChange::Rebuild {
+                collection: name,
+                model,
+            } = change
+            {
+                rebuild_index(&collection(name), model).await?;
+            }
+        }
+
+        for (name, model) in conflicting {
+            collection(name).create_index(model.clone()).await?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for IndexPlan {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.changes.is_empty() {
+            return write!(f, "no changes");
+        }
+
+        for (i, change) in self.changes.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{change}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Lists the existing indexes of every registered collection and diffs them against the
+/// declared ones. Indexes are matched by name; the `_id` index is never touched.
+pub async fn plan_indexes(mongo: Mongo<'_>) -> Result<IndexPlan> {
+    let mut declared = BTreeMap::<&'static str, Vec<&'static IndexModel>>::new();
+    for metadata in entity_metadata() {
+        declared
+            .entry(metadata.collection_name())
+            .or_default()
+            .extend(metadata.indexes());
+    }
+
+    let mut changes = Vec::new();
+    for (collection, models) in declared {
+        let mut existing = match mongo
+            .db
+            .collection::<Document>(collection)
+            .list_indexes()
+            .await
+        {
+            Ok(cursor) => cursor.try_collect::<Vec<_>>().await?,
+            Err(error) if is_namespace_not_found(&error) => Vec::new(),
+            Err(error) => return Err(error),
+        };
+        existing.retain(|model| index_name(model) != "_id_");
+
+        for model in models {
+            let name = index_name(model);
+            match existing
+                .iter()
+                .position(|existing| index_name(existing) == name)
+            {
+                Some(i) => {
+                    if !index_matches(&existing.swap_remove(i), model) {
+                        changes.push(IndexChange::Rebuild {
+                            collection,
+                            model: model.clone(),
+                        });
+                    }
+                }
+                None => changes.push(IndexChange::Create {
+                    collection,
+                    model: model.clone(),
+                }),
+            }
+        }
+
+        changes.extend(existing.iter().map(|model| IndexChange::Drop {
+            collection,
+            name: index_name(model),
+        }));
+    }
+
+    Ok(IndexPlan { changes })
+}
+
+async fn rebuild_index(collection: &Collection<Document>, model: &IndexModel) -> Result<()> {
+    let name = index_name(model);
+
+    let temporary = temporary_index(model);
+    match collection.create_index(temporary.clone()).await {
+        Ok(_) => collection.drop_index(index_name(&temporary)).await?,
+        // The server doesn't allow it next to the existing index, e.g. with the same keys,
+        // so it's only built once that one is dropped
+        Err(error) if is_index_conflict(&error) => {}
+        Err(error) => return Err(error),
+    }
+
+    collection.drop_index(&name).await?;
+    collection
+        .create_index(model.clone())
+        .await
+        .map_err(|error| {
+            Error::custom(format!(
+                "index `{name}` of `{}` was dropped, but recreating it failed: {error}",
+                collection.name()
+            ))
+        })?;
+
+    Ok(())
+}
+
+/// The model renamed to a name that doesn't clash with the index it replaces.
+fn temporary_index(model: &IndexModel) -> IndexModel {
+    let mut options = model.options.clone().unwrap_or_default();
+    options.name = Some(format!("{}_rebuild", index_name(model)));
+
+    IndexModel::builder()
+        .keys(model.keys.clone())
+        .options(options)
+        .build()
+}
+
+fn is_namespace_not_found(error: &Error) -> bool {
+    matches!(&*error.kind, ErrorKind::Command(error) if error.code == 26)
+}
+
+// `IndexOptionsConflict` and `IndexKeySpecsConflict`
+fn is_index_conflict(error: &Error) -> bool {
+    matches!(&*error.kind, ErrorKind::Command(error) if error.code == 85 || error.code == 86)
+}
+
+// Same as the name the driver generates for indexes created without one
+fn index_name(model: &IndexModel) -> String {
+    if let Some(name) = model
+        .options
+        .as_ref()
+        .and_then(|options| options.name.clone())
+    {
+        return name;
+    }
+
+    model
+        .keys
+        .iter()
+        .map(|(key, value)| {
+            // Unquoted, e.g. `a_text`
+            let value = value
+                .as_str()
+                .map_or_else(|| value.to_string(), str::to_owned);
+            format!("{key}_{value}")
+        })
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+fn index_matches(existing: &IndexModel, declared: &IndexModel) -> bool {
+    // Key order matters, and the server may report `1` as a double or a long
+    let keys_match = existing.keys.len() == declared.keys.len()
+        && existing.keys.iter().zip(&declared.keys).all(
+            |((existing_key, existing_value), (declared_key, declared_value))| {
+                existing_key == declared_key && values_equal(existing_value, declared_value)
+            },
+        );
+
+    keys_match && options_match(existing.options.as_ref(), declared.options.as_ref())
+}
+
+/// Options that change which documents an index accepts or keeps. They are compared even when
+/// only the existing index has them, while other options (e.g. the index version) may be
+/// filled in by the server, so they are only compared when declared.
+const INDEX_BEHAVIOR_OPTIONS: &[&str] = &[
+    "unique",
+    "sparse",
+    "expireAfterSeconds",
+    "partialFilterExpression",
+    "collation",
+    "hidden",
+];
+
+fn options_match(existing: Option<&IndexOptions>, declared: Option<&IndexOptions>) -> bool {
+    let to_document = |options: Option<&IndexOptions>| {
+        options
+            .and_then(|options| bson::to_document(options).ok())
+            .unwrap_or_default()
+    };
+
+    let existing = to_document(existing);
+    let mut declared = to_document(declared);
+    declared.remove("name");
+
+    let declared_match = declared.iter().all(|(key, declared)| {
+        match (key.as_str(), existing.get(key), declared) {
+            // The server fills in the defaults of the locale
+            ("collation", Some(Bson::Document(existing)), Bson::Document(declared)) => {
+                declared.iter().all(|(key, declared)| {
+                    existing
+                        .get(key)
+                        .is_some_and(|existing| values_equal(existing, declared))
+                })
+            }
+            (_, Some(existing), declared) => values_equal(existing, declared),
+            (_, None, declared) => *declared == Bson::Boolean(false),
+        }
+    });
+
+    let existing_match = INDEX_BEHAVIOR_OPTIONS.iter().all(|key| {
+        declared.contains_key(key)
+            || existing
+                .get(key)
+                .is_none_or(|existing| *existing == Bson::Boolean(false))
+    });
+
+    declared_match && existing_match
+}
+
+// Compares numbers regardless of their BSON type, including inside documents and arrays
+fn values_equal(a: &Bson, b: &Bson) -> bool {
+    match (a, b) {
+        (Bson::Document(a), Bson::Document(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .zip(b)
+                    .all(|((a_key, a), (b_key, b))| a_key == b_key && values_equal(a, b))
+        }
+        (Bson::Array(a), Bson::Array(b)) => {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| values_equal(a, b))
+        }
+        (a, b) => match (as_number(a), as_number(b)) {
+            (Some(a), Some(b)) => a == b,
+            _ => a == b,
+        },
+    }
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn as_number(value: &Bson) -> Option<i64> {
+    match value {
+        Bson::Int32(value) => Some(i64::from(*value)),
+        Bson::Int64(value) => Some(*value),
+        Bson::Double(value) if value.fract() == 0.0 => Some(*value as i64),
+        _ => None,
+    }
+}
+
+impl EntityMetadata {
+    /// Reports the documents of the collection that don't match the entity. The server checks
+    /// them against the schema, taken as in [`EntityMetadata::try_json_schema`] with
+    /// `rewrite`, so use the value passed to [`enforce_validators`]. The documents matching the
+    /// schema, or all documents of entities without one, are deserialized into the entity.
+    /// Without the `schema` feature, `rewrite` is ignored. The queries run in the session of
+    /// `mongo`, if it has one.
+    ///
+    /// Documents that don't match the schema are reported as a single failure at `#`, as the
+    /// server doesn't tell which part of the schema they fail. Deserialization failures are
+    /// grouped by their message, with values stripped from it. Up to `samples` `_id`s are kept
+    /// for each of the first 100 kinds of failure, and further kinds are only counted.
+    pub async fn audit(
+        &self,
+        mongo: Mongo<'_>,
+        samples: usize,
+        rewrite: bool,
+    ) -> Result<AuditReport> {
+        #[cfg(feature = "schema")]
+        let schema = self
+            .try_json_schema(rewrite)
+            .map_err(Error::custom)?
+            .map(|schema| mongodb::bson::to_document(&schema))
+            .transpose()?;
+        #[cfg(not(feature = "schema"))]
+        let _ = rewrite;
+
+        let mut report = AuditReport {
+            entity_name: self.entity_name,
+            collection_name: self.collection_name,
+            checked: 0,
+            failed: 0,
+            failures: Vec::new(),
+            omitted: 0,
+        };
+        let mut kinds = HashMap::new();
+
+        #[cfg_attr(not(feature = "schema"), allow(unused_mut))]
+        let Mongo { db, mut session } = mongo;
+        let collection = db.collection::<RawDocumentBuf>(self.collection_name);
+
+        #[cfg(feature = "schema")]
+        let filter = match schema {
+            Some(schema) => {
+                let query = collection
+                    .find(doc! { "$nor": [{ "$jsonSchema": schema.clone() }] })
+                    .projection(doc! { "_id": 1 });
+
+                let mut stream = crate::cursor_stream(query, session.as_deref_mut()).await?;
+                while let Some(raw) = stream.try_next().await? {
+                    report.record(
+                        &mut kinds,
+                        Some("#".to_owned()),
+                        "document doesn't match the schema".to_owned(),
+                        &raw,
+                        samples,
+                    );
+                }
+
+                doc! { "$jsonSchema": schema }
+            }
+            None => doc! {},
+        };
+        #[cfg(not(feature = "schema"))]
+        let filter = doc! {};
+
+        let mut stream = crate::cursor_stream(collection.find(filter), session).await?;
+        while let Some(raw) = stream.try_next().await? {
+            match (self.deserialize_ptr)(raw.as_bytes()) {
+                Ok(()) => report.checked += 1,
+                Err(error) => report.record(&mut kinds, None, error.to_string(), &raw, samples),
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+const MAX_FAILURE_KINDS: usize = 100;
+
+/// Strips the values from a failure message, e.g. `invalid type: string "abc", expected i32`
+/// becomes `invalid type: string, expected i32`.
+fn failure_kind(message: &str) -> String {
+    let (head, expected) = match message.rfind(", expected ") {
+        Some(i) => message.split_at(i),
+        None => (message, ""),
+    };
+
+    for prefix in ["invalid type: ", "invalid value: "] {
+        if let Some(unexpected) = head.strip_prefix(prefix) {
+            // `serde::de::Unexpected` displays values after the type, e.g. `integer `5``
+            let unexpected = unexpected
+                .split(' ')
+                .take_while(|word| !word.starts_with(['`', '"']));
+            return format!(
+                "{prefix}{}{expected}",
+                unexpected.collect::<Vec<_>>().join(" ")
+            );
+        }
+    }
+
+    for prefix in [
+        "invalid length ",
+        "unknown variant ",
+        "unexpected property ",
+    ] {
+        if head.starts_with(prefix) {
+            return format!("{}{expected}", prefix.trim_end());
+        }
+    }
+
+    message.to_owned()
+}
+
+/// Documents of a collection that don't match their entity, returned by
+/// [`EntityMetadata::audit`].
+///
+/// The documents failing the schema and the ones matching it are read by two queries, so the
+/// numbers aren't a snapshot of the collection: a document written in between may be counted
+/// twice or missed. Run the audit in a snapshot session (started with
+/// [`snapshot(true)`](mongodb::action::StartSession::snapshot)) for consistent numbers.
+#[derive(Debug, Clone)]
+pub struct AuditReport {
+    entity_name: &'static str,
+    collection_name: &'static str,
+    checked: u64,
+    failed: u64,
+    failures: Vec<AuditFailure>,
+    omitted: u64,
+}
+
+impl AuditReport {
+    pub fn entity_name(&self) -> &'static str {
+        self.entity_name
+    }
+
+    pub fn collection_name(&self) -> &'static str {
+        self.collection_name
+    }
+
+    /// The number of documents in the collection.
+    pub fn checked(&self) -> u64 {
+        self.checked
+    }
+
+    /// The number of documents with at least one failure.
+    pub fn failed(&self) -> u64 {
+        self.failed
+    }
+
+    pub fn failures(&self) -> &[AuditFailure] {
+        &self.failures
+    }
+
+    /// The number of failures of further kinds, which are not listed in
+    /// [`AuditReport::failures`].
+    pub fn omitted(&self) -> u64 {
+        self.omitted
+    }
+}
+
+impl AuditReport {
+    /// Counts a failed document under the kind of its failure.
+    fn record(
+        &mut self,
+        kinds: &mut HashMap<(Option<String>, String), usize>,
+        path: Option<String>,
+        message: String,
+        raw: &RawDocumentBuf,
+        samples: usize,
+    ) {
+        self.checked += 1;
+        self.failed += 1;
+
+        let kind = failure_kind(&message);
+        let i = if let Some(&i) = kinds.get(&(path.clone(), kind.clone())) {
+            i
+        } else if self.failures.len() < MAX_FAILURE_KINDS {
+            kinds.insert((path.clone(), kind.clone()), self.failures.len());
+            self.failures.push(AuditFailure {
+                path,
+                kind,
+                message,
+                count: 0,
+                sample_ids: Vec::new(),
+            });
+            self.failures.len() - 1
+        } else {
+            self.omitted += 1;
+            return;
+        };
+
+        let failure = &mut self.failures[i];
+        failure.count += 1;
+
+        let id = raw
+            .get("_id")
+            .ok()
+            .flatten()
+            .and_then(|id| Bson::try_from(id.to_raw_bson()).ok());
+        if let Some(id) = id
+            && failure.sample_ids.len() < samples
+        {
+            failure.sample_ids.push(id);
+        }
+    }
+}
+
+impl Display for AuditReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}): {} of {} documents failed",
+            self.entity_name, self.collection_name, self.failed, self.checked
+        )?;
+        for failure in &self.failures {
+            write!(f, "\n  {failure}")?;
+        }
+        if self.omitted > 0 {
+            write!(f, "\n  {} failures of other kinds", self.omitted)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A kind of failure found by [`EntityMetadata::audit`], with the number of documents it
+/// occurred in.
+#[derive(Debug, Clone)]
+pub struct AuditFailure {
+    path: Option<String>,
+    kind: String,
+    message: String,
+    count: u64,
+    sample_ids: Vec<Bson>,
+}
+
+impl AuditFailure {
+    /// JSON pointer to the subschema the documents don't match, e.g. `#/properties/age`, or
+    /// `None` if they failed to deserialize.
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+
+    /// The message without values, shared by all the documents.
+    pub fn kind(&self) -> &str {
+        &self.kind
+    }
+
+    /// The message of the first document, which may include its values.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// `_id`s of some of the documents.
+    pub fn sample_ids(&self) -> &[Bson] {
+        &self.sample_ids
+    }
+}
+
+impl Display for AuditFailure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} ({} documents",
+            self.path.as_deref().unwrap_or("deserialization"),
+            self.kind,
+            self.count
+        )?;
+        for (i, id) in self.sample_ids.iter().enumerate() {
+            write!(f, "{}{id}", if i == 0 { ", e.g. " } else { ", " })?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// A machine-readable snapshot of every registered entity, identified by the path of its type:
+/// its collection, fields with their BSON types, indexes, and JSON schema. Commit it with [`SchemaSnapshot::to_json`], and check
+/// the registry against it with [`SchemaSnapshot::drift`].
+#[cfg(feature = "schema")]
+#[derive(Debug, Clone)]
+pub struct SchemaSnapshot {
+    entities: BTreeMap<String, EntitySnapshot>,
+}
+
+#[cfg(feature = "schema")]
+#[derive(Debug, Clone)]
+struct EntitySnapshot {
+    collection_name: String,
+    fields: BTreeMap<String, FieldSnapshot>,
+    indexes: Vec<IndexModel>,
+    schema: Option<Document>,
+}
+
+#[cfg(feature = "schema")]
+#[derive(Debug, Clone, PartialEq)]
+struct FieldSnapshot {
+    // Empty if the field accepts any type
+    types: BTreeSet<String>,
+    required: bool,
+    allowed: Option<Vec<Bson>>,
+}
+
+#[cfg(feature = "schema")]
+impl SchemaSnapshot {
+    /// Takes a snapshot of the registered entities. Schemas are taken as in
+    /// [`EntityMetadata::try_json_schema`] with `rewrite`, so use the value passed to
+    /// [`enforce_validators`], and a custom [`SchemaError`] is returned for unsupported ones.
+    pub fn current(rewrite: bool) -> Result<Self> {
+        let mut entities = BTreeMap::new();
+        for metadata in entity_metadata() {
+            let schema = metadata.try_json_schema(rewrite).map_err(Error::custom)?;
+
+            let mut fields = BTreeMap::new();
+            if let Some(schema) = &schema {
+                collect_fields(schema, "", &mut fields);
+            }
+
+            let previous = entities.insert(
+                metadata.entity_name().to_owned(),
+                EntitySnapshot {
+                    collection_name: metadata.collection_name().to_owned(),
+                    fields,
+                    indexes: metadata.indexes().to_vec(),
+                    schema: schema
+                        .as_ref()
+                        .map(mongodb::bson::to_document)
+                        .transpose()?,
+                },
+            );
+            if previous.is_some() {
+                return Err(Error::custom(format!(
+                    "entity `{}` is registered twice",
+                    metadata.entity_name()
+                )));
+            }
+        }
+
+        Ok(Self { entities })
+    }
+
+    /// Serializes the snapshot to pretty-printed JSON.
+    pub fn to_json(&self) -> String {
+        let entities = self
+            .entities
+            .iter()
+            .map(|(entity_name, entity)| {
+                let fields = entity
+                    .fields
+                    .iter()
+                    .map(|(path, field)| {
+                        let mut document = doc! {
+                            "types": field.types.iter().collect::<Vec<_>>(),
+                            "required": field.required,
+                        };
+                        if let Some(allowed) = &field.allowed {
+                            document.insert("enum", allowed.clone());
+                        }
+                        (path.clone(), Bson::Document(document))
+                    })
+                    .collect::<Document>();
+
+                let indexes = entity
+                    .indexes
+                    .iter()
+                    .map(|model| {
+                        let mut document = doc! {
+                            "name": index_name(model),
+                            "keys": model.keys.clone(),
+                        };
+                        if let Some(mut options) = model
+                            .options
+                            .as_ref()
+                            .and_then(|options| bson::to_document(options).ok())
+                        {
+                            options.remove("name");
+                            document.extend(options);
+                        }
+                        Bson::Document(document)
+                    })
+                    .collect::<Vec<_>>();
+
+                Bson::Document(doc! {
+                    "entity": entity_name,
+                    "collection": &entity.collection_name,
+                    "fields": fields,
+                    "indexes": indexes,
+                    "schema": entity.schema.clone(),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // Relaxed extended JSON doesn't keep the BSON types of numbers, so snapshots compare
+        // numbers by value
+        let json = Bson::Document(doc! { "entities": entities }).into_relaxed_extjson();
+        format!("{json:#}")
+    }
+
+    /// Parses a snapshot produced by [`SchemaSnapshot::to_json`].
+    pub fn from_json(json: &str) -> Result<Self> {
+        let invalid = |reason: &str| Error::custom(format!("invalid schema snapshot: {reason}"));
+
+        let json = serde_json::from_str::<serde_json::Value>(json)
+            .map_err(|error| invalid(&error.to_string()))?;
+        let Ok(Bson::Document(snapshot)) = Bson::try_from(json) else {
+            return Err(invalid("expected an extended JSON object"));
+        };
+
+        let mut entities = BTreeMap::new();
+        for entity in snapshot
+            .get_array("entities")
+            .map_err(|_| invalid("missing `entities`"))?
+        {
+            let entity = entity
+                .as_document()
+                .ok_or_else(|| invalid("expected an entity object"))?;
+            let entity_name = entity
+                .get_str("entity")
+                .map_err(|_| invalid("missing `entity`"))?;
+
+            let mut fields = BTreeMap::new();
+            for (path, field) in entity
+                .get_document("fields")
+                .map_err(|_| invalid("missing `fields`"))?
+            {
+                let field = field
+                    .as_document()
+                    .ok_or_else(|| invalid("expected a field object"))?;
+                fields.insert(
+                    path.clone(),
+                    FieldSnapshot {
+                        types: field
+                            .get_array("types")
+                            .map_err(|_| invalid("missing field `types`"))?
+                            .iter()
+                            .filter_map(|typ| typ.as_str().map(str::to_owned))
+                            .collect(),
+                        required: field.get_bool("required").unwrap_or(false),
+                        allowed: field.get_array("enum").ok().cloned(),
+                    },
+                );
+            }
+
+            let mut indexes = Vec::new();
+            for index in entity
+                .get_array("indexes")
+                .map_err(|_| invalid("missing `indexes`"))?
+            {
+                let mut options = index
+                    .as_document()
+                    .ok_or_else(|| invalid("expected an index object"))?
+                    .clone();
+                let keys = options
+                    .remove("keys")
+                    .and_then(|keys| keys.as_document().cloned())
+                    .ok_or_else(|| invalid("missing index `keys`"))?;
+                if options.get_str("name").is_err() {
+                    return Err(invalid("missing index `name`"));
+                }
+                let options = bson::from_document::<IndexOptions>(options)
+                    .map_err(|error| invalid(&format!("invalid index options: {error}")))?;
+                indexes.push(IndexModel::builder().keys(keys).options(options).build());
+            }
+
+            entities.insert(
+                entity_name.to_owned(),
+                EntitySnapshot {
+                    collection_name: entity
+                        .get_str("collection")
+                        .map_err(|_| invalid("missing `collection`"))?
+                        .to_owned(),
+                    fields,
+                    indexes,
+                    schema: entity.get_document("schema").ok().cloned(),
+                },
+            );
+        }
+
+        Ok(Self { entities })
+    }
+
+    /// Compares the snapshot with the registered entities, taken as in
+    /// [`SchemaSnapshot::current`].
+    pub fn drift(&self, rewrite: bool) -> Result<SchemaDrift> {
+        Ok(self.diff(&Self::current(rewrite)?))
+    }
+
+    /// Lists the changes from this snapshot to a newer one.
+    pub fn diff(&self, newer: &Self) -> SchemaDrift {
+        let mut drift = SchemaDrift {
+            changes: Vec::new(),
+        };
+
+        for (entity_name, old) in &self.entities {
+            match newer.entities.get(entity_name) {
+                Some(new) => diff_entity(entity_name, old, new, &mut drift),
+                None => drift.push(entity_name, SchemaChangeKind::Breaking, "entity removed"),
+            }
+        }
+        for entity_name in newer.entities.keys() {
+            if !self.entities.contains_key(entity_name) {
+                drift.push(entity_name, SchemaChangeKind::Additive, "entity added");
+            }
+        }
+
+        drift
+    }
+}
+
+/// Changes between two [`SchemaSnapshot`]s.
+#[cfg(feature = "schema")]
+#[derive(Debug, Clone, Default)]
+pub struct SchemaDrift {
+    changes: Vec<SchemaChange>,
+}
+
+#[cfg(feature = "schema")]
+impl SchemaDrift {
+    pub fn changes(&self) -> &[SchemaChange] {
+        &self.changes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// The most severe kind of the changes, or `None` if there are none. `Index` means that
+    /// only indexes have changed.
+    pub fn kind(&self) -> Option<SchemaChangeKind> {
+        self.changes.iter().map(|change| change.kind).max()
+    }
+
+    fn push(&mut self, entity_name: &str, kind: SchemaChangeKind, description: impl Into<String>) {
+        self.changes.push(SchemaChange {
+            entity_name: entity_name.to_owned(),
+            kind,
+            description: description.into(),
+        });
+    }
+}
+
+#[cfg(feature = "schema")]
+impl Display for SchemaDrift {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.changes.is_empty() {
+            return write!(f, "no changes");
+        }
+
+        for (i, change) in self.changes.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{change}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A change of an entity between two [`SchemaSnapshot`]s.
+#[cfg(feature = "schema")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaChange {
+    entity_name: String,
+    kind: SchemaChangeKind,
+    description: String,
+}
+
+#[cfg(feature = "schema")]
+impl SchemaChange {
+    pub fn entity_name(&self) -> &str {
+        &self.entity_name
+    }
+
+    pub fn kind(&self) -> SchemaChangeKind {
+        self.kind
+    }
+
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+}
+
+#[cfg(feature = "schema")]
+impl Display for SchemaChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}): {}",
+            self.entity_name, self.kind, self.description
+        )
+    }
+}
+
+/// How a change affects stored documents, from the least to the most severe.
+#[cfg(feature = "schema")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SchemaChangeKind {
+    /// An index was added, removed, or changed.
+    Index,
+    /// Existing documents remain valid, e.g. an optional field was added or a type was widened.
+    Additive,
+    /// Existing documents may become invalid, e.g. a field was removed, retyped or made required.
+    Breaking,
+}
+
+#[cfg(feature = "schema")]
+impl Display for SchemaChangeKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Index => write!(f, "index"),
+            Self::Additive => write!(f, "additive"),
+            Self::Breaking => write!(f, "breaking"),
+        }
+    }
+}
+
+#[cfg(feature = "schema")]
+fn diff_entity(
+    entity_name: &str,
+    old: &EntitySnapshot,
+    new: &EntitySnapshot,
+    drift: &mut SchemaDrift,
+) {
+    use SchemaChangeKind::Breaking;
+
+    if old.collection_name != new.collection_name {
+        drift.push(
+            entity_name,
+            Breaking,
+            format!(
+                "collection renamed from `{}` to `{}`",
+                old.collection_name, new.collection_name
+            ),
+        );
+    }
+
+    let reported = drift.changes.len();
+    diff_fields(entity_name, old, new, drift);
+
+    // The fields only cover types, presence and allowed values, so other changes to the schema
+    // (bounds, patterns, nested constraints...) are reported as a whole, unless a field change
+    // already explains them. Snapshots written without a schema aren't compared.
+    if let (Some(old_schema), Some(new_schema)) = (&old.schema, &new.schema) {
+        let changed = !values_equal(
+            &Bson::Document(old_schema.clone()),
+            &Bson::Document(new_schema.clone()),
+        );
+        if changed && drift.changes.len() == reported {
+            drift.push(entity_name, Breaking, "schema changed");
+        }
+    }
+
+    diff_indexes(entity_name, &old.indexes, &new.indexes, drift);
+}
+
+#[cfg(feature = "schema")]
+fn diff_fields(
+    entity_name: &str,
+    old: &EntitySnapshot,
+    new: &EntitySnapshot,
+    drift: &mut SchemaDrift,
+) {
+    use SchemaChangeKind::{Additive, Breaking};
+
+    for (path, old) in &old.fields {
+        let Some(new) = new.fields.get(path) else {
+            drift.push(entity_name, Breaking, format!("field `{path}` removed"));
+            continue;
+        };
+
+        if old.types != new.types {
+            // An empty set accepts any type
+            let widened = new.types.is_empty()
+                || !old.types.is_empty()
+                    && old.types.iter().all(|typ| accepts_type(&new.types, typ));
+            drift.push(
+                entity_name,
+                if widened { Additive } else { Breaking },
+                format!(
+                    "field `{path}` retyped from {} to {}",
+                    format_types(&old.types),
+                    format_types(&new.types)
+                ),
+            );
+        }
+
+        if old.required != new.required {
+            drift.push(
+                entity_name,
+                if new.required { Breaking } else { Additive },
+                format!(
+                    "field `{path}` made {}",
+                    if new.required { "required" } else { "optional" }
+                ),
+            );
+        }
+
+        match (&old.allowed, &new.allowed) {
+            (Some(old), Some(new)) => {
+                let missing_from = |values: &[Bson], others: &[Bson]| {
+                    values
+                        .iter()
+                        .any(|value| !others.iter().any(|other| values_equal(value, other)))
+                };
+                let narrowed = missing_from(old, new);
+                if narrowed || missing_from(new, old) {
+                    drift.push(
+                        entity_name,
+                        if narrowed { Breaking } else { Additive },
+                        format!("allowed values of field `{path}` changed"),
+                    );
+                }
+            }
+            (Some(_), None) => {
+                drift.push(
+                    entity_name,
+                    Additive,
+                    format!("field `{path}` no longer restricted to specific values"),
+                );
+            }
+            (None, Some(_)) => {
+                drift.push(
+                    entity_name,
+                    Breaking,
+                    format!("field `{path}` restricted to specific values"),
+                );
+            }
+            _ => {}
+        }
+    }
+
+    for (path, new) in &new.fields {
+        if !old.fields.contains_key(path) {
+            drift.push(
+                entity_name,
+                if new.required { Breaking } else { Additive },
+                format!(
+                    "{} field `{path}` added",
+                    if new.required { "required" } else { "optional" }
+                ),
+            );
+        }
+    }
+}
+
+#[cfg(feature = "schema")]
+fn diff_indexes(
+    entity_name: &str,
+    old: &[IndexModel],
+    new: &[IndexModel],
+    drift: &mut SchemaDrift,
+) {
+    use SchemaChangeKind::Index;
+
+    for old_model in old {
+        let name = index_name(old_model);
+        // Both ways, since `index_matches` ignores some options only set on the existing index
+        match new.iter().find(|model| index_name(model) == name) {
+            Some(new_model)
+                if !index_matches(old_model, new_model) || !index_matches(new_model, old_model) =>
+            {
+                drift.push(entity_name, Index, format!("index `{name}` changed"));
+            }
+            Some(_) => {}
+            None => drift.push(entity_name, Index, format!("index `{name}` removed")),
+        }
+    }
+    for new_model in new {
+        let name = index_name(new_model);
+        if !old.iter().any(|model| index_name(model) == name) {
+            drift.push(entity_name, Index, format!("index `{name}` added"));
+        }
+    }
+}
+
+#[cfg(feature = "schema")]
+const NUMBER_TYPES: &[&str] = &["double", "int", "long", "decimal"];
+
+// Whether a field of the BSON types accepts the values of another type
+#[cfg(feature = "schema")]
+fn accepts_type(types: &BTreeSet<String>, typ: &str) -> bool {
+    types.contains(typ)
+        || NUMBER_TYPES.contains(&typ) && types.contains("number")
+        || typ == "number" && NUMBER_TYPES.iter().all(|typ| types.contains(*typ))
+}
+
+#[cfg(feature = "schema")]
+fn format_types(types: &BTreeSet<String>) -> String {
+    if types.is_empty() {
+        return "any".to_owned();
+    }
+
+    types
+        .iter()
+        .map(|typ| format!("`{typ}`"))
+        .collect::<Vec<_>>()
+        .join(" | ")
+}
+
+// Flattens the properties of an object schema, and of its subschemas, into dotted paths,
+// with `[]` marking array items
+#[cfg(feature = "schema")]
+fn collect_fields(
+    schema: &schemars::schema::Schema,
+    path: &str,
+    fields: &mut BTreeMap<String, FieldSnapshot>,
+) {
+    use schemars::schema::{Schema, SingleOrVec};
+
+    let Schema::Object(schema) = schema else {
+        return;
+    };
+
+    if let Some(object) = &schema.object {
+        for (name, property) in &object.properties {
+            let path = if path.is_empty() {
+                name.clone()
+            } else {
+                format!("{path}.{name}")
+            };
+            insert_field(fields, &path, property, object.required.contains(name));
+            collect_fields(property, &path, fields);
+        }
+    }
+
+    if let Some(array) = &schema.array {
+        let path = format!("{path}[]");
+        let items = match &array.items {
+            Some(SingleOrVec::Single(items)) => std::slice::from_ref(&**items),
+            Some(SingleOrVec::Vec(items)) => items.as_slice(),
+            None => &[],
+        };
+        for items in items {
+            insert_field(fields, &path, items, false);
+            collect_fields(items, &path, fields);
+        }
+    }
+
+    if let Some(subschemas) = &schema.subschemas {
+        for schema in [&subschemas.all_of, &subschemas.any_of, &subschemas.one_of]
+            .into_iter()
+            .flatten()
+            .flatten()
+        {
+            collect_fields(schema, path, fields);
+        }
+    }
+}
+
+#[cfg(feature = "schema")]
+fn insert_field(
+    fields: &mut BTreeMap<String, FieldSnapshot>,
+    path: &str,
+    schema: &schemars::schema::Schema,
+    required: bool,
+) {
+    use schemars::schema::Schema;
+
+    let field = FieldSnapshot {
+        types: schema_types(schema),
+        required,
+        allowed: match schema {
+            Schema::Object(schema) => schema.enum_values.as_ref().map(|values| {
+                values
+                    .iter()
+                    .filter_map(|value| mongodb::bson::to_bson(value).ok())
+                    .collect()
+            }),
+            Schema::Bool(_) => None,
+        },
+    };
+
+    // Variants of enums, and tuple items, may declare the same field
+    match fields.get_mut(path) {
+        Some(existing) => {
+            existing.types.extend(field.types);
+            existing.required &= field.required;
+            if let (Some(existing), Some(allowed)) = (&mut existing.allowed, field.allowed) {
+                existing.extend(allowed);
+            }
+        }
+        None => {
+            fields.insert(path.to_owned(), field);
+        }
+    }
+}
+
+// BSON type aliases accepted by a schema, or none if it accepts any type
+#[cfg(feature = "schema")]
+fn schema_types(schema: &schemars::schema::Schema) -> BTreeSet<String> {
+    use schemars::schema::{Schema, SingleOrVec};
+
+    let Schema::Object(schema) = schema else {
+        return BTreeSet::new();
+    };
+
+    let mut types = BTreeSet::new();
+
+    match &schema.instance_type {
+        Some(SingleOrVec::Single(typ)) => types.extend(
+            bson_type_aliases(**typ)
+                .iter()
+                .map(|alias| (*alias).to_owned()),
+        ),
+        Some(SingleOrVec::Vec(instance_types)) => {
+            for typ in instance_types {
+                types.extend(
+                    bson_type_aliases(*typ)
+                        .iter()
+                        .map(|alias| (*alias).to_owned()),
+                );
+            }
+        }
+        None => {}
+    }
+
+    match schema
+        .extensions
+        .get("bsonType")
+        .map(mongodb::bson::to_bson)
+    {
+        Some(Ok(Bson::String(alias))) => {
+            types.insert(alias);
+        }
+        Some(Ok(Bson::Array(aliases))) => {
+            types.extend(
+                aliases
+                    .iter()
+                    .filter_map(|alias| alias.as_str().map(str::to_owned)),
+            );
+        }
+        _ => {}
+    }
+
+    if let Some(subschemas) = &schema.subschemas {
+        for schemas in [&subschemas.any_of, &subschemas.one_of]
+            .into_iter()
+            .flatten()
+        {
+            let branches = schemas.iter().map(schema_types).collect::<Vec<_>>();
+            // A branch accepting any type makes the whole field accept any type
+            if branches.iter().any(BTreeSet::is_empty) {
+                return BTreeSet::new();
+            }
+            types.extend(branches.into_iter().flatten());
+        }
+    }
+
+    types
+}
+
+#[cfg(feature = "schema")]
+fn bson_type_aliases(typ: schemars::schema::InstanceType) -> &'static [&'static str] {
+    use schemars::schema::InstanceType;
+
+    match typ {
+        InstanceType::Null => &["null"],
+        InstanceType::Boolean => &["bool"],
+        InstanceType::Object => &["object"],
+        InstanceType::Array => &["array"],
+        InstanceType::Number => &["number"],
+        InstanceType::String => &["string"],
+        InstanceType::Integer => &["int", "long"],
+    }
+}
+
+/// Registers a migration of an entity, run by [`Migrator`]. Versions are per entity, as
+/// identified by the path of its type, and migrations run in the order of their versions, then
+/// of their entity paths. A migration
+/// either updates the documents matching a filter of the entity, updates them with an
+/// aggregation pipeline, or runs a function:
+///
+/// ```no_run
+/// # pub use khan::*;
+/// # use khan::mongodb::{bson::{doc, oid::ObjectId}, error::Result};
+/// # use futures_util::{FutureExt, future::BoxFuture};
+/// # use serde::{Deserialize, Serialize};
+/// # #[derive(Serialize, Deserialize, Entity)]
+/// # pub struct User {
+/// #     #[serde(rename = "_id")]
+/// #     pub id: ObjectId,
+/// #     pub name: String,
+/// #     pub nickname: Option<String>,
+/// # }
+/// khan::migration!(User, 1, "add_nickname", update(user::filter! {}, user::update! {
+///     nickname: None
+/// }));
+///
+/// khan::migration!(User, 2, "split_name", pipeline(user::filter! {}, [
+///     doc! { "$set": { "first_name": { "$first": { "$split": ["$name", " "] } } } },
+/// ]));
+///
+/// fn reindex(mongo: Mongo<'_>) -> BoxFuture<'_, Result<()>> {
+///     async move { /* ... */ Ok(()) }.boxed()
+/// }
+///
+/// khan::migration!(User, 3, "reindex", run(reindex));
+/// # fn main() {}
+/// ```
+#[macro_export]
+macro_rules! migration {
+    ($entity:ty, $version:expr, $name:expr, update($filter:expr, $update:expr $(,)?)) => {
+        $crate::migration!($entity, $version, $name, run({
+            fn migrate(
+                mongo: $crate::Mongo<'_>,
+            ) -> $crate::meta::MigrationFuture<'_> {
+                ::std::boxed::Box::pin(async move {
+                    <$entity as $crate::Entity>::update(mongo, $filter, $update).await?;
+                    ::std::result::Result::Ok(())
+                })
+            }
+
+            migrate
+        }));
+    };
+    ($entity:ty, $version:expr, $name:expr, pipeline($filter:expr, [$($stage:expr),* $(,)?] $(,)?)) => {
+        $crate::migration!($entity, $version, $name, run({
+            fn migrate(
+                mongo: $crate::Mongo<'_>,
+            ) -> $crate::meta::MigrationFuture<'_> {
+                $crate::meta::update_with_pipeline(
+                    mongo,
+                    <$entity as $crate::Entity>::COLLECTION_NAME,
+                    $crate::Filter::<$entity>::to_document(&$filter),
+                    ::std::vec![$($stage),*],
+                )
+            }
+
+            migrate
+        }));
+    };
+    ($entity:ty, $version:expr, $name:expr, run($function:expr $(,)?)) => {
+        $crate::inventory::submit! {
+            $crate::meta::MigrationWrapper($crate::meta::Migration::new(
+                <$entity as $crate::EntityName>::ENTITY_NAME,
+                <$entity as $crate::Entity>::COLLECTION_NAME,
+                $version,
+                $name,
+                $function,
+            ))
+        }
+    };
+}
+
+#[doc(hidden)]
+pub struct MigrationWrapper(pub Migration);
+
+inventory::collect!(MigrationWrapper);
+
+#[doc(hidden)]
+pub type MigrationFuture<'a> = BoxFuture<'a, Result<()>>;
+
+type MigrationPtr = for<'a> fn(Mongo<'a>) -> MigrationFuture<'a>;
+
+/// A migration of an entity, registered with [`migration!`](crate::migration).
+#[derive(Debug)]
+pub struct Migration {
+    entity_name: &'static str,
+    collection_name: &'static str,
+    version: u32,
+    name: &'static str,
+    migrate_ptr: MigrationPtr,
+}
+
+impl Migration {
+    #[doc(hidden)]
+    pub const fn new(
+        entity_name: &'static str,
+        collection_name: &'static str,
+        version: u32,
+        name: &'static str,
+        migrate_ptr: MigrationPtr,
+    ) -> Self {
+        Self {
+            entity_name,
+            collection_name,
+            version,
+            name,
+            migrate_ptr,
+        }
+    }
+
+    /// The path of the migrated entity type, see [`EntityName`](crate::EntityName).
+    pub fn entity_name(&self) -> &'static str {
+        self.entity_name
+    }
+
+    pub fn collection_name(&self) -> &'static str {
+        self.collection_name
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+impl Display for Migration {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} v{}: {}", self.entity_name, self.version, self.name)
+    }
+}
+
+pub fn migrations() -> impl Iterator<Item = &'static Migration> {
+    inventory::iter::<MigrationWrapper>
+        .into_iter()
+        .map(|wrapper| &wrapper.0)
+}
+
+#[doc(hidden)]
+pub fn update_with_pipeline<'a>(
+    mongo: Mongo<'a>,
+    collection_name: &'static str,
+    filter: Document,
+    pipeline: Vec<Document>,
+) -> MigrationFuture<'a> {
+    async move {
+        let Mongo { db, session } = mongo;
+        let collection = db.collection::<Document>(collection_name);
+        crate::with_session!(collection.update_many(filter, pipeline), session).await?;
+
+        Ok(())
+    }
+    .boxed()
+}
+
+/// Runs the pending [`migrations`]. Applied migrations are recorded in a tracking collection,
+/// `khan_migrations` by default, which also holds a lock, so that only one instance runs
+/// migrations at a time. The lock expires after a lease, in case an instance dies while
+/// holding it. The lease is renewed while migrations run, and a migration is interrupted if
+/// the lock is lost.
+///
+/// With a session, each migration is committed in a transaction together with its record, so
+/// the session must not be in a transaction already. Without a session, a migration that
+/// fails, or whose record fails to be inserted, is run again in full, so it should be safe to
+/// repeat.
+///
+/// Migrations are forward-only: a pending migration with a version lower than an applied one
+/// of the same entity is an error.
+#[derive(Debug, Clone)]
+pub struct Migrator {
+    collection_name: String,
+    lease: Duration,
+    dry_run: bool,
+}
+
+impl Default for Migrator {
+    fn default() -> Self {
+        Self {
+            collection_name: "khan_migrations".to_owned(),
+            // `Duration::from_mins` needs Rust 1.91
+            #[allow(clippy::duration_suboptimal_units)]
+            lease: Duration::from_secs(10 * 60),
+            dry_run: false,
+        }
+    }
+}
+
+impl Migrator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the name of the tracking collection.
+    pub fn collection_name(mut self, collection_name: impl Into<String>) -> Self {
+        self.collection_name = collection_name.into();
+        self
+    }
+
+    /// Sets how long the lock is held without being renewed. Defaults to 10 minutes. It is
+    /// renewed every third of the lease.
+    pub fn lease(mut self, lease: Duration) -> Self {
+        self.lease = lease;
+        self
+    }
+
+    /// Only lists the pending migrations, without taking the lock or running them.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Runs the pending migrations, and returns them. Fails if another instance holds
+    /// the lock.
+    pub async fn run(&self, mut mongo: Mongo<'_>) -> Result<Vec<&'static Migration>> {
+        if self.dry_run {
+            return self.pending(mongo).await;
+        }
+
+        let owner = ObjectId::new();
+        self.lock(mongo.rb(), owner).await?;
+
+        let result: Result<_> = async {
+            let pending = self.pending(mongo.rb()).await?;
+            for migration in &pending {
+                self.migrate(mongo.rb(), owner, migration).await?;
+            }
+
+            Ok(pending)
+        }
+        .await;
+
+        // The error of a migration matters more than failing to unlock
+        let unlocked = self.unlock(mongo, owner).await;
+        let pending = result?;
+        unlocked?;
+
+        Ok(pending)
+    }
+
+    async fn migrate(
+        &self,
+        mut mongo: Mongo<'_>,
+        owner: ObjectId,
+        migration: &Migration,
+    ) -> Result<()> {
+        if let Some(session) = &mut mongo.session {
+            session.start_transaction().await?;
+        }
+
+        let result = async {
+            let db = mongo.db;
+            let heartbeat = pin!(self.heartbeat(db, owner));
+            match future::select((migration.migrate_ptr)(mongo.rb()), heartbeat).await {
+                Either::Left((result, _)) => result?,
+                Either::Right((Err(error), _)) => return Err(error),
+            }
+
+            // Only the owner of the lock records the migration
+            self.renew(Mongo::new(db), owner).await?;
+
+            let Mongo { db, session } = mongo.rb();
+            let collection = db.collection::<Document>(&self.collection_name);
+            crate::with_session!(
+                collection.insert_one(doc! {
+                    "_id": {
+                        "entity": migration.entity_name,
+                        "version": i64::from(migration.version),
+                    },
+                    "name": migration.name,
+                    "applied_at": DateTime::now(),
+                }),
+                session
+            )
+            .await?;
+
+            Ok(())
+        }
+        .await;
+
+        match (mongo.session, result) {
+            (Some(session), Ok(())) => session.commit_transaction().await,
+            (Some(session), Err(error)) => {
+                // The server aborts the transaction anyway, so only the error of the migration
+                // is reported
+                let _ = session.abort_transaction().await;
+                Err(error)
+            }
+            (None, result) => result,
+        }
+    }
+
+    // Renews the lease until the lock is lost
+    async fn heartbeat(&self, db: &Database, owner: ObjectId) -> Result<Infallible> {
+        loop {
+            tokio::time::sleep(self.lease / 3).await;
+            self.renew(Mongo::new(db), owner).await?;
+        }
+    }
+
+    async fn pending(&self, mongo: Mongo<'_>) -> Result<Vec<&'static Migration>> {
+        let registered = sort_migrations(migrations().collect())?;
+
+        let Mongo { db, session } = mongo;
+        let collection = db.collection::<Document>(&self.collection_name);
+        let filter = doc! { "_id": { "$ne": LOCK_ID } };
+        let records = match session {
+            Some(session) => {
+                let mut cursor = collection.find(filter).session(&mut *session).await?;
+                let mut records = Vec::new();
+                while let Some(record) = cursor.next(&mut *session).await {
+                    records.push(record?);
+                }
+                records
+            }
+            None => collection.find(filter).await?.try_collect().await?,
+        };
+
+        pending_migrations(registered, &records)
+    }
+
+    async fn lock(&self, mongo: Mongo<'_>, owner: ObjectId) -> Result<()> {
+        let result = mongo
+            .db
+            .collection::<Document>(&self.collection_name)
+            .update_one(
+                doc! { "_id": LOCK_ID, "expires_at": { "$lt": DateTime::now() } },
+                doc! { "$set": { "owner": owner, "expires_at": self.expires_at() } },
+            )
+            .upsert(true)
+            .await;
+
+        check_lock(result)
+    }
+
+    async fn renew(&self, mongo: Mongo<'_>, owner: ObjectId) -> Result<()> {
+        let result = mongo
+            .db
+            .collection::<Document>(&self.collection_name)
+            .update_one(
+                doc! { "_id": LOCK_ID, "owner": owner },
+                doc! { "$set": { "expires_at": self.expires_at() } },
+            )
+            .await?;
+
+        if result.matched_count == 0 {
+            return Err(Error::custom(
+                "migration lock expired and was taken by another instance".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    async fn unlock(&self, mongo: Mongo<'_>, owner: ObjectId) -> Result<()> {
+        mongo
+            .db
+            .collection::<Document>(&self.collection_name)
+            .delete_one(doc! { "_id": LOCK_ID, "owner": owner })
+            .await?;
+
+        Ok(())
+    }
+
+    fn expires_at(&self) -> DateTime {
+        DateTime::from_system_time(SystemTime::now() + self.lease)
+    }
+}
+
+const LOCK_ID: &str = "lock";
+
+// Sorts the migrations in the order they run, and rejects duplicate versions of an entity
+fn sort_migrations(mut registered: Vec<&Migration>) -> Result<Vec<&Migration>> {
+    registered.sort_by_key(|migration| (migration.version, migration.entity_name));
+    for pair in registered.windows(2) {
+        if (pair[0].entity_name, pair[0].version) == (pair[1].entity_name, pair[1].version) {
+            return Err(Error::custom(format!(
+                "migrations `{}` and `{}` of `{}` have the same version {}",
+                pair[0].name, pair[1].name, pair[0].entity_name, pair[0].version
+            )));
+        }
+    }
+
+    Ok(registered)
+}
+
+// Keeps the sorted migrations that have no record, and checks that they are newer than the
+// applied ones
+fn pending_migrations<'a>(
+    registered: Vec<&'a Migration>,
+    records: &[Document],
+) -> Result<Vec<&'a Migration>> {
+    let mut applied = BTreeMap::<&str, Vec<i64>>::new();
+    for record in records {
+        let id = record.get_document("_id").ok();
+        if let (Some(entity_name), Some(version)) = (
+            id.and_then(|id| id.get_str("entity").ok()),
+            id.and_then(|id| id.get("version")).and_then(as_number),
+        ) {
+            applied.entry(entity_name).or_default().push(version);
+        }
+    }
+
+    let mut pending = Vec::new();
+    for migration in registered {
+        let versions = applied
+            .get(migration.entity_name)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        if versions.contains(&i64::from(migration.version)) {
+            continue;
+        }
+        match versions.iter().max() {
+            Some(latest) if *latest > i64::from(migration.version) => {
+                return Err(Error::custom(format!(
+                    "migration {migration} is older than the applied version {latest}, and migrations are forward-only"
+                )));
+            }
+            _ => pending.push(migration),
+        }
+    }
+
+    Ok(pending)
+}
+
+fn check_lock(result: Result<UpdateResult>) -> Result<()> {
+    match result {
+        Ok(_) => Ok(()),
+        // The lock exists and hasn't expired, so the upsert conflicts with it
+        Err(error) if is_duplicate_key(&error) => Err(Error::custom(
+            "migrations are being run by another instance".to_owned(),
+        )),
+        Err(error) => Err(error),
+    }
+}
+
+fn is_duplicate_key(error: &Error) -> bool {
+    match &*error.kind {
+        ErrorKind::Write(WriteFailure::WriteError(error)) => error.code == 11000,
+        ErrorKind::Command(error) => error.code == 11000,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model(keys: Document, options: Document) -> IndexModel {
+        IndexModel::builder()
+            .keys(keys)
+            .options(bson::from_document::<IndexOptions>(options).unwrap())
+            .build()
+    }
+
+    #[test]
+    fn default_index_name() {
+        assert_eq!(
+            index_name(&model(doc! { "a": 1, "b": -1 }, doc! {})),
+            "a_1_b_-1"
+        );
+        assert_eq!(index_name(&model(doc! { "a": "text" }, doc! {})), "a_text");
+        assert_eq!(
+            index_name(&model(doc! { "a": 1 }, doc! { "name": "by_a" })),
+            "by_a"
+        );
+    }
+
+    #[test]
+    fn temporary_index_name() {
+        let temporary = temporary_index(&model(doc! { "a": 1 }, doc! { "unique": true }));
+        assert_eq!(index_name(&temporary), "a_1_rebuild");
+        assert_eq!(temporary.keys, doc! { "a": 1 });
+        assert_eq!(temporary.options.unwrap().unique, Some(true));
+
+        let temporary = temporary_index(&model(doc! { "a": 1 }, doc! { "name": "by_a" }));
+        assert_eq!(index_name(&temporary), "by_a_rebuild");
+    }
+
+    #[test]
+    fn index_keys_match() {
+        let declared = model(doc! { "a": 1, "b": -1 }, doc! {});
+
+        assert!(index_matches(
+            &model(doc! { "a": 1.0, "b": -1_i64 }, doc! {}),
+            &declared
+        ));
+        assert!(!index_matches(
+            &model(doc! { "b": -1, "a": 1 }, doc! {}),
+            &declared
+        ));
+        assert!(!index_matches(&model(doc! { "a": 1 }, doc! {}), &declared));
+    }
+
+    #[test]
+    fn index_options_match() {
+        let declared = model(
+            doc! { "a": 1 },
+            doc! {
+                "name": "by_a",
+                "unique": true,
+                "partialFilterExpression": { "a": { "$gt": 5_i64 } },
+            },
+        );
+
+        // The server adds the version, and may change the number types
+        let existing = model(
+            doc! { "a": 1 },
+            doc! {
+                "name": "by_a",
+                "v": 2,
+                "unique": true,
+                "partialFilterExpression": { "a": { "$gt": 5 } },
+            },
+        );
+        assert!(index_matches(&existing, &declared));
+
+        let existing = model(
+            doc! { "a": 1 },
+            doc! { "name": "by_a", "partialFilterExpression": { "a": { "$gt": 5 } } },
+        );
+        assert!(!index_matches(&existing, &declared));
+
+        let existing = model(doc! { "a": 1 }, doc! { "name": "by_a", "unique": true });
+        assert!(!index_matches(&existing, &declared));
+    }
+
+    #[test]
+    fn index_options_only_on_existing() {
+        let declared = model(doc! { "a": 1 }, doc! {});
+
+        assert!(index_matches(
+            &model(doc! { "a": 1 }, doc! { "unique": false, "v": 2 }),
+            &declared
+        ));
+        assert!(!index_matches(
+            &model(doc! { "a": 1 }, doc! { "sparse": true }),
+            &declared
+        ));
+        assert!(!index_matches(
+            &model(doc! { "a": 1 }, doc! { "expireAfterSeconds": 60 }),
+            &declared
+        ));
+    }
+
+    #[test]
+    fn index_collation_match() {
+        let declared = model(doc! { "a": 1 }, doc! { "collation": { "locale": "en" } });
+
+        // The server fills in the defaults of the locale
+        let existing = model(
+            doc! { "a": 1 },
+            doc! {
+                "collation": {
+                    "locale": "en",
+                    "caseLevel": false,
+                    "strength": 3,
+                    "numericOrdering": false,
+                },
+            },
+        );
+        assert!(index_matches(&existing, &declared));
+
+        let existing = model(doc! { "a": 1 }, doc! { "collation": { "locale": "fr" } });
+        assert!(!index_matches(&existing, &declared));
+
+        assert!(!index_matches(&model(doc! { "a": 1 }, doc! {}), &declared));
+        assert!(!index_matches(&existing, &model(doc! { "a": 1 }, doc! {})));
+    }
+
+    #[cfg(feature = "schema")]
+    fn checked_schema(
+        schema: serde_json::Value,
+        rewrite: bool,
+    ) -> (serde_json::Value, Vec<(String, SchemaKeyword)>) {
+        let mut schema = serde_json::from_value(schema).unwrap();
+        let mut incompatibilities = Vec::new();
+        check_schema(&mut schema, "#", rewrite, &mut incompatibilities);
+
+        let incompatibilities = incompatibilities
+            .into_iter()
+            .map(|incompatibility| (incompatibility.path, incompatibility.keyword))
+            .collect();
+
+        (serde_json::to_value(schema).unwrap(), incompatibilities)
+    }
+
+    #[cfg(feature = "schema")]
+    #[test]
+    fn schema_rewrite() {
+        let (schema, incompatibilities) = checked_schema(
+            serde_json::json!({
+                "type": "object",
+                "properties": {
+                    "age": { "type": "integer", "format": "int32", "default": 0 },
+                    "score": { "type": ["integer", "null"] },
+                    "tags": { "type": "array", "items": { "type": "integer" } },
+                },
+            }),
+            true,
+        );
+
+        assert_eq!(incompatibilities, []);
+        assert_eq!(
+            schema,
+            serde_json::json!({
+                "type": "object",
+                "properties": {
+                    "age": { "bsonType": ["int", "long"] },
+                    "score": { "bsonType": ["int", "long", "null"] },
+                    "tags": { "type": "array", "items": { "bsonType": ["int", "long"] } },
+                },
+            })
+        );
+    }
+
+    #[cfg(feature = "schema")]
+    #[test]
+    fn schema_incompatibilities() {
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": {
+                "age": { "type": "integer", "format": "int32" },
+                "a/b": { "anyOf": [{ "type": "string" }, { "$ref": "#/definitions/Node" }] },
+                "nested": {
+                    "type": "object",
+                    "additionalProperties": { "type": "string", "default": "" },
+                },
+            },
+        });
+
+        let (unchanged, incompatibilities) = checked_schema(schema.clone(), false);
+        assert_eq!(unchanged, schema);
+        assert_eq!(
+            incompatibilities,
+            [
+                ("#/properties/a~1b/anyOf/1".to_owned(), SchemaKeyword::Ref),
+                ("#/properties/age".to_owned(), SchemaKeyword::Format),
+                ("#/properties/age".to_owned(), SchemaKeyword::Integer),
+                (
+                    "#/properties/nested/additionalProperties".to_owned(),
+                    SchemaKeyword::Default
+                ),
+            ]
+        );
+
+        // `$ref` can't be rewritten
+        let (_, incompatibilities) = checked_schema(schema, true);
+        assert_eq!(
+            incompatibilities,
+            [("#/properties/a~1b/anyOf/1".to_owned(), SchemaKeyword::Ref)]
+        );
+    }
+
+    #[cfg(feature = "schema")]
+    #[test]
+    fn schema_error_display() {
+        let error = SchemaError {
+            entity_name: "User",
+            incompatibilities: vec![SchemaIncompatibility {
+                path: "#/properties/age".to_owned(),
+                keyword: SchemaKeyword::Integer,
+            }],
+        };
+
+        assert!(error.to_string().starts_with(
+            "schema of `User` is not supported by `MongoDB` schema validation:\n  \
+             #/properties/age: `integer` type is not supported"
+        ));
+    }
+
+    #[cfg(feature = "schema")]
+    #[test]
+    fn validator_commands() {
+        let schema = doc! { "bsonType": "object", "required": ["_id"] };
+
+        assert_eq!(
+            validator_command(
+                "collMod",
+                "user",
+                vec![schema.clone()],
+                &ValidationLevel::Strict,
+                &ValidationAction::Error,
+            )
+            .unwrap(),
+            doc! {
+                "collMod": "user",
+                "validator": { "$jsonSchema": schema.clone() },
+                "validationLevel": "strict",
+                "validationAction": "error",
+            }
+        );
+
+        // Entities sharing a collection are validated with any of their schemas
+        let other = doc! { "bsonType": "object", "required": ["name"] };
+        assert_eq!(
+            validator_command(
+                "create",
+                "user",
+                vec![schema.clone(), other.clone()],
+                &ValidationLevel::Moderate,
+                &ValidationAction::Warn,
+            )
+            .unwrap(),
+            doc! {
+                "create": "user",
+                "validator": { "$jsonSchema": { "anyOf": [schema, other] } },
+                "validationLevel": "moderate",
+                "validationAction": "warn",
+            }
+        );
+    }
+
+    #[test]
+    fn audit_report_counts_documents() {
+        let mut report = AuditReport {
+            entity_name: "User",
+            collection_name: "user",
+            checked: 0,
+            failed: 0,
+            failures: Vec::new(),
+            omitted: 0,
+        };
+        let mut kinds = HashMap::new();
+
+        for id in 1..=3 {
+            let raw = RawDocumentBuf::from_document(&doc! { "_id": id }).unwrap();
+            report.record(
+                &mut kinds,
+                None,
+                format!("invalid type: string \"{id}\", expected i32"),
+                &raw,
+                2,
+            );
+        }
+        let raw = RawDocumentBuf::from_document(&doc! { "_id": 4 }).unwrap();
+        report.record(
+            &mut kinds,
+            Some("#".to_owned()),
+            "document doesn't match the schema".to_owned(),
+            &raw,
+            2,
+        );
+
+        assert_eq!((report.checked(), report.failed()), (4, 4));
+        assert_eq!(report.failures().len(), 2);
+
+        let failure = &report.failures()[0];
+        assert_eq!(failure.path(), None);
+        assert_eq!(failure.kind(), "invalid type: string, expected i32");
+        assert_eq!(
+            failure.message(),
+            r#"invalid type: string "1", expected i32"#
+        );
+        assert_eq!(failure.count(), 3);
+        assert_eq!(failure.sample_ids(), [Bson::Int32(1), Bson::Int32(2)]);
+
+        assert_eq!(report.failures()[1].path(), Some("#"));
+        assert_eq!(report.failures()[1].count(), 1);
+
+        for i in 0..MAX_FAILURE_KINDS {
+            report.record(&mut kinds, None, format!("missing field `{i}`"), &raw, 2);
+        }
+        assert_eq!(report.failures().len(), MAX_FAILURE_KINDS);
+        assert_eq!(report.omitted(), 2);
+    }
+
+    #[test]
+    fn failure_kinds() {
+        assert_eq!(
+            failure_kind(r#"invalid type: string "a, expected b", expected i32"#),
+            "invalid type: string, expected i32"
+        );
+        assert_eq!(
+            failure_kind("invalid value: integer `-1`, expected u32"),
+            "invalid value: integer, expected u32"
+        );
+        assert_eq!(
+            failure_kind("invalid type: floating point `1.5`, expected i64"),
+            "invalid type: floating point, expected i64"
+        );
+        assert_eq!(
+            failure_kind("invalid type: map, expected a string"),
+            "invalid type: map, expected a string"
+        );
+        assert_eq!(
+            failure_kind("invalid length 3, expected a tuple of size 2"),
+            "invalid length, expected a tuple of size 2"
+        );
+        assert_eq!(
+            failure_kind("unknown variant `x`, expected `a` or `b`"),
+            "unknown variant, expected `a` or `b`"
+        );
+        assert_eq!(
+            failure_kind("unexpected property `extra`"),
+            "unexpected property"
+        );
+        assert_eq!(failure_kind("missing field `name`"), "missing field `name`");
+    }
+
+    #[cfg(feature = "schema")]
+    fn snapshot(fields: &[(&str, &[&str], bool)], indexes: Vec<IndexModel>) -> SchemaSnapshot {
+        let fields = fields
+            .iter()
+            .map(|(path, types, required)| {
+                (
+                    (*path).to_owned(),
+                    FieldSnapshot {
+                        types: types.iter().map(|typ| (*typ).to_owned()).collect(),
+                        required: *required,
+                        allowed: None,
+                    },
+                )
+            })
+            .collect();
+
+        SchemaSnapshot {
+            entities: BTreeMap::from([(
+                "User".to_owned(),
+                EntitySnapshot {
+                    collection_name: "users".to_owned(),
+                    fields,
+                    indexes,
+                    schema: Some(doc! { "bsonType": "object", "maxProperties": 10_i64 }),
+                },
+            )]),
+        }
+    }
+
+    #[cfg(feature = "schema")]
+    fn changes(drift: &SchemaDrift) -> Vec<(SchemaChangeKind, &str)> {
+        drift
+            .changes()
+            .iter()
+            .map(|change| (change.kind(), change.description()))
+            .collect()
+    }
+
+    #[cfg(feature = "schema")]
+    #[test]
+    fn snapshot_round_trip() {
+        let mut snapshot = snapshot(
+            &[
+                ("_id", &["objectId"], true),
+                ("age", &["int", "long"], false),
+            ],
+            vec![
+                model(doc! { "email": 1_i64 }, doc! { "unique": true }),
+                model(
+                    doc! { "age": -1_i64 },
+                    doc! {
+                        "name": "adults",
+                        "expireAfterSeconds": 60_i64,
+                        "partialFilterExpression": { "age": { "$gte": 18_i64 } },
+                        "collation": { "locale": "en", "strength": 2 },
+                        "hidden": true,
+                    },
+                ),
+            ],
+        );
+        snapshot.entities.get_mut("User").unwrap().fields.insert(
+            "role".to_owned(),
+            FieldSnapshot {
+                types: BTreeSet::from(["int".to_owned()]),
+                required: true,
+                allowed: Some(vec![Bson::Int64(1), Bson::Int64(2)]),
+            },
+        );
+
+        let json = snapshot.to_json();
+        let parsed = SchemaSnapshot::from_json(&json).unwrap();
+
+        let drift = snapshot.diff(&parsed);
+        assert!(drift.is_empty(), "{drift}");
+        let drift = parsed.diff(&snapshot);
+        assert!(drift.is_empty(), "{drift}");
+        assert_eq!(parsed.to_json(), json);
+    }
+
+    #[cfg(feature = "schema")]
+    #[test]
+    fn snapshot_diff_fields() {
+        use SchemaChangeKind::{Additive, Breaking};
+
+        let old = snapshot(
+            &[
+                ("age", &["int", "long"], false),
+                ("name", &["string"], true),
+                ("nickname", &["string"], false),
+                ("score", &["number"], false),
+            ],
+            Vec::new(),
+        );
+        let new = snapshot(
+            &[
+                ("age", &["number"], false),
+                ("email", &["string"], true),
+                ("name", &["string"], false),
+                ("score", &["int"], false),
+            ],
+            Vec::new(),
+        );
+
+        assert_eq!(
+            changes(&old.diff(&new)),
+            [
+                (
+                    Additive,
+                    "field `age` retyped from `int` | `long` to `number`"
+                ),
+                (Additive, "field `name` made optional"),
+                (Breaking, "field `nickname` removed"),
+                (Breaking, "field `score` retyped from `number` to `int`"),
+                (Breaking, "required field `email` added"),
+            ]
+        );
+        assert_eq!(old.diff(&new).kind(), Some(Breaking));
+
+        let widened = snapshot(
+            &[("score", &["decimal", "double", "int", "long"], false)],
+            Vec::new(),
+        );
+        let score = snapshot(&[("score", &["number"], false)], Vec::new());
+        assert!(
+            changes(&widened.diff(&score))
+                .iter()
+                .chain(&changes(&score.diff(&widened)))
+                .all(|(kind, _)| *kind == Additive)
+        );
+    }
+
+    #[cfg(feature = "schema")]
+    #[test]
+    fn snapshot_diff_allowed_values() {
+        use SchemaChangeKind::{Additive, Breaking};
+
+        let with_allowed = |allowed: Vec<Bson>| {
+            let mut snapshot = snapshot(&[("role", &["int"], true)], Vec::new());
+            snapshot
+                .entities
+                .get_mut("User")
+                .unwrap()
+                .fields
+                .get_mut("role")
+                .unwrap()
+                .allowed = Some(allowed);
+            snapshot
+        };
+
+        let old = with_allowed(vec![Bson::Int32(1), Bson::Int32(2)]);
+        assert!(
+            old.diff(&with_allowed(vec![Bson::Int64(2), Bson::Int64(1)]))
+                .is_empty()
+        );
+        assert_eq!(
+            changes(&old.diff(&with_allowed(vec![
+                Bson::Int32(1),
+                Bson::Int32(2),
+                Bson::Int32(3)
+            ]))),
+            [(Additive, "allowed values of field `role` changed")]
+        );
+        assert_eq!(
+            changes(&old.diff(&with_allowed(vec![Bson::Int32(1)]))),
+            [(Breaking, "allowed values of field `role` changed")]
+        );
+    }
+
+    #[cfg(feature = "schema")]
+    #[test]
+    fn snapshot_diff_schema() {
+        use SchemaChangeKind::Breaking;
+
+        let with_schema = |schema: Document| {
+            let mut snapshot = snapshot(&[("age", &["int"], false)], Vec::new());
+            snapshot.entities.get_mut("User").unwrap().schema = Some(schema);
+            snapshot
+        };
+
+        let old = with_schema(doc! {
+            "properties": {
+                "age": { "bsonType": "int", "minimum": 0_i64 },
+                "address": { "required": ["city"] },
+            },
+        });
+        // Numbers are compared by value, the JSON round trip doesn't keep their type
+        let same = with_schema(doc! {
+            "properties": {
+                "age": { "bsonType": "int", "minimum": 0.0 },
+                "address": { "required": ["city"] },
+            },
+        });
+        assert!(old.diff(&same).is_empty());
+
+        let minimum = with_schema(doc! {
+            "properties": {
+                "age": { "bsonType": "int", "minimum": 18_i64 },
+                "address": { "required": ["city"] },
+            },
+        });
+        assert_eq!(changes(&old.diff(&minimum)), [(Breaking, "schema changed")]);
+
+        let nested_required = with_schema(doc! {
+            "properties": {
+                "age": { "bsonType": "int", "minimum": 0_i64 },
+                "address": { "required": ["city", "street"] },
+            },
+        });
+        assert_eq!(
+            changes(&old.diff(&nested_required)),
+            [(Breaking, "schema changed")]
+        );
+
+        // Already explained by the field change
+        let mut retyped = with_schema(doc! {});
+        retyped
+            .entities
+            .get_mut("User")
+            .unwrap()
+            .fields
+            .get_mut("age")
+            .unwrap()
+            .types = BTreeSet::from(["string".to_owned()]);
+        assert_eq!(
+            changes(&old.diff(&retyped)),
+            [(Breaking, "field `age` retyped from `int` to `string`")]
+        );
+
+        let mut without_schema = old.clone();
+        without_schema.entities.get_mut("User").unwrap().schema = None;
+        assert!(old.diff(&without_schema).is_empty());
+    }
+
+    #[cfg(feature = "schema")]
+    #[test]
+    fn snapshot_diff_indexes() {
+        use SchemaChangeKind::Index;
+
+        let old = snapshot(
+            &[],
+            vec![
+                model(doc! { "email": 1 }, doc! {}),
+                model(doc! { "name": 1 }, doc! {}),
+                model(doc! { "age": 1 }, doc! { "collation": { "locale": "en" } }),
+            ],
+        );
+        let new = snapshot(
+            &[],
+            vec![
+                model(doc! { "email": 1 }, doc! { "unique": true }),
+                model(doc! { "age": 1 }, doc! {}),
+                model(doc! { "created_at": -1 }, doc! {}),
+            ],
+        );
+
+        assert_eq!(
+            changes(&old.diff(&new)),
+            [
+                (Index, "index `email_1` changed"),
+                (Index, "index `name_1` removed"),
+                (Index, "index `age_1` changed"),
+                (Index, "index `created_at_-1` added"),
+            ]
+        );
+    }
+
+    fn migration(entity_name: &'static str, version: u32, name: &'static str) -> Migration {
+        fn migrate(_: Mongo<'_>) -> MigrationFuture<'_> {
+            async { Ok(()) }.boxed()
+        }
+
+        Migration::new(entity_name, "", version, name, migrate)
+    }
+
+    fn record(entity_name: &str, version: i32) -> Document {
+        doc! { "_id": { "entity": entity_name, "version": version }, "name": "" }
+    }
+
+    fn names(migrations: &[&Migration]) -> Vec<String> {
+        migrations.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn migration_order() {
+        let migrations = [
+            migration("User", 2, "split_name"),
+            migration("Session", 1, "expire"),
+            migration("User", 1, "add_nickname"),
+            migration("Purchase", 2, "reprice"),
+        ];
+
+        let sorted = sort_migrations(migrations.iter().collect()).unwrap();
+        assert_eq!(
+            names(&sorted),
+            [
+                "Session v1: expire",
+                "User v1: add_nickname",
+                "Purchase v2: reprice",
+                "User v2: split_name",
+            ]
+        );
+    }
+
+    #[test]
+    fn migration_duplicate_versions() {
+        let migrations = [
+            migration("User", 1, "add_nickname"),
+            migration("Session", 1, "expire"),
+            migration("User", 1, "split_name"),
+        ];
+
+        let error = sort_migrations(migrations.iter().collect()).unwrap_err();
+        assert!(matches!(&*error.kind, ErrorKind::Custom(_)));
+        assert!(
+            error.to_string().contains(
+                "migrations `add_nickname` and `split_name` of `User` have the same version 1"
+            ),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn pending_migrations_skip_applied() {
+        let migrations = [
+            migration("User", 1, "add_nickname"),
+            migration("User", 2, "split_name"),
+            migration("Session", 1, "expire"),
+        ];
+        let registered = sort_migrations(migrations.iter().collect()).unwrap();
+
+        let pending = pending_migrations(registered.clone(), &[]).unwrap();
+        assert_eq!(pending.len(), 3);
+
+        // Versions are matched by value, whatever their BSON type
+        let records = [
+            record("User", 1),
+            doc! { "_id": { "entity": "Session", "version": 1_i64 } },
+            // Ignored, such as the lock
+            doc! { "_id": "lock" },
+        ];
+        let pending = pending_migrations(registered.clone(), &records).unwrap();
+        assert_eq!(names(&pending), ["User v2: split_name"]);
+
+        let records = [record("User", 1), record("User", 2), record("Session", 1)];
+        assert!(pending_migrations(registered, &records).unwrap().is_empty());
+    }
+
+    #[test]
+    fn pending_migrations_forward_only() {
+        let migrations = [
+            migration("User", 1, "add_nickname"),
+            migration("User", 2, "split_name"),
+            migration("Session", 1, "expire"),
+        ];
+        let registered = sort_migrations(migrations.iter().collect()).unwrap();
+
+        // Applied versions of other entities don't matter
+        let records = [record("User", 1), record("Session", 3)];
+        let error = pending_migrations(registered.clone(), &records).unwrap_err();
+        assert!(
+            error.to_string().contains(
+                "migration Session v1: expire is older than the applied version 3, and migrations are forward-only"
+            ),
+            "{error}"
+        );
+
+        let records = [record("User", 2)];
+        let error = pending_migrations(registered, &records).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("migration User v1: add_nickname is older than the applied version 2"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn lock_contention() {
+        let write_error = |code: i32| -> Error {
+            let error = bson::from_document(doc! { "code": code, "errmsg": "" }).unwrap();
+            ErrorKind::Write(WriteFailure::WriteError(error)).into()
+        };
+        let command_error = |code: i32| -> Error {
+            let error = bson::from_document(doc! { "code": code, "errmsg": "" }).unwrap();
+            ErrorKind::Command(error).into()
+        };
+
+        assert!(check_lock(Ok(UpdateResult::default())).is_ok());
+
+        // A second instance's upsert conflicts with the unexpired lock
+        for error in [write_error(11000), command_error(11000)] {
+            let error = check_lock(Err(error)).unwrap_err();
+            assert!(
+                error
+                    .to_string()
+                    .contains("migrations are being run by another instance"),
+                "{error}"
+            );
+        }
+
+        for error in [write_error(121), command_error(13)] {
+            let error = check_lock(Err(error)).unwrap_err();
+            assert!(!is_duplicate_key(&error));
+            assert!(!error.to_string().contains("another instance"), "{error}");
+        }
+    }
+
+    #[test]
+    fn migrations_of_same_named_entities() {
+        let migrations = [
+            migration("app::User", 1, "add_nickname"),
+            migration("app::admin::User", 1, "add_role"),
+        ];
+        let registered = sort_migrations(migrations.iter().collect()).unwrap();
+
+        let records = [record("app::User", 1)];
+        let pending = pending_migrations(registered, &records).unwrap();
+        assert_eq!(names(&pending), ["app::admin::User v1: add_role"]);
+    }
+}
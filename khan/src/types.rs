@@ -1,5 +1,7 @@
 use mongodb::bson;
+#[cfg(feature = "schema")]
 use schemars::JsonSchema;
+#[cfg(feature = "schema")]
 use schemars::schema::SchemaObject;
 use serde::{Deserialize, Serialize};
 use std::borrow::BorrowMut;
@@ -58,6 +60,7 @@ macro_rules! impl_wrapper {
             }
         }
 
+        #[cfg(feature = "schema")]
         impl JsonSchema for $outer {
             fn schema_name() -> String {
                 std::stringify!($outer).into()
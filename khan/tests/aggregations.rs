@@ -0,0 +1,174 @@
+use khan::{
+    Accumulator, Entity, Fields, Group, Order, Relation, WithRelated,
+    mongodb::bson::{self, doc, oid::ObjectId},
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Entity)]
+pub struct Customer {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Entity)]
+pub struct Purchase {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    #[entity(references = Customer)]
+    pub customer_id: Option<ObjectId>,
+    pub customer: String,
+    pub amount: i64,
+    pub paid: bool,
+    pub items: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Fields)]
+pub struct CustomerTotal {
+    #[serde(rename = "_id")]
+    pub customer: String,
+    pub total: i64,
+    pub purchases: i64,
+}
+
+#[test]
+fn stages() {
+    let pipeline = Purchase::aggregate()
+        .filter(purchase::filter! { paid: true })
+        .sort(purchase::Fields::Amount, Order::Desc)
+        .sort(purchase::Fields::Customer, Order::Asc)
+        .skip(10)
+        .limit(20)
+        .unwind(purchase::Fields::Items);
+
+    assert_eq!(
+        pipeline.stages(),
+        [
+            doc! { "$match": { "paid": { "$eq": true } } },
+            doc! { "$sort": { "amount": -1, "customer": 1 } },
+            doc! { "$skip": 10_i64 },
+            doc! { "$limit": 20_i64 },
+            doc! { "$unwind": "$items" },
+        ]
+    );
+}
+
+#[test]
+fn group_retypes_the_pipeline() {
+    let pipeline = Purchase::aggregate()
+        .group::<CustomerTotal>(
+            Group::by(purchase::Fields::Customer)
+                .with(
+                    customer_total::Fields::Total,
+                    Accumulator::Sum(purchase::Fields::Amount),
+                )
+                .with(customer_total::Fields::Purchases, Accumulator::Count),
+        )
+        .sort(customer_total::Fields::Total, Order::Desc);
+
+    assert_eq!(
+        pipeline.stages(),
+        [
+            doc! {
+                "$group": {
+                    "_id": "$customer",
+                    "total": { "$sum": "$amount" },
+                    "purchases": { "$sum": 1 },
+                }
+            },
+            doc! { "$sort": { "total": -1 } },
+        ]
+    );
+}
+
+#[test]
+fn group_by_fields() {
+    let group = Group::<_, customer_total::Fields>::by_fields([
+        purchase::Fields::Customer,
+        purchase::Fields::Paid,
+    ]);
+    let pipeline = Purchase::aggregate().group::<CustomerTotal>(group);
+
+    assert_eq!(
+        pipeline.stages(),
+        [doc! { "$group": { "_id": { "customer": "$customer", "paid": "$paid" } } }]
+    );
+}
+
+#[test]
+fn lookup() {
+    let pipeline = Purchase::aggregate()
+        .filter(purchase::filter! { paid: true })
+        .lookup::<purchase::relations::CustomerId>();
+
+    assert_eq!(
+        pipeline.stages(),
+        [
+            doc! { "$match": { "paid": { "$eq": true } } },
+            doc! {
+                "$lookup": {
+                    "from": Customer::COLLECTION_NAME,
+                    "localField": "customer_id",
+                    "foreignField": "_id",
+                    "as": "_related",
+                }
+            },
+            doc! {
+                "$replaceRoot": {
+                    "newRoot": {
+                        "entity": "$$ROOT",
+                        "related": { "$arrayElemAt": ["$_related", 0] },
+                    }
+                }
+            },
+            doc! { "$project": { "entity._related": 0 } },
+        ]
+    );
+}
+
+#[test]
+fn relation_id() {
+    let customer_id = ObjectId::new();
+    let mut purchase = Purchase {
+        id: ObjectId::new(),
+        customer_id: Some(customer_id),
+        customer: "Kit".to_string(),
+        amount: 10,
+        paid: true,
+        items: Vec::new(),
+    };
+    assert_eq!(
+        purchase::relations::CustomerId::id(&purchase),
+        Some(customer_id)
+    );
+
+    purchase.customer_id = None;
+    assert_eq!(purchase::relations::CustomerId::id(&purchase), None);
+}
+
+#[test]
+fn with_related_deserialization() {
+    let (id, customer_id) = (ObjectId::new(), ObjectId::new());
+    let entity = doc! {
+        "_id": id,
+        "customer_id": customer_id,
+        "customer": "Kit",
+        "amount": 10_i64,
+        "paid": true,
+        "items": [],
+    };
+
+    let found = bson::from_document::<WithRelated<Purchase, Customer>>(doc! {
+        "entity": entity.clone(),
+        "related": { "_id": customer_id, "name": "Kit" },
+    })
+    .unwrap();
+    assert_eq!(found.entity.id, id);
+    assert_eq!(found.related.unwrap().id, customer_id);
+
+    let missing = bson::from_document::<WithRelated<Purchase, Customer>>(doc! {
+        "entity": entity,
+    })
+    .unwrap();
+    assert!(missing.related.is_none());
+}
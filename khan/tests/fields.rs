@@ -0,0 +1,37 @@
+use khan::{
+    Entity, TypedField,
+    mongodb::bson::{self, Bson, oid::ObjectId},
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Entity)]
+pub struct User {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    #[serde(rename = "e")]
+    pub email: String,
+    pub tags: Vec<String>,
+    pub nickname: Option<String>,
+    pub aliases: Option<Vec<String>>,
+}
+
+/// Deserializes a value the way `distinct` does, checking the value type of the field.
+fn value<F: TypedField>(_: F, value: impl Into<Bson>) -> F::Value {
+    bson::from_bson(value.into()).unwrap()
+}
+
+#[test]
+fn typed_field_names() {
+    assert_eq!(user::fields::Id.name(), "_id");
+    assert_eq!(user::fields::Email.name(), "e");
+    assert_eq!(user::fields::Tags.name(), "tags");
+}
+
+#[test]
+fn typed_field_values() {
+    let _: ObjectId = value(user::fields::Id, ObjectId::new());
+    let _: String = value(user::fields::Email, "kit@example.com");
+    let _: String = value(user::fields::Tags, "admin");
+    let _: String = value(user::fields::Nickname, "Kitty");
+    let _: String = value(user::fields::Aliases, "K.I.");
+}
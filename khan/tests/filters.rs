@@ -0,0 +1,365 @@
+use khan::{
+    Entity, Fields, Filter, NestedFilter, UntypedFilter, by_id,
+    mongodb::bson::{Regex, doc, oid::ObjectId, spec::ElementType},
+    nor, not, types,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Fields)]
+pub struct Address {
+    #[serde(rename = "c")]
+    pub city: String,
+    pub zip: String,
+}
+
+#[derive(Serialize, Deserialize, Entity)]
+pub struct User {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub email: String,
+    pub name: String,
+    pub age: i32,
+    pub active: bool,
+    pub tags: Vec<String>,
+    pub nickname: Option<String>,
+    pub address: Address,
+    pub previous_addresses: Vec<Address>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Fields)]
+pub struct Comment {
+    pub text: String,
+    pub likes: i32,
+}
+
+#[derive(Serialize, Deserialize, Entity)]
+pub struct Post {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub comments: Vec<Comment>,
+}
+
+fn untyped(document: khan::mongodb::bson::Document) -> UntypedFilter<User> {
+    UntypedFilter::new(document)
+}
+
+#[test]
+fn or_with_by_id() {
+    let id = ObjectId::new();
+    let filter = user::filter! { email: "kit@example.com" } | by_id::<User>(id);
+
+    assert_eq!(
+        filter.to_document(),
+        doc! { "$or": [{ "email": { "$eq": "kit@example.com" } }, { "_id": id }] }
+    );
+}
+
+#[test]
+fn and_with_negation() {
+    let filter = user::filter! { name: "Kit" } & !user::filter! { email: "kit@example.com" };
+
+    assert_eq!(
+        Filter::<User>::to_document(&filter),
+        doc! {
+            "$and": [
+                { "name": { "$eq": "Kit" } },
+                { "$nor": [{ "email": { "$eq": "kit@example.com" } }] },
+            ]
+        }
+    );
+}
+
+#[test]
+fn chained_operators_are_flattened() {
+    let filter = untyped(doc! { "a": 1 }) & untyped(doc! { "b": 2 }) & untyped(doc! { "c": 3 });
+    assert_eq!(
+        filter.to_document(),
+        doc! { "$and": [{ "a": 1 }, { "b": 2 }, { "c": 3 }] }
+    );
+
+    let filter = untyped(doc! { "a": 1 }) | (untyped(doc! { "b": 2 }) | untyped(doc! { "c": 3 }));
+    assert_eq!(
+        filter.to_document(),
+        doc! { "$or": [{ "a": 1 }, { "b": 2 }, { "c": 3 }] }
+    );
+}
+
+#[test]
+fn mixed_operators_are_not_flattened() {
+    let filter = (untyped(doc! { "a": 1 }) | untyped(doc! { "b": 2 })) & untyped(doc! { "c": 3 });
+
+    assert_eq!(
+        filter.to_document(),
+        doc! { "$and": [{ "$or": [{ "a": 1 }, { "b": 2 }] }, { "c": 3 }] }
+    );
+}
+
+#[test]
+fn operand_with_other_keys_is_not_flattened() {
+    let filter = untyped(doc! { "$and": [{ "a": 1 }], "b": 2 }) & untyped(doc! { "c": 3 });
+
+    assert_eq!(
+        filter.to_document(),
+        doc! { "$and": [{ "$and": [{ "a": 1 }], "b": 2 }, { "c": 3 }] }
+    );
+}
+
+#[test]
+fn nor_inlines_or() {
+    let filter = nor(
+        untyped(doc! { "a": 1 }),
+        untyped(doc! { "b": 2 }) | untyped(doc! { "c": 3 }),
+    );
+    assert_eq!(
+        filter.to_document(),
+        doc! { "$nor": [{ "a": 1 }, { "b": 2 }, { "c": 3 }] }
+    );
+
+    let filter = !(untyped(doc! { "a": 1 }) | untyped(doc! { "b": 2 }));
+    assert_eq!(
+        filter.to_document(),
+        doc! { "$nor": [{ "a": 1 }, { "b": 2 }] }
+    );
+}
+
+#[test]
+fn not_wraps_single_operand() {
+    let filter = not(untyped(doc! { "a": 1 }) & untyped(doc! { "b": 2 }));
+
+    assert_eq!(
+        filter.to_document(),
+        doc! { "$nor": [{ "$and": [{ "a": 1 }, { "b": 2 }] }] }
+    );
+}
+
+#[test]
+fn macro_groups() {
+    let filter = user::filter! {
+        or { email: "kit@example.com", name: "Kit" },
+        not { age: Lt(18) },
+    };
+
+    assert_eq!(
+        Filter::<User>::to_document(&filter),
+        doc! {
+            "$and": [
+                { "$or": [{ "email": { "$eq": "kit@example.com" } }, { "name": { "$eq": "Kit" } }] },
+                { "$nor": [{ "age": { "$lt": 18 } }] },
+            ]
+        }
+    );
+}
+
+#[test]
+fn macro_groups_repeat_fields() {
+    let filter = user::filter! { nor { age: 1, age: 2 } };
+
+    assert_eq!(
+        Filter::<User>::to_document(&filter),
+        doc! { "$nor": [{ "age": { "$eq": 1 } }, { "age": { "$eq": 2 } }] }
+    );
+}
+
+#[test]
+fn macro_groups_nest() {
+    let filter = user::filter! {
+        name: "Kit",
+        and { age: Gte(18), or { email: "a@example.com", email: "b@example.com" } },
+    };
+
+    assert_eq!(
+        Filter::<User>::to_document(&filter),
+        doc! {
+            "$and": [
+                { "name": { "$eq": "Kit" } },
+                { "age": { "$gte": 18 } },
+                { "$or": [{ "email": { "$eq": "a@example.com" } }, { "email": { "$eq": "b@example.com" } }] },
+            ]
+        }
+    );
+}
+
+#[test]
+fn macro_not_combines_fields() {
+    let filter = user::filter! { not { name: "Kit", age: 18 } };
+
+    assert_eq!(
+        Filter::<User>::to_document(&filter),
+        doc! { "$nor": [{ "name": { "$eq": "Kit" }, "age": { "$eq": 18 } }] }
+    );
+}
+
+#[test]
+fn element_operators() {
+    let filter = user::filter! {
+        nickname: Exists(false),
+        age: Type(ElementType::Int32),
+    };
+    assert_eq!(
+        Filter::<User>::to_document(&filter),
+        doc! { "age": { "$type": 16 }, "nickname": { "$exists": false } }
+    );
+
+    let filter = user::filter! { nickname: Null };
+    assert_eq!(
+        Filter::<User>::to_document(&filter),
+        doc! { "nickname": { "$eq": null } }
+    );
+}
+
+#[test]
+fn regex_operator() {
+    let pattern = Regex {
+        pattern: "^Kit".to_string(),
+        options: "i".to_string(),
+    };
+    let regex = types::Regex(pattern.clone());
+    let filter = user::filter! { name: Regex(&regex) };
+
+    assert_eq!(
+        Filter::<User>::to_document(&filter),
+        doc! { "name": { "$regex": pattern } }
+    );
+}
+
+#[test]
+fn array_operators() {
+    let filter = user::filter! { tags: Contains("admin") };
+    assert_eq!(
+        Filter::<User>::to_document(&filter),
+        doc! { "tags": { "$eq": "admin" } }
+    );
+
+    let filter = user::filter! { tags: All(["admin", "staff"]) };
+    assert_eq!(
+        Filter::<User>::to_document(&filter),
+        doc! { "tags": { "$all": ["admin", "staff"] } }
+    );
+
+    let filter = user::filter! { tags: Size(2) };
+    assert_eq!(
+        Filter::<User>::to_document(&filter),
+        doc! { "tags": { "$size": 2_i64 } }
+    );
+}
+
+#[test]
+fn elem_match_operator() {
+    let filter = post::filter! {
+        comments: ElemMatch(comment::filter! { likes: Gt(10) })
+    };
+
+    assert_eq!(
+        Filter::<Post>::to_document(&filter),
+        doc! { "comments": { "$elemMatch": { "likes": { "$gt": 10 } } } }
+    );
+}
+
+#[test]
+fn operators_by_field_type() {
+    let filter = user::filter! {
+        name: In(["Kit", "Kat"]),
+        age: Lte(65),
+        active: Ne(false),
+        nickname: "Kitty",
+    };
+
+    assert_eq!(
+        Filter::<User>::to_document(&filter),
+        doc! {
+            "name": { "$in": ["Kit", "Kat"] },
+            "age": { "$lte": 65 },
+            "active": { "$ne": false },
+            "nickname": { "$eq": "Kitty" },
+        }
+    );
+}
+
+fn owned_filter(email: String, ids: Vec<ObjectId>) -> user::TypedFilter<'static> {
+    user::filter! {
+        email: email,
+        id: In(ids),
+    }
+}
+
+#[test]
+fn owned_operands() {
+    let id = ObjectId::new();
+    let filter = owned_filter("kit@example.com".to_string(), vec![id]);
+
+    assert_eq!(
+        Filter::<User>::to_document(&filter),
+        doc! { "_id": { "$in": [id] }, "email": { "$eq": "kit@example.com" } }
+    );
+}
+
+#[test]
+fn borrowed_operands() {
+    let email = "kit@example.com".to_string();
+    let (first_id, second_id) = (ObjectId::new(), ObjectId::new());
+    let filter = user::filter! {
+        email: &email,
+        id: In([&first_id, &second_id]),
+    };
+
+    assert_eq!(
+        Filter::<User>::to_document(&filter),
+        doc! { "_id": { "$in": [first_id, second_id] }, "email": { "$eq": "kit@example.com" } }
+    );
+}
+
+#[test]
+fn dot_paths() {
+    let filter = user::filter! { address.city: "Berlin", address.zip: Ne("10115") };
+
+    assert_eq!(
+        Filter::<User>::to_document(&filter),
+        doc! { "address.c": { "$eq": "Berlin" }, "address.zip": { "$ne": "10115" } }
+    );
+}
+
+#[test]
+fn dot_paths_mixed_with_fields() {
+    let filter = user::filter! { name: "Kit", previous_addresses.city: In(["Berlin", "Paris"]) };
+
+    assert_eq!(
+        Filter::<User>::to_document(&filter),
+        doc! {
+            "$and": [
+                { "name": { "$eq": "Kit" } },
+                { "previous_addresses.c": { "$in": ["Berlin", "Paris"] } },
+            ]
+        }
+    );
+}
+
+#[test]
+fn dot_paths_in_groups() {
+    let filter = user::filter! { or { address.city: "Berlin", address.zip: "10115" } };
+
+    assert_eq!(
+        Filter::<User>::to_document(&filter),
+        doc! { "$or": [{ "address.c": { "$eq": "Berlin" } }, { "address.zip": { "$eq": "10115" } }] }
+    );
+}
+
+#[test]
+fn nested_filter_prefixes_combinators() {
+    let filter = NestedFilter::<User, Address, _>::new(
+        "address",
+        address::filter! { city: "Berlin" } | address::filter! { zip: "10115" },
+    );
+    assert_eq!(
+        filter.to_document(),
+        doc! { "$or": [{ "address.c": { "$eq": "Berlin" } }, { "address.zip": { "$eq": "10115" } }] }
+    );
+
+    let filter = NestedFilter::<User, Address, _>::new(
+        "address",
+        UntypedFilter::<Address>::new(doc! { "$text": { "$search": "Berlin" }, "zip": "10115" }),
+    );
+    assert_eq!(
+        filter.to_document(),
+        doc! { "$text": { "$search": "Berlin" }, "address.zip": "10115" }
+    );
+}
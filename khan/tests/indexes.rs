@@ -0,0 +1,90 @@
+use khan::{
+    Entity,
+    mongodb::bson::{DateTime, doc, oid::ObjectId},
+};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+#[derive(Debug, Serialize, Deserialize, Entity)]
+#[entity(indexes(
+    by_email(keys(email = 1), unique),
+    by_org_and_name(keys(org_id = 1, name = -1)),
+    by_invite_code(keys(invite_code = 1), sparse, partial = user::filter! {
+        invite_code: Exists(true)
+    }),
+    expire_sessions(keys(session_started_at = 1), expire_after = 86400),
+))]
+pub struct User {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    #[serde(rename = "e")]
+    pub email: String,
+    pub org_id: ObjectId,
+    pub name: String,
+    pub invite_code: Option<String>,
+    pub session_started_at: DateTime,
+}
+
+// Index directions passed as `literal` fragments are single literals, `-1` included
+macro_rules! entity_with_direction {
+    ($entity: ident, $direction: literal) => {
+        #[derive(Debug, Serialize, Deserialize, Entity)]
+        #[entity(indexes(by_created_at(keys(created_at = $direction))))]
+        pub struct $entity {
+            #[serde(rename = "_id")]
+            pub id: ObjectId,
+            pub created_at: DateTime,
+        }
+    };
+}
+
+entity_with_direction!(Ascending, 1);
+entity_with_direction!(Descending, -1);
+
+#[test]
+fn index_models() {
+    let indexes = User::indexes();
+    let names = indexes
+        .iter()
+        .map(|index| {
+            index
+                .options
+                .as_ref()
+                .and_then(|options| options.name.as_deref())
+        })
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        names,
+        [
+            Some("by_email"),
+            Some("by_org_and_name"),
+            Some("by_invite_code"),
+            Some("expire_sessions"),
+        ]
+    );
+
+    assert_eq!(indexes[0].keys, doc! { "e": 1 });
+    assert_eq!(indexes[0].options.as_ref().unwrap().unique, Some(true));
+
+    assert_eq!(indexes[1].keys, doc! { "org_id": 1, "name": -1 });
+    assert_eq!(indexes[1].options.as_ref().unwrap().unique, None);
+
+    let options = indexes[2].options.as_ref().unwrap();
+    assert_eq!(options.sparse, Some(true));
+    assert_eq!(
+        options.partial_filter_expression,
+        Some(doc! { "invite_code": { "$exists": true } })
+    );
+
+    assert_eq!(
+        indexes[3].options.as_ref().unwrap().expire_after,
+        Some(Duration::from_secs(86400))
+    );
+}
+
+#[test]
+fn literal_directions() {
+    assert_eq!(Ascending::indexes()[0].keys, doc! { "created_at": 1 });
+    assert_eq!(Descending::indexes()[0].keys, doc! { "created_at": -1 });
+}
@@ -0,0 +1,119 @@
+#![cfg(feature = "schema")]
+
+use khan::{
+    Entity, EntityName,
+    meta::{self, EntityMetadata, SchemaChangeKind, SchemaSnapshot},
+    mongodb::bson::oid::ObjectId,
+    types,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Entity, JsonSchema)]
+pub struct User {
+    #[serde(rename = "_id")]
+    pub id: types::ObjectId,
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Entity)]
+pub struct Session {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+}
+
+mod admin {
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Entity)]
+    pub struct User {
+        #[serde(rename = "_id")]
+        pub id: ObjectId,
+    }
+}
+
+fn metadata(entity_name: &str) -> &'static EntityMetadata {
+    meta::entity_metadata()
+        .find(|metadata| metadata.entity_name() == entity_name)
+        .unwrap_or_else(|| panic!("`{entity_name}` is not registered"))
+}
+
+#[test]
+fn derived_entities_are_registered() {
+    assert_eq!(User::ENTITY_NAME, "meta::User");
+    assert_eq!(metadata("meta::User").collection_name(), "user");
+    assert_eq!(metadata("meta::Session").collection_name(), "session");
+}
+
+#[test]
+fn same_named_entities_are_registered_apart() {
+    assert_eq!(admin::User::ENTITY_NAME, "meta::admin::User");
+    assert_eq!(
+        meta::entity_metadata()
+            .filter(|metadata| metadata.entity_name().ends_with("::User"))
+            .count(),
+        2
+    );
+    assert!(
+        metadata("meta::admin::User")
+            .try_json_schema(false)
+            .unwrap()
+            .is_none()
+    );
+}
+
+#[test]
+fn schema_of_registered_entities() {
+    let schema = metadata("meta::User")
+        .try_json_schema(false)
+        .unwrap()
+        .unwrap();
+    let schema = schema.into_object();
+    assert!(schema.object.unwrap().properties.contains_key("name"));
+
+    assert!(
+        metadata("meta::Session")
+            .try_json_schema(false)
+            .unwrap()
+            .is_none()
+    );
+}
+
+#[test]
+fn snapshot_of_same_named_entities() {
+    let current = SchemaSnapshot::current(false).unwrap();
+    let json = serde_json::from_str::<serde_json::Value>(&current.to_json()).unwrap();
+    let entities = json["entities"].as_array().unwrap();
+    let entity = |entity_name: &str| {
+        entities
+            .iter()
+            .find(|entity| entity["entity"] == entity_name)
+            .unwrap_or_else(|| panic!("`{entity_name}` is not in the snapshot"))
+    };
+
+    // Each keeps its own schema
+    assert!(entity("meta::User")["fields"].get("name").is_some());
+    assert!(entity("meta::admin::User")["fields"].get("name").is_none());
+
+    let mut older = json.clone();
+    older["entities"]
+        .as_array_mut()
+        .unwrap()
+        .retain(|entity| entity["entity"] != "meta::admin::User");
+    let older = SchemaSnapshot::from_json(&older.to_string()).unwrap();
+
+    let drift = older.diff(&current);
+    let changes = drift
+        .changes()
+        .iter()
+        .map(|change| (change.entity_name(), change.kind(), change.description()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        changes,
+        [(
+            "meta::admin::User",
+            SchemaChangeKind::Additive,
+            "entity added"
+        )]
+    );
+}
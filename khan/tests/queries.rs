@@ -0,0 +1,129 @@
+use futures_util::StreamExt;
+use khan::{
+    Entity, Filter, Mongo, Order, ReturnDocument, Selectable, by_ids,
+    mongodb::{
+        Client,
+        bson::{doc, oid::ObjectId},
+        options::{self, Collation, Hint},
+    },
+};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+#[derive(Debug, Serialize, Deserialize, Entity)]
+#[entity(projections(Profile(id, name), Contact(email)))]
+pub struct User {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub name: String,
+    #[serde(rename = "e")]
+    pub email: String,
+    pub age: i32,
+    pub active: bool,
+}
+
+#[test]
+fn filter() {
+    let query = User::query(user::filter! { active: true });
+
+    assert_eq!(query.filter(), &doc! { "active": { "$eq": true } });
+}
+
+#[test]
+fn default_options() {
+    let options = User::query(user::filter! {}).options();
+
+    assert_eq!(options.sort, None);
+    assert_eq!(options.projection, None);
+    assert_eq!(options.skip, None);
+    assert_eq!(options.limit, None);
+    assert_eq!(options.batch_size, None);
+}
+
+#[test]
+fn sort_skip_limit() {
+    let options = User::query(user::filter! {})
+        .sort(user::Fields::Age, Order::Desc)
+        .sort(user::Fields::Email, Order::Asc)
+        .skip(10)
+        .limit(20)
+        .options();
+
+    assert_eq!(options.sort, Some(doc! { "age": -1, "e": 1 }));
+    assert_eq!(options.skip, Some(10));
+    assert_eq!(options.limit, Some(20));
+}
+
+#[test]
+fn other_options() {
+    let collation = Collation::builder().locale("en").build();
+
+    let options = User::query(user::filter! {})
+        .collation(collation.clone())
+        .hint(Hint::Name("by_age".to_string()))
+        .max_time(Duration::from_secs(5))
+        .batch_size(100)
+        .options();
+
+    assert_eq!(options.collation.unwrap().locale, collation.locale);
+    assert!(matches!(options.hint, Some(Hint::Name(name)) if name == "by_age"));
+    assert_eq!(options.max_time, Some(Duration::from_secs(5)));
+    assert_eq!(options.batch_size, Some(100));
+}
+
+#[test]
+fn projections() {
+    let projection = user::Profile::query(user::filter! {})
+        .options()
+        .projection
+        .unwrap();
+    assert_eq!(projection.get_i32("name"), Ok(1));
+    assert!(!projection.contains_key("e"));
+
+    let projection = user::Contact::query(user::filter! {})
+        .options()
+        .projection
+        .unwrap();
+    assert_eq!(projection.get_i32("e"), Ok(1));
+    assert!(!projection.contains_key("name"));
+}
+
+#[test]
+fn filter_by_ids() {
+    let ids = [ObjectId::new(), ObjectId::new()];
+
+    assert_eq!(
+        by_ids::<User>(ids).to_document(),
+        doc! { "_id": { "$in": [ids[0], ids[1]] } }
+    );
+    assert_eq!(
+        by_ids::<User>([]).to_document(),
+        doc! { "_id": { "$in": [] } }
+    );
+}
+
+#[test]
+fn return_document() {
+    assert!(matches!(
+        options::ReturnDocument::from(ReturnDocument::default()),
+        options::ReturnDocument::Before
+    ));
+    assert!(matches!(
+        options::ReturnDocument::from(ReturnDocument::After),
+        options::ReturnDocument::After
+    ));
+}
+
+#[tokio::test]
+async fn find_stream_reports_errors_as_items() {
+    // Nothing listens on the port, so the query fails once the stream is polled
+    let client = Client::with_uri_str("mongodb://127.0.0.1:1/?serverSelectionTimeoutMS=100")
+        .await
+        .unwrap();
+    let db = client.database("khan");
+
+    let mut stream = User::find_stream(Mongo::new(&db), user::filter! {}, Some(10));
+
+    assert!(stream.next().await.unwrap().is_err());
+    assert!(stream.next().await.is_none());
+}
@@ -0,0 +1,69 @@
+use khan::{
+    DeleteResult, InsertManyResult, UpdateResult,
+    mongodb::{
+        bson::{Bson, oid::ObjectId},
+        results,
+    },
+};
+
+#[test]
+fn update_result() {
+    let id = ObjectId::new();
+
+    let mut result = results::UpdateResult::default();
+    result.upserted_id = Some(Bson::ObjectId(id));
+
+    let result = UpdateResult::<ObjectId>::try_from(result).unwrap();
+    assert_eq!(result.matched(), 0);
+    assert_eq!(result.modified(), 0);
+    assert_eq!(result.upserted_id(), Some(id));
+    assert!(result.created());
+
+    let mut result = results::UpdateResult::default();
+    result.matched_count = 3;
+    result.modified_count = 2;
+
+    let result = UpdateResult::<ObjectId>::try_from(result).unwrap();
+    assert_eq!(result.matched(), 3);
+    assert_eq!(result.modified(), 2);
+    assert_eq!(result.upserted_id(), None);
+    assert!(!result.created());
+}
+
+#[test]
+fn update_result_with_other_id_type() {
+    let mut result = results::UpdateResult::default();
+    result.upserted_id = Some(Bson::String("kit".to_string()));
+
+    assert!(UpdateResult::<ObjectId>::try_from(result).is_err());
+}
+
+#[test]
+fn delete_result() {
+    let mut result = results::DeleteResult::default();
+    result.deleted_count = 4;
+
+    assert_eq!(DeleteResult::from(result).deleted(), 4);
+}
+
+#[test]
+fn insert_many_result_is_ordered_by_index() {
+    let ids = (0..20).map(|_| ObjectId::new()).collect::<Vec<_>>();
+
+    // The driver reports the ids in a `HashMap` keyed by the index of the entity
+    let mut result = results::InsertManyResult::default();
+    for (i, id) in ids.iter().enumerate().rev() {
+        result.inserted_ids.insert(i, Bson::ObjectId(*id));
+    }
+
+    let result = InsertManyResult::<ObjectId>::try_from(result).unwrap();
+    assert_eq!(result.inserted_ids(), ids);
+}
+
+#[test]
+fn insert_many_result_with_other_id_type() {
+    let mut result = results::InsertManyResult::default();
+    result.inserted_ids.insert(0, Bson::Int32(1));
+
+    assert!(InsertManyResult::<ObjectId>::try_from(result).is_err());
+}
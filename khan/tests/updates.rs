@@ -0,0 +1,298 @@
+use khan::{
+    ArrayUpdateOperator, DateUpdateOperator, Entity, Fields, NestedUpdate, NullableUpdateOperator,
+    NumUpdateOperator, OrdUpdateOperator, UntypedUpdate, Update, UpdateApply, UpdateOperator,
+    mongodb::bson::{self, DateTime, doc, oid::ObjectId},
+    types,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Fields)]
+pub struct Address {
+    #[serde(rename = "c")]
+    pub city: String,
+    pub zip: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Entity)]
+pub struct User {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub name: String,
+    pub address: Address,
+    pub login_count: i32,
+    pub tags: Vec<String>,
+    pub nickname: Option<String>,
+}
+
+/// A type named like `bson::DateTime`, but stored as a string, e.g. `chrono::DateTime`.
+pub mod text_date {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, PartialEq, PartialOrd, Serialize, Deserialize)]
+    pub struct DateTime(pub String);
+}
+
+#[derive(Debug, Serialize, Deserialize, Entity)]
+pub struct Session {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub started_at: bson::DateTime,
+    pub renewed_at: types::DateTime,
+    pub expires_at: text_date::DateTime,
+}
+
+fn user() -> User {
+    User {
+        id: ObjectId::new(),
+        name: "Kit".to_string(),
+        address: Address {
+            city: "Berlin".to_string(),
+            zip: "10115".to_string(),
+        },
+        login_count: 1,
+        tags: vec!["admin".to_string()],
+        nickname: Some("Kitty".to_string()),
+    }
+}
+
+#[test]
+fn dot_paths() {
+    let update =
+        user::update! { address.zip: "10117".to_string(), address.city: "Bonn".to_string() };
+
+    assert_eq!(
+        Update::<User>::to_document(&update),
+        doc! { "$set": { "address.c": "Bonn", "address.zip": "10117" } }
+    );
+}
+
+#[test]
+fn dot_paths_mixed_with_fields() {
+    let update = user::update! { name: "K.I.".to_string(), address.zip: "10117".to_string() };
+
+    assert_eq!(
+        Update::<User>::to_document(&update),
+        doc! { "$set": { "name": "K.I.", "address.zip": "10117" } }
+    );
+}
+
+#[test]
+fn nested_update_prefixes_every_operator() {
+    let update = NestedUpdate::<User, Address, _>::new(
+        "address",
+        UntypedUpdate::<Address>::new(doc! { "$set": { "c": "Bonn" }, "$unset": { "zip": "" } }),
+    );
+
+    assert_eq!(
+        update.to_document(),
+        doc! { "$set": { "address.c": "Bonn" }, "$unset": { "address.zip": "" } }
+    );
+}
+
+#[test]
+fn dot_paths_apply() {
+    let mut user = user();
+
+    user::update! { name: "K.I.".to_string(), address.zip: "10117".to_string() }
+        .apply(&mut user)
+        .unwrap();
+
+    assert_eq!(user.name, "K.I.");
+    assert_eq!(
+        user.address,
+        Address {
+            city: "Berlin".to_string(),
+            zip: "10117".to_string(),
+        }
+    );
+}
+
+#[test]
+fn operators_are_grouped() {
+    let update = user::update! {
+        name: "Kit".to_string(),
+        login_count: Inc(1),
+        tags: Push("staff".to_string()),
+        nickname: Unset,
+    };
+
+    assert_eq!(
+        Update::<User>::to_document(&update),
+        doc! {
+            "$set": { "name": "Kit" },
+            "$inc": { "login_count": 1 },
+            "$push": { "tags": "staff" },
+            "$unset": { "nickname": "" },
+        }
+    );
+}
+
+#[test]
+fn operators_apply() {
+    let mut user = user();
+
+    user::update! {
+        login_count: Inc(2),
+        tags: AddToSet("staff".to_string()),
+        nickname: Unset,
+    }
+    .apply(&mut user)
+    .unwrap();
+
+    assert_eq!(user.login_count, 3);
+    assert_eq!(user.tags, ["admin", "staff"]);
+    assert_eq!(user.nickname, None);
+}
+
+#[test]
+fn num_operators_apply() {
+    let apply = |operator: NumUpdateOperator<i32>, mut value: i32| {
+        operator.apply(&mut value).map(|()| value)
+    };
+
+    assert_eq!(apply(NumUpdateOperator::Set(5), 1).unwrap(), 5);
+    assert_eq!(apply(NumUpdateOperator::Inc(-2), 1).unwrap(), -1);
+    assert_eq!(apply(NumUpdateOperator::Mul(3), 2).unwrap(), 6);
+    assert_eq!(apply(NumUpdateOperator::Min(0), 1).unwrap(), 0);
+    assert_eq!(apply(NumUpdateOperator::Min(2), 1).unwrap(), 1);
+    assert_eq!(apply(NumUpdateOperator::Max(2), 1).unwrap(), 2);
+    assert_eq!(apply(NumUpdateOperator::Max(0), 1).unwrap(), 1);
+
+    assert!(apply(NumUpdateOperator::Inc(1), i32::MAX).is_err());
+    assert!(apply(NumUpdateOperator::Mul(2), i32::MIN).is_err());
+
+    let mut value = 1.5_f64;
+    NumUpdateOperator::Mul(f64::MAX).apply(&mut value).unwrap();
+    assert!(value.is_infinite());
+}
+
+#[test]
+fn ord_and_date_operators_apply() {
+    let (earlier, later) = (ObjectId::from_bytes([0; 12]), ObjectId::from_bytes([1; 12]));
+
+    let mut value = later;
+    OrdUpdateOperator::Min(earlier).apply(&mut value).unwrap();
+    assert_eq!(value, earlier);
+
+    OrdUpdateOperator::Max(later).apply(&mut value).unwrap();
+    assert_eq!(value, later);
+
+    let mut date = DateTime::from_millis(0);
+    DateUpdateOperator::Max(DateTime::from_millis(10))
+        .apply(&mut date)
+        .unwrap();
+    assert_eq!(date, DateTime::from_millis(10));
+
+    DateUpdateOperator::CurrentDate.apply(&mut date).unwrap();
+    assert!(date > DateTime::from_millis(10));
+}
+
+#[test]
+fn array_operators_apply() {
+    let apply = |operator: ArrayUpdateOperator<i32>| {
+        let mut value = vec![1, 2, 1, 3];
+        operator.apply(&mut value).map(|()| value)
+    };
+
+    assert_eq!(
+        apply(ArrayUpdateOperator::Push(1)).unwrap(),
+        [1, 2, 1, 3, 1]
+    );
+    assert_eq!(apply(ArrayUpdateOperator::Pull(1)).unwrap(), [2, 3]);
+    assert_eq!(
+        apply(ArrayUpdateOperator::AddToSet(2)).unwrap(),
+        [1, 2, 1, 3]
+    );
+    assert_eq!(
+        apply(ArrayUpdateOperator::AddToSet(4)).unwrap(),
+        [1, 2, 1, 3, 4]
+    );
+    assert_eq!(apply(ArrayUpdateOperator::PopFirst).unwrap(), [2, 1, 3]);
+    assert_eq!(apply(ArrayUpdateOperator::PopLast).unwrap(), [1, 2, 1]);
+
+    let mut empty = Vec::<i32>::new();
+    ArrayUpdateOperator::PopFirst.apply(&mut empty).unwrap();
+    assert!(empty.is_empty());
+}
+
+#[test]
+fn array_pull_conditions_apply() {
+    // A document is a condition on the fields of the elements, as on the server
+    let mut value = vec![
+        doc! { "tag": "a", "score": 1 },
+        doc! { "score": 2, "tag": "b" },
+        doc! { "tag": "a" },
+        doc! { "tags": ["a", "b"] },
+    ];
+    ArrayUpdateOperator::Pull(doc! { "tag": "a" })
+        .apply(&mut value)
+        .unwrap();
+    assert_eq!(
+        value,
+        [doc! { "score": 2, "tag": "b" }, doc! { "tags": ["a", "b"] }]
+    );
+
+    // Field order doesn't matter, and array fields match any of their elements
+    ArrayUpdateOperator::Pull(doc! { "tag": "b", "score": 2 })
+        .apply(&mut value)
+        .unwrap();
+    ArrayUpdateOperator::Pull(doc! { "tags": "b" })
+        .apply(&mut value)
+        .unwrap();
+    assert!(value.is_empty());
+
+    // `null` matches missing fields
+    let mut value = vec![doc! { "tag": "a" }, doc! { "tag": "a", "score": 1 }];
+    ArrayUpdateOperator::Pull(doc! { "tag": "a", "score": null })
+        .apply(&mut value)
+        .unwrap();
+    assert_eq!(value, [doc! { "tag": "a", "score": 1 }]);
+
+    // Query operators are only supported by the server
+    let mut value = vec![doc! { "score": 1 }];
+    let error = ArrayUpdateOperator::Pull(doc! { "$or": [{ "score": 1 }] })
+        .apply(&mut value)
+        .unwrap_err();
+    assert!(error.to_string().contains("can't be applied in memory"));
+    assert_eq!(value, [doc! { "score": 1 }]);
+}
+
+#[test]
+fn nullable_operators_apply() {
+    let mut value = None;
+    NullableUpdateOperator::Set(Some(1))
+        .apply(&mut value)
+        .unwrap();
+    assert_eq!(value, Some(1));
+
+    NullableUpdateOperator::Unset.apply(&mut value).unwrap();
+    assert_eq!(value, None);
+}
+
+#[test]
+fn untyped_update_is_passed_through() {
+    let update = UntypedUpdate::<User>::new(doc! { "$inc": { "login_count": 1 } });
+
+    assert_eq!(update.to_document(), doc! { "$inc": { "login_count": 1 } });
+}
+
+#[test]
+fn dates_are_recognized_by_path() {
+    let _: DateUpdateOperator<bson::DateTime> = session::update_operators::started_at::CurrentDate;
+    let _: DateUpdateOperator<types::DateTime> = session::update_operators::renewed_at::CurrentDate;
+    let _: OrdUpdateOperator<text_date::DateTime> =
+        session::update_operators::expires_at::Set(text_date::DateTime("2025-01-01".to_string()));
+
+    let update = session::update! {
+        started_at: CurrentDate,
+        expires_at: text_date::DateTime("2025-01-01".to_string()),
+    };
+
+    assert_eq!(
+        Update::<Session>::to_document(&update),
+        doc! {
+            "$currentDate": { "started_at": true },
+            "$set": { "expires_at": "2025-01-01" },
+        }
+    );
+}